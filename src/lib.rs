@@ -0,0 +1,5 @@
+extern crate itertools;
+//#[macro_use]
+extern crate snafu;
+
+pub mod parse;
@@ -1,33 +1,51 @@
-extern crate itertools;
-//#[macro_use]
-extern crate snafu;
-
-mod parse;
-
-use std::collections::HashMap;
 use std::env::args;
 use std::fs;
 use std::path::Path;
 use std::process::Command;
 
-use parse::ast::ASTNode;
+use threecc::parse;
+use threecc::parse::ast::{self, ASTNode, Context, Options};
+use threecc::parse::diagnostic::snippet;
+
+// Columns between tab stops when quoting source in an error.
+const TAB_WIDTH: usize = 8;
 
 fn main() {
     let args: Vec<String> = args().collect();
     let path = Path::new(&args[1]);
     let src = fs::read_to_string(path).unwrap();
-    let tok = parse::lex(&src);
+    let tok = parse::lex::lex_spanned(&src);
+    let spans = tok.iter().map(|&(_, span)| span).collect::<Vec<_>>();
     println!("Tokens:\n{:#?}", tok);
-    match exec(tok, &path) {
+    match exec(tok, path) {
         Ok(_) => {}
-        Err(e) => eprintln!("{}", e),
+        Err(e) => {
+            eprintln!("{}", e);
+            // Not every parse error records its span, but those raised on a
+            // token can be traced back to it.
+            let span = e.downcast_ref::<ast::Error>().and_then(|e| {
+                e.span()
+                    .or_else(|| e.token_index(spans.len()).map(|i| spans[i]))
+            });
+            if let Some(span) = span {
+                eprintln!("{}", snippet(&src, span, TAB_WIDTH));
+            }
+        }
     };
 }
 
-fn exec(tok: Vec<parse::lex::Token>, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    let ast = parse::parse(tok)?;
+fn exec(
+    tok: Vec<(parse::lex::Token, parse::lex::Span)>,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ast = parse::parse_spanned(tok.clone())?;
     println!("AST:\n{:#?}", ast);
-    let out = ast.emit(&mut HashMap::new(), &mut 8)?;
+    for d in ast.check_spanned(&tok, &Options::default())? {
+        eprintln!("{}", d);
+    }
+    let mut ctx = Context::new();
+    let code = ast.emit(&mut ctx)?;
+    let out = ctx.render(&code);
     println!("Emitted:\n{}", out);
     fs::write("/tmp/t.asm", out).expect("Failed to write assembly out");
     println!(
@@ -35,7 +53,8 @@ fn exec(tok: Vec<parse::lex::Token>, path: &Path) -> Result<(), Box<dyn std::err
         Command::new("nasm")
             .arg("-felf64")
             .arg("/tmp/t.asm")
-            .arg("-o /tmp/t.o")
+            .arg("-o")
+            .arg("/tmp/t.o")
             .output()?
     );
     /*    println!(
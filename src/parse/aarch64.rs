@@ -0,0 +1,508 @@
+use super::asm::{Condition, Instruction, Operand, Register, Width};
+
+// The AArch64 register standing in for each x86-64 one. The argument
+// registers line up with the AAPCS64 ones, x0 to x5, so calls in and out of
+// C work. rax shares x0 with rdi, which is safe because the arguments are
+// spilled on entry and only loaded just before a call. The callee-saved
+// registers map to callee-saved ones, and x16 and x17 are kept as scratch.
+fn number(reg: Register) -> u8 {
+    match reg {
+        Register::Rax | Register::Rdi => 0,
+        Register::Rsi => 1,
+        Register::Rdx => 2,
+        Register::Rcx => 3,
+        Register::R8 => 4,
+        Register::R9 => 5,
+        Register::R10 => 9,
+        Register::R11 => 10,
+        Register::Rbx => 19,
+        Register::R12 => 20,
+        Register::R13 => 21,
+        Register::R14 => 22,
+        Register::R15 => 23,
+        Register::Rbp => 29,
+        Register::Rsp => SP,
+    }
+}
+
+// Holds a value loaded from memory or an immediate.
+const SCRATCH: u8 = 16;
+// Holds a computed address, or an immediate second operand.
+const ADDRESS: u8 = 17;
+const SP: u8 = 31;
+
+fn name(n: u8, wide: bool) -> String {
+    match (n, wide) {
+        (SP, true) => String::from("sp"),
+        (SP, false) => String::from("wsp"),
+        (n, true) => format!("x{}", n),
+        (n, false) => format!("w{}", n),
+    }
+}
+
+// Whether an operation on this operand works on all 64 bits.
+fn is_wide(op: &Operand) -> bool {
+    match op {
+        Operand::Dword(_) | Operand::Byte(_) => false,
+        op => !matches!(op.width(), Some(Width::Dword) | Some(Width::Byte)),
+    }
+}
+
+// The load and store instructions for a memory operand of the given width.
+// A narrower load zero-extends.
+fn transfer(width: Option<Width>) -> (&'static str, &'static str) {
+    match width {
+        Some(Width::Byte) => ("ldrb", "strb"),
+        _ => ("ldr", "str"),
+    }
+}
+
+fn condition(c: Condition) -> &'static str {
+    match c {
+        Condition::Equal => "eq",
+        Condition::NotEqual => "ne",
+        Condition::Less => "lt",
+        Condition::LessEqual => "le",
+        Condition::Greater => "gt",
+        Condition::GreaterEqual => "ge",
+        Condition::Below => "lo",
+        Condition::BelowEqual => "ls",
+        Condition::Above => "hi",
+        Condition::AboveEqual => "hs",
+        Condition::Overflow => "vs",
+        Condition::NotSign => "pl",
+    }
+}
+
+// Put `value` in the 64-bit register `n`.
+fn immediate(out: &mut Vec<String>, n: u8, value: i64) {
+    if (-0x10000..=0xffff).contains(&value) {
+        out.push(format!("mov {}, #{}", name(n, true), value));
+        return;
+    }
+    let mut op = "movz";
+    for shift in (0..64).step_by(16) {
+        let part = (value as u64 >> shift) & 0xffff;
+        if part != 0 {
+            out.push(format!(
+                "{} {}, #{}, lsl #{}",
+                op,
+                name(n, true),
+                part,
+                shift
+            ));
+            op = "movk";
+        }
+    }
+}
+
+// Compute the address of a memory operand into x17.
+fn compute_address(out: &mut Vec<String>, op: &Operand) {
+    match op {
+        Operand::Mem {
+            base,
+            index,
+            offset,
+            ..
+        } => {
+            immediate(out, ADDRESS, *offset);
+            out.push(format!("add x17, {}, x17", name(number(*base), true)));
+            if let Some((index, scale)) = index {
+                out.push(format!(
+                    "add x17, x17, {}, lsl #{}",
+                    name(number(*index), true),
+                    scale.trailing_zeros()
+                ));
+            }
+        }
+        Operand::Rel(symbol, _) => {
+            out.push(format!("adrp x17, {}", symbol));
+            out.push(format!("add x17, x17, :lo12:{}", symbol));
+        }
+        _ => unreachable!(),
+    }
+}
+
+// The addressing mode for a memory operand. Anything that doesn't fit one
+// is computed into x17 first.
+fn address(out: &mut Vec<String>, op: &Operand) -> String {
+    match op {
+        Operand::Mem {
+            base,
+            index: None,
+            offset: 0,
+            ..
+        } => format!("[{}]", name(number(*base), true)),
+        Operand::Mem {
+            base,
+            index: None,
+            offset,
+            ..
+        } if (-256..256).contains(offset) => {
+            format!("[{}, #{}]", name(number(*base), true), offset)
+        }
+        Operand::Mem {
+            base,
+            index: Some((index, 8)),
+            offset: 0,
+            width: Width::Qword,
+        } => format!(
+            "[{}, {}, lsl #3]",
+            name(number(*base), true),
+            name(number(*index), true)
+        ),
+        op => {
+            compute_address(out, op);
+            String::from("[x17]")
+        }
+    }
+}
+
+// A register holding the value of `op`, loading it into `scratch` if it
+// isn't in one already.
+fn value(out: &mut Vec<String>, op: &Operand, scratch: u8, wide: bool) -> String {
+    match op {
+        Operand::Reg(r) | Operand::Dword(r) | Operand::Byte(r) => name(number(*r), wide),
+        Operand::Imm(i) => {
+            immediate(out, scratch, *i);
+            name(scratch, wide)
+        }
+        Operand::Mem { .. } | Operand::Rel(..) => {
+            let at = address(out, op);
+            let (load, _) = transfer(op.width());
+            out.push(format!("{} {}, {}", load, name(scratch, is_wide(op)), at));
+            name(scratch, wide)
+        }
+        Operand::Symbol(_) => unreachable!(),
+    }
+}
+
+// Like `value`, but leaves a small enough immediate as one.
+fn value_or_immediate(out: &mut Vec<String>, op: &Operand, scratch: u8, wide: bool) -> String {
+    match op {
+        Operand::Imm(i) if (0..4096).contains(i) => format!("#{}", i),
+        op => value(out, op, scratch, wide),
+    }
+}
+
+// `dst = dst op src`, going through x16 if `dst` is in memory.
+fn arithmetic(out: &mut Vec<String>, op: &str, dst: &Operand, src: &Operand) -> String {
+    let wide = is_wide(dst);
+    let d = value(out, dst, SCRATCH, wide);
+    let s = match op {
+        "adds" | "subs" | "lsl" | "asr" | "lsr" => value_or_immediate(out, src, ADDRESS, wide),
+        _ => value(out, src, ADDRESS, wide),
+    };
+    out.push(format!("{} {}, {}, {}", op, d, d, s));
+    store(out, dst, &d);
+    d
+}
+
+// Write `reg` back to `dst` if it is in memory. `reg` is a w register if
+// `dst` is narrower than a quadword.
+fn store(out: &mut Vec<String>, dst: &Operand, reg: &str) {
+    if let Operand::Mem { .. } | Operand::Rel(..) = dst {
+        let at = address(out, dst);
+        let (_, store) = transfer(dst.width());
+        out.push(format!("{} {}, {}", store, reg, at));
+    }
+}
+
+// Move sp by `bytes`, rounded up to 16 to keep it aligned.
+fn adjust_stack(out: &mut Vec<String>, op: &str, bytes: i64) {
+    let bytes = (bytes + 15) / 16 * 16;
+    if bytes < 4096 {
+        out.push(format!("{} sp, sp, #{}", op, bytes));
+    } else {
+        immediate(out, SCRATCH, bytes);
+        out.push(format!("{} sp, sp, x16", op));
+    }
+}
+
+// Multiply, and if `check` compare so that `ne` means the product
+// overflowed, as `jo` would after `imul`.
+fn multiply(out: &mut Vec<String>, dst: &Operand, src: &Operand, check: bool) {
+    let wide = is_wide(dst);
+    let d = value(out, dst, SCRATCH, wide);
+    let s = value(out, src, ADDRESS, wide);
+    match (check, wide) {
+        (false, _) => out.push(format!("mul {}, {}, {}", d, d, s)),
+        (true, true) => out.extend(vec![
+            format!("smulh x16, {}, {}", d, s),
+            format!("mul {}, {}, {}", d, d, s),
+            format!("cmp x16, {}, asr #63", d),
+        ]),
+        (true, false) => out.extend(vec![
+            format!("smull x16, {}, {}", d, s),
+            format!("mov {}, w16", d),
+            String::from("cmp x16, w16, sxtw"),
+        ]),
+    }
+}
+
+// Divide rax by `op`, leaving the quotient in rax and the remainder in rdx.
+fn divide(out: &mut Vec<String>, op: &str, divisor: &Operand) {
+    let wide = is_wide(divisor);
+    let d = value(out, divisor, ADDRESS, wide);
+    let (q, rax, rdx) = (name(SCRATCH, wide), name(0, wide), name(2, wide));
+    out.extend(vec![
+        format!("{} {}, {}, {}", op, q, rax, d),
+        format!("msub {}, {}, {}, {}", rdx, q, d, rax),
+        format!("mov {}, {}", rax, q),
+    ]);
+}
+
+// Translate a NASM directive to its GNU assembler equivalent.
+fn directive(out: &mut Vec<String>, d: &str) {
+    let mut words = d.splitn(2, ' ');
+    let (word, rest) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+    let width = |suffix: &str| match suffix {
+        "b" => 1,
+        "w" => 2,
+        "d" => 4,
+        _ => 8,
+    };
+    match word {
+        "global" => out.push(format!(".globl {}", rest)),
+        // Undefined symbols are external anyway.
+        "extern" => {}
+        "section" => {
+            let mut parts = rest.split(" align=");
+            let section = parts.next().unwrap_or("");
+            out.push(format!(".section {}", section));
+            match parts.next() {
+                Some(align) => out.push(format!(".balign {}", align)),
+                None if section == ".text" => out.push(String::from(".balign 4")),
+                None => {}
+            }
+        }
+        "times" => {
+            let count = rest.split(' ').next().unwrap_or("0");
+            out.push(format!(".zero {}", count));
+        }
+        "resb" | "resw" | "resd" | "resq" => {
+            let count = rest.parse::<usize>().unwrap_or(0);
+            out.push(format!(".zero {}", count * width(&word[3..])));
+        }
+        "db" | "dw" | "dd" | "dq" => {
+            out.push(format!(".{}byte {}", width(&word[1..]), rest));
+        }
+        // Windows unwind data only describes x86-64 code.
+        w if w.starts_with(".seh_") => {}
+        _ => out.push(d.to_owned()),
+    }
+}
+
+fn instruction(out: &mut Vec<String>, i: &Instruction, next: Option<&Instruction>) {
+    // Whether the next instruction reads the flags this one would set on
+    // x86-64.
+    let flags_read = matches!(
+        next,
+        Some(Instruction::Jcc(..)) | Some(Instruction::Set(..))
+    );
+    match i {
+        Instruction::Label(label) => out.push(format!("{}:", label)),
+        Instruction::Directive(d) => directive(out, d),
+        Instruction::Mov(dst @ Operand::Mem { .. }, src)
+        | Instruction::Mov(dst @ Operand::Rel(..), src) => {
+            let s = value(out, src, SCRATCH, is_wide(dst));
+            store(out, dst, &s);
+        }
+        // A dword move clears the upper half, as on x86-64.
+        Instruction::Mov(dst @ Operand::Dword(_), Operand::Imm(i)) => {
+            immediate(out, number_of(dst), *i as u32 as i64);
+        }
+        Instruction::Mov(dst, Operand::Imm(i)) => {
+            immediate(out, number_of(dst), *i);
+        }
+        Instruction::Mov(dst, src @ Operand::Mem { .. })
+        | Instruction::Mov(dst, src @ Operand::Rel(..)) => {
+            let at = address(out, src);
+            let (load, _) = transfer(src.width());
+            out.push(format!(
+                "{} {}, {}",
+                load,
+                name(number_of(dst), is_wide(src)),
+                at
+            ));
+        }
+        Instruction::Mov(dst, src) => {
+            let wide = is_wide(dst);
+            let s = value(out, src, SCRATCH, wide);
+            out.push(format!("mov {}, {}", name(number_of(dst), wide), s));
+        }
+        Instruction::Movsxd(dst, src) => {
+            let s = value(out, src, SCRATCH, false);
+            out.push(format!("sxtw {}, {}", name(number_of(dst), true), s));
+        }
+        Instruction::Movsx(dst, src) => {
+            let s = value(out, src, SCRATCH, false);
+            out.push(format!("sxtb {}, {}", name(number_of(dst), true), s));
+        }
+        Instruction::Lea(dst, src) => {
+            let d = name(number_of(dst), true);
+            match src {
+                Operand::Mem {
+                    base,
+                    index: None,
+                    offset,
+                    ..
+                } if (-4095..4096).contains(offset) => out.push(format!(
+                    "{} {}, {}, #{}",
+                    if *offset < 0 { "sub" } else { "add" },
+                    d,
+                    name(number(*base), true),
+                    offset.abs()
+                )),
+                src => {
+                    compute_address(out, src);
+                    out.push(format!("mov {}, x17", d));
+                }
+            }
+        }
+        Instruction::Xchg(a, b) => {
+            let (a, b) = (name(number_of(a), true), name(number_of(b), true));
+            out.extend(vec![
+                format!("mov x16, {}", a),
+                format!("mov {}, {}", a, b),
+                format!("mov {}, x16", b),
+            ]);
+        }
+        Instruction::Push(src) => {
+            let s = value(out, src, SCRATCH, true);
+            out.push(format!("str {}, [sp, #-16]!", s));
+        }
+        Instruction::Pop(dst) => {
+            let d = match dst {
+                Operand::Reg(r) => name(number(*r), true),
+                _ => name(SCRATCH, true),
+            };
+            out.push(format!("ldr {}, [sp], #16", d));
+            store(out, dst, &d);
+        }
+        Instruction::Add(Operand::Reg(Register::Rsp), Operand::Imm(bytes)) => {
+            adjust_stack(out, "add", *bytes)
+        }
+        Instruction::Sub(Operand::Reg(Register::Rsp), Operand::Imm(bytes)) => {
+            adjust_stack(out, "sub", *bytes)
+        }
+        Instruction::Add(dst, src) => {
+            arithmetic(out, "adds", dst, src);
+        }
+        Instruction::Sub(dst, src) => {
+            arithmetic(out, "subs", dst, src);
+        }
+        Instruction::And(dst, src) => {
+            arithmetic(out, "ands", dst, src);
+        }
+        // These set no flags on AArch64, so test the result if they're
+        // read.
+        Instruction::Or(dst, src) | Instruction::Xor(dst, src) => {
+            let op = match i {
+                Instruction::Or(..) => "orr",
+                _ => "eor",
+            };
+            let d = arithmetic(out, op, dst, src);
+            if flags_read {
+                out.push(format!("tst {}, {}", d, d));
+            }
+        }
+        Instruction::Shl(dst, src) => {
+            arithmetic(out, "lsl", dst, src);
+        }
+        Instruction::Sar(dst, src) => {
+            arithmetic(out, "asr", dst, src);
+        }
+        Instruction::Shr(dst, src) => {
+            arithmetic(out, "lsr", dst, src);
+        }
+        Instruction::Imul(dst, src) => multiply(out, dst, src, false),
+        Instruction::Idiv(divisor) => divide(out, "sdiv", divisor),
+        Instruction::Div(divisor) => divide(out, "udiv", divisor),
+        Instruction::Cqo => out.push(String::from("asr x2, x0, #63")),
+        Instruction::Neg(dst) | Instruction::Not(dst) => {
+            let wide = is_wide(dst);
+            let d = value(out, dst, SCRATCH, wide);
+            let op = match i {
+                Instruction::Neg(_) => "negs",
+                _ => "mvn",
+            };
+            out.push(format!("{} {}, {}", op, d, d));
+            store(out, dst, &d);
+        }
+        Instruction::Inc(dst) => {
+            arithmetic(out, "adds", dst, &Operand::Imm(1));
+        }
+        Instruction::Cmp(a, b) | Instruction::Test(a, b) => {
+            let wide = is_wide(a);
+            let a = value(out, a, SCRATCH, wide);
+            let (op, b) = match i {
+                Instruction::Cmp(..) => ("cmp", value_or_immediate(out, b, ADDRESS, wide)),
+                _ => ("tst", value(out, b, ADDRESS, wide)),
+            };
+            out.push(format!("{} {}, {}", op, a, b));
+        }
+        Instruction::Set(c, dst) => out.push(format!(
+            "cset {}, {}",
+            name(number_of(dst), false),
+            condition(*c)
+        )),
+        Instruction::Cmov(c, dst, src) => {
+            let d = name(number_of(dst), true);
+            out.push(format!(
+                "csel {}, {}, {}, {}",
+                d,
+                name(number_of(src), true),
+                d,
+                condition(*c)
+            ));
+        }
+        Instruction::Jmp(Operand::Symbol(label)) => out.push(format!("b {}", label)),
+        Instruction::Jmp(target) => {
+            let t = value(out, target, SCRATCH, true);
+            out.push(format!("br {}", t));
+        }
+        Instruction::Jcc(c, label) => out.push(format!("b.{} {}", condition(*c), label)),
+        // The return address goes on the stack, as x86-64 `call` would put
+        // it, so that the callee is free to overwrite x30.
+        Instruction::Call(symbol) => out.extend(vec![
+            String::from("str x30, [sp, #-16]!"),
+            format!("bl {}", symbol),
+            String::from("ldr x30, [sp], #16"),
+        ]),
+        Instruction::Ret => out.push(String::from("ret")),
+        Instruction::Ud2 => out.push(String::from("udf #0")),
+    }
+}
+
+// The register number of a register operand.
+fn number_of(op: &Operand) -> u8 {
+    match op {
+        Operand::Reg(r) | Operand::Dword(r) | Operand::Byte(r) => number(*r),
+        _ => unreachable!(),
+    }
+}
+
+/// Render instructions as AArch64 GNU assembler source, one or more lines
+/// each. Registers map onto the AAPCS64 ones, so that the argument and
+/// return registers agree with C. Every push takes 16 bytes to keep sp
+/// aligned, so code addressing locals relative to rsp can't be translated.
+pub fn to_aarch64(code: &[Instruction]) -> String {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < code.len() {
+        match &code[i..] {
+            [Instruction::Imul(dst, src), Instruction::Jcc(Condition::Overflow, label), ..] => {
+                multiply(&mut out, dst, src, true);
+                out.push(format!("b.ne {}", label));
+                i += 2;
+            }
+            [inst, rest @ ..] => {
+                instruction(&mut out, inst, rest.first());
+                i += 1;
+            }
+            [] => unreachable!(),
+        }
+    }
+    out.iter().map(|line| format!("{}\n", line)).collect()
+}
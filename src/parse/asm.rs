@@ -0,0 +1,432 @@
+use std::fmt;
+
+use super::aarch64::to_aarch64;
+
+/// A 64-bit general purpose register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Rax,
+    Rbx,
+    Rcx,
+    Rdx,
+    Rsi,
+    Rdi,
+    Rbp,
+    Rsp,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Register {
+    fn names(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Register::Rax => ("rax", "eax", "al"),
+            Register::Rbx => ("rbx", "ebx", "bl"),
+            Register::Rcx => ("rcx", "ecx", "cl"),
+            Register::Rdx => ("rdx", "edx", "dl"),
+            Register::Rsi => ("rsi", "esi", "sil"),
+            Register::Rdi => ("rdi", "edi", "dil"),
+            Register::Rbp => ("rbp", "ebp", "bpl"),
+            Register::Rsp => ("rsp", "esp", "spl"),
+            Register::R8 => ("r8", "r8d", "r8b"),
+            Register::R9 => ("r9", "r9d", "r9b"),
+            Register::R10 => ("r10", "r10d", "r10b"),
+            Register::R11 => ("r11", "r11d", "r11b"),
+            Register::R12 => ("r12", "r12d", "r12b"),
+            Register::R13 => ("r13", "r13d", "r13b"),
+            Register::R14 => ("r14", "r14d", "r14b"),
+            Register::R15 => ("r15", "r15d", "r15b"),
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.names().0)
+    }
+}
+
+/// The size of a value in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Dword,
+    Qword,
+}
+
+impl Width {
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::Byte => 1,
+            Width::Dword => 4,
+            Width::Qword => 8,
+        }
+    }
+}
+
+impl fmt::Display for Width {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Width::Byte => write!(f, "byte"),
+            Width::Dword => write!(f, "dword"),
+            Width::Qword => write!(f, "qword"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(Register),
+    /// The low 32 bits of a register, e.g. `eax`.
+    Dword(Register),
+    /// The low byte of a register, e.g. `al`.
+    Byte(Register),
+    Imm(i64),
+    /// The value of the given width at `[base + index*scale + offset]`.
+    Mem {
+        base: Register,
+        index: Option<(Register, u8)>,
+        offset: i64,
+        width: Width,
+    },
+    /// The value of the given width at a symbol, addressed relative to rip.
+    Rel(String, Width),
+    /// A label or function, as the target of a jump or call.
+    Symbol(String),
+}
+
+impl Operand {
+    /// The quadword at `offset` from the address in `base`.
+    pub fn at(base: Register, offset: i64) -> Operand {
+        Operand::Mem {
+            base,
+            index: None,
+            offset,
+            width: Width::Qword,
+        }
+    }
+
+    /// The same memory operand, but of the given width. Anything else is
+    /// returned as is.
+    pub fn sized(self, width: Width) -> Operand {
+        match self {
+            Operand::Mem {
+                base,
+                index,
+                offset,
+                ..
+            } => Operand::Mem {
+                base,
+                index,
+                offset,
+                width,
+            },
+            Operand::Rel(name, _) => Operand::Rel(name, width),
+            op => op,
+        }
+    }
+
+    /// The width of a memory operand.
+    pub fn width(&self) -> Option<Width> {
+        match self {
+            Operand::Mem { width, .. } | Operand::Rel(_, width) => Some(*width),
+            _ => None,
+        }
+    }
+
+    // Whether reading this operand reads `reg`.
+    fn uses(&self, reg: Register) -> bool {
+        match self {
+            Operand::Reg(r) | Operand::Dword(r) | Operand::Byte(r) => *r == reg,
+            Operand::Mem { base, index, .. } => {
+                *base == reg || matches!(index, Some((index, _)) if *index == reg)
+            }
+            Operand::Imm(_) | Operand::Rel(..) | Operand::Symbol(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "{}", r),
+            Operand::Dword(r) => write!(f, "{}", r.names().1),
+            Operand::Byte(r) => write!(f, "{}", r.names().2),
+            Operand::Imm(i) => write!(f, "{}", i),
+            Operand::Mem {
+                base,
+                index,
+                offset,
+                width,
+            } => {
+                // Narrower operands are always spelled out, since the
+                // register they pair with may not be.
+                if *width != Width::Qword {
+                    write!(f, "{} ", width)?;
+                }
+                write!(f, "[{}", base)?;
+                if let Some((index, scale)) = index {
+                    write!(f, " + {}*{}", index, scale)?;
+                }
+                match offset {
+                    0 => write!(f, "]"),
+                    o if *o < 0 => write!(f, " - {}]", -o),
+                    o => write!(f, " + {}]", o),
+                }
+            }
+            Operand::Rel(name, Width::Qword) => write!(f, "[rel {}]", name),
+            Operand::Rel(name, width) => write!(f, "{} [rel {}]", width, name),
+            Operand::Symbol(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// The flag conditions tested by `set` and conditional jumps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    /// Unsigned less than.
+    Below,
+    /// Unsigned less than or equal.
+    BelowEqual,
+    /// Unsigned greater than.
+    Above,
+    /// Unsigned greater than or equal.
+    AboveEqual,
+    /// Signed overflow.
+    Overflow,
+    /// The result was not negative.
+    NotSign,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let suffix = match self {
+            Condition::Equal => "e",
+            Condition::NotEqual => "ne",
+            Condition::Less => "l",
+            Condition::LessEqual => "le",
+            Condition::Greater => "g",
+            Condition::GreaterEqual => "ge",
+            Condition::Below => "b",
+            Condition::BelowEqual => "be",
+            Condition::Above => "a",
+            Condition::AboveEqual => "ae",
+            Condition::Overflow => "o",
+            Condition::NotSign => "ns",
+        };
+        write!(f, "{}", suffix)
+    }
+}
+
+/// One line of emitted assembly. Two-operand instructions take the
+/// destination first, as in Intel syntax.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Label(String),
+    /// An assembler directive, copied to the output as is.
+    Directive(String),
+    Mov(Operand, Operand),
+    /// Move a dword, sign-extending it to a quadword.
+    Movsxd(Operand, Operand),
+    /// Move a byte, sign-extending it to a quadword.
+    Movsx(Operand, Operand),
+    Lea(Operand, Operand),
+    Xchg(Operand, Operand),
+    Push(Operand),
+    Pop(Operand),
+    Add(Operand, Operand),
+    Sub(Operand, Operand),
+    Imul(Operand, Operand),
+    /// Divide rdx:rax, leaving the quotient in rax and the remainder in rdx.
+    Idiv(Operand),
+    /// Unsigned `Idiv`.
+    Div(Operand),
+    /// Sign-extend rax into rdx.
+    Cqo,
+    And(Operand, Operand),
+    Or(Operand, Operand),
+    Xor(Operand, Operand),
+    Shl(Operand, Operand),
+    Sar(Operand, Operand),
+    Shr(Operand, Operand),
+    Neg(Operand),
+    Not(Operand),
+    Inc(Operand),
+    Cmp(Operand, Operand),
+    Test(Operand, Operand),
+    Set(Condition, Operand),
+    /// Copy the second operand, a register, into the first if the
+    /// condition holds.
+    Cmov(Condition, Operand, Operand),
+    Jmp(Operand),
+    Jcc(Condition, String),
+    Call(String),
+    Ret,
+    Ud2,
+}
+
+impl Instruction {
+    /// Whether this is a machine instruction, rather than a label or
+    /// directive.
+    pub fn is_executable(&self) -> bool {
+        !matches!(self, Instruction::Label(_) | Instruction::Directive(_))
+    }
+
+    // The register this loads or copies into and where from, if it is a
+    // plain or extending move into a register.
+    fn load(&self) -> Option<(Register, &Operand)> {
+        match self {
+            Instruction::Mov(Operand::Reg(to), from)
+            | Instruction::Mov(Operand::Dword(to), from)
+            | Instruction::Movsxd(Operand::Reg(to), from)
+            | Instruction::Movsx(Operand::Reg(to), from) => Some((*to, from)),
+            _ => None,
+        }
+    }
+}
+
+// An operand whose size isn't implied by another, which needs spelling out
+// if it is in memory. Only quadwords need it here, since `Operand` spells
+// out the rest.
+struct SizedOperand<'a>(&'a Operand);
+
+impl fmt::Display for SizedOperand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.width() == Some(Width::Qword) {
+            write!(f, "qword ")?;
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+// The operands of a two-operand instruction. A memory operand paired with
+// an immediate has its size spelled out, since neither implies it.
+struct Operands<'a>(&'a Operand, &'a Operand);
+
+impl fmt::Display for Operands<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.0, self.1) {
+            (a, b @ Operand::Imm(_)) => write!(f, "{}, {}", SizedOperand(a), b),
+            (a, b) => write!(f, "{}, {}", a, b),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::Label(name) => write!(f, "{}:", name),
+            Instruction::Directive(d) => write!(f, "{}", d),
+            Instruction::Mov(a, b) => write!(f, "mov {}", Operands(a, b)),
+            Instruction::Movsxd(a, b) => write!(f, "movsxd {}", Operands(a, b)),
+            Instruction::Movsx(a, b) => write!(f, "movsx {}", Operands(a, b)),
+            Instruction::Lea(a, b) => write!(f, "lea {}", Operands(a, b)),
+            Instruction::Xchg(a, b) => write!(f, "xchg {}", Operands(a, b)),
+            Instruction::Push(a) => write!(f, "push {}", SizedOperand(a)),
+            Instruction::Pop(a) => write!(f, "pop {}", SizedOperand(a)),
+            Instruction::Add(a, b) => write!(f, "add {}", Operands(a, b)),
+            Instruction::Sub(a, b) => write!(f, "sub {}", Operands(a, b)),
+            Instruction::Imul(a, b) => write!(f, "imul {}", Operands(a, b)),
+            Instruction::Idiv(a) => write!(f, "idiv {}", SizedOperand(a)),
+            Instruction::Div(a) => write!(f, "div {}", SizedOperand(a)),
+            Instruction::Cqo => write!(f, "cqo"),
+            Instruction::And(a, b) => write!(f, "and {}", Operands(a, b)),
+            Instruction::Or(a, b) => write!(f, "or {}", Operands(a, b)),
+            Instruction::Xor(a, b) => write!(f, "xor {}", Operands(a, b)),
+            Instruction::Shl(a, b) => write!(f, "shl {}", Operands(a, b)),
+            Instruction::Sar(a, b) => write!(f, "sar {}", Operands(a, b)),
+            Instruction::Shr(a, b) => write!(f, "shr {}", Operands(a, b)),
+            Instruction::Neg(a) => write!(f, "neg {}", SizedOperand(a)),
+            Instruction::Not(a) => write!(f, "not {}", SizedOperand(a)),
+            Instruction::Inc(a) => write!(f, "inc {}", SizedOperand(a)),
+            Instruction::Cmp(a, b) => write!(f, "cmp {}", Operands(a, b)),
+            Instruction::Test(a, b) => write!(f, "test {}", Operands(a, b)),
+            Instruction::Set(c, a) => write!(f, "set{} {}", c, a),
+            Instruction::Cmov(c, a, b) => write!(f, "cmov{} {}", c, Operands(a, b)),
+            Instruction::Jmp(a) => write!(f, "jmp {}", SizedOperand(a)),
+            Instruction::Jcc(c, label) => write!(f, "j{} {}", c, label),
+            Instruction::Call(name) => write!(f, "call {}", name),
+            Instruction::Ret => write!(f, "ret"),
+            Instruction::Ud2 => write!(f, "ud2"),
+        }
+    }
+}
+
+/// Render instructions as NASM source, one per line.
+pub fn to_nasm(code: &[Instruction]) -> String {
+    code.iter().map(|i| format!("{}\n", i)).collect()
+}
+
+/// The architecture emitted code is rendered for. Code is always generated
+/// for x86-64, and other targets translate it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// NASM source.
+    #[default]
+    X86_64,
+    /// GNU assembler source, by way of `to_aarch64`. Locals are always
+    /// addressed through the frame pointer, and SEH directives are dropped.
+    AArch64,
+}
+
+impl Target {
+    pub fn render(self, code: &[Instruction]) -> String {
+        match self {
+            Target::X86_64 => to_nasm(code),
+            Target::AArch64 => to_aarch64(code),
+        }
+    }
+}
+
+/// Tidy up the stack machine's output without changing what it computes:
+///
+/// - `push a` then `pop b` becomes `mov b, a`, or nothing if they match.
+/// - `push a`, a single load into a register other than `b`, then `pop b`
+///   becomes `mov b, a` followed by the load. This is how a binary operator
+///   with a constant or variable on the right comes out. Loads addressed
+///   through `rsp` or through `b` are left alone, since the push moves one
+///   and the copy overwrites the other.
+/// - `mov a, a` is dropped.
+pub fn peephole(code: &[Instruction]) -> Vec<Instruction> {
+    let mut out = Vec::with_capacity(code.len());
+    let mut i = 0;
+    while i < code.len() {
+        match &code[i..] {
+            [Instruction::Mov(Operand::Reg(a), Operand::Reg(b)), ..] if a == b => i += 1,
+            [Instruction::Push(Operand::Reg(a)), Instruction::Pop(Operand::Reg(b)), ..] => {
+                if a != b {
+                    out.push(Instruction::Mov(Operand::Reg(*b), Operand::Reg(*a)));
+                }
+                i += 2;
+            }
+            [Instruction::Push(Operand::Reg(a)), load, Instruction::Pop(Operand::Reg(b)), ..]
+                if load.load().is_some_and(|(to, from)| {
+                    to != *b && !from.uses(*b) && !from.uses(Register::Rsp)
+                }) =>
+            {
+                if a != b {
+                    out.push(Instruction::Mov(Operand::Reg(*b), Operand::Reg(*a)));
+                }
+                out.push(load.clone());
+                i += 3;
+            }
+            _ => {
+                out.push(code[i].clone());
+                i += 1;
+            }
+        }
+    }
+    out
+}
@@ -1,16 +1,23 @@
-use itertools::{put_back_n, PutBackN};
+use itertools::{put_back_n, Itertools, PutBackN};
 use snafu::Snafu;
 
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 
-use super::lex::{Keyword, Literal, Token};
+use super::asm::{
+    peephole, to_nasm, Condition, Instruction, Operand, Register, Register::*, Target, Width,
+};
+use super::diagnostic::{Diagnostic, Severity};
+use super::lex::{lex_spanned, Keyword, Literal, Span, Token};
+use super::print::signature;
+use super::section::{LineEnding, Section, SectionBuilder};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display(
-        "Failed trying to parse a {}.\n\tExpected one of {:?}.\n\tFound {:?} instead.\n\tRemaining context: {:?}",
+        "Failed trying to parse a {}{}.\n\tExpected one of {:?}.\n\tFound {:?} instead.\n\tRemaining context: {:?}",
         wanted,
+        at(span),
         expected,
         found,
         tokens
@@ -20,14 +27,30 @@ pub enum Error {
         expected: Vec<Token>,
         found: Token,
         tokens: Vec<Token>,
+        span: Option<Span>,
+    },
+    #[snafu(display(
+        "Failed trying to parse a {}.\n\tFound operator {:?} where an operand was expected.\n\tDid you mean the unary operator {:?}?\n\tRemaining context: {:?}",
+        wanted,
+        found,
+        suggested,
+        tokens
+    ))]
+    MisplacedOperator {
+        wanted: &'static str,
+        found: Token,
+        suggested: Token,
+        tokens: Vec<Token>,
     },
     InvalidSyntax,
     #[snafu(display(
-        "Failed trying to parse a {}.\n\tEncountered end of token stream instead.",
-        wanted
+        "Failed trying to parse a {}{}.\n\tEncountered end of token stream instead.",
+        wanted,
+        at(span)
     ))]
     UnexpectedEnd {
         wanted: &'static str,
+        span: Option<Span>,
     },
 
     #[snafu(display("Duplicate declaration of {}.", var))]
@@ -39,150 +62,3338 @@ pub enum Error {
     UndeclaredVariable {
         var: String,
     },
+
+    #[snafu(display("Use of undefined struct {}.", name))]
+    UnknownStruct {
+        name: String,
+    },
+
+    #[snafu(display("Struct {} has no member {}.", name, member))]
+    UnknownMember {
+        name: String,
+        member: String,
+    },
+
+    #[snafu(display("Use of undefined type {}.", name))]
+    UnknownType {
+        name: String,
+    },
+
+    #[snafu(display("Conflicting definitions of type {}.", name))]
+    ConflictingTypedef {
+        name: String,
+    },
+
+    #[snafu(display("Member access on {}, which is not a struct.", expr))]
+    NotAStruct {
+        expr: String,
+    },
+
+    #[snafu(display(
+        "Invalid integer literal {}.\n\tCheck its digits are valid in its base, and that it is not too large for a long.",
+        literal
+    ))]
+    InvalidLiteral {
+        literal: String,
+    },
+
+    #[snafu(display(
+        "Invalid suffix on integer literal {}.\n\tA suffix may only be u, l, ll, ul or ull.",
+        literal
+    ))]
+    InvalidSuffix {
+        literal: String,
+    },
+
+    #[snafu(display("Label {} is defined more than once in {}.", label, function))]
+    DuplicateLabel {
+        label: String,
+        function: String,
+    },
+
+    #[snafu(display("Use of undefined label {} in {}.", label, function))]
+    UndefinedLabel {
+        label: String,
+        function: String,
+    },
+
+    #[snafu(display(
+        "Function {} takes {} arguments, but only six can be passed in registers.",
+        function,
+        count
+    ))]
+    StackArguments {
+        function: String,
+        count: usize,
+    },
+
+    #[snafu(display("Function {} returns void, but returns a value.", function))]
+    ReturnValueInVoid {
+        function: String,
+    },
+
+    #[snafu(display("Function {} returns int, but returns without a value.", function))]
+    ReturnWithoutValue {
+        function: String,
+    },
+
+    #[snafu(display("Operand of {} is not an lvalue: {}.", op, expr))]
+    NotAssignable {
+        op: &'static str,
+        expr: String,
+    },
+
+    #[snafu(display("Array {} has neither a size nor an initializer.", var))]
+    IncompleteArrayType {
+        var: String,
+    },
+
+    #[snafu(display("Array {} must have a positive size.", var))]
+    InvalidArraySize {
+        var: String,
+    },
+
+    #[snafu(display("Conflicting type specifiers: {}.", specifiers))]
+    ConflictingSpecifiers {
+        specifiers: String,
+    },
+
+    #[snafu(display("Too many initializers for array {}.", var))]
+    ExcessInitializers {
+        var: String,
+    },
+
+    #[snafu(display("Cannot cast {} to {}.", from, to))]
+    InvalidCast {
+        from: String,
+        to: String,
+    },
+
+    #[snafu(display("Subscript of {}, which is not a pointer.", expr))]
+    NotAPointer {
+        expr: String,
+    },
+
+    #[snafu(display("{} is not supported yet. {}", feature, hint))]
+    Unsupported {
+        feature: &'static str,
+        hint: &'static str,
+    },
+
+    #[snafu(display("{}", diagnostic))]
+    WarningAsError {
+        diagnostic: Diagnostic,
+    },
+
+    #[snafu(display("Exceeded the limit of {} {}.", max, limit))]
+    LimitExceeded {
+        limit: &'static str,
+        max: usize,
+    },
+
+    #[snafu(display("{} statement not within a loop.", statement))]
+    NotInLoop {
+        statement: &'static str,
+    },
+
+    #[snafu(display("{} label not within a switch.", label))]
+    NotInSwitch {
+        label: &'static str,
+    },
+
+    #[snafu(display("Case label {} is not a constant.", expr))]
+    NonConstantCase {
+        expr: String,
+    },
+
+    #[snafu(display("Initializer of global {} is not a constant.", var))]
+    NonConstantInitializer {
+        var: String,
+    },
+
+    #[snafu(display("Duplicate case label {}.", label))]
+    DuplicateCase {
+        label: String,
+    },
+
+    #[snafu(display("Emitted {} instructions, over the limit of {}.", count, limit))]
+    CodeSizeExceeded {
+        count: usize,
+        limit: usize,
+    },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-static LABEL_COUNT: AtomicUsize = AtomicUsize::new(0);
+// Where a parse error happened, if known, for its message.
+fn at(span: &Option<Span>) -> String {
+    span.map_or(String::new(), |span| format!(" at {}", span))
+}
+
+impl Error {
+    /// Where in a stream of `total` tokens the offending token was, for
+    /// errors raised on a particular token.
+    pub fn token_index(&self, total: usize) -> Option<usize> {
+        match self {
+            Error::UnexpectedToken { tokens, .. } | Error::MisplacedOperator { tokens, .. } => {
+                total.checked_sub(tokens.len() + 1)
+            }
+            _ => None,
+        }
+    }
+
+    /// The position in the source of the token a parse error is about, if
+    /// it was parsed with `parse_spanned`, or of a promoted warning's
+    /// declaration.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::UnexpectedToken { span, .. } | Error::UnexpectedEnd { span, .. } => *span,
+            Error::WarningAsError { diagnostic } => diagnostic.span,
+            _ => None,
+        }
+    }
+
+    // Fill in the span of a parse error from those of the tokens parsed.
+    // Running out of tokens is reported at the last one.
+    fn locate(mut self, spans: &[Span]) -> Error {
+        let at = match self.token_index(spans.len()) {
+            Some(i) => spans.get(i),
+            None => spans.last(),
+        };
+        if let Error::UnexpectedToken { span, .. } | Error::UnexpectedEnd { span, .. } = &mut self {
+            *span = at.copied();
+        }
+        self
+    }
+}
+
+// Whether a jump table for these case values would be mostly full.
+fn is_dense(values: &[i64]) -> bool {
+    match (values.iter().min(), values.iter().max()) {
+        (Some(min), Some(max)) => values.len() >= 4 && max - min < 2 * values.len() as i64,
+        _ => false,
+    }
+}
+
+// The most stack the locals declared in `body` need at once. Sibling scopes
+// share slots. Struct and type definitions are recorded in `scratch` as they
+// are met, so that later declarations can be sized.
+fn frame_bytes(body: &[Statement], scratch: &mut Context) -> usize {
+    let mut live = 0;
+    let mut peak = 0;
+    for s in body.iter() {
+        let nested = match s {
+            Statement::Struct(name, fields) => {
+                scratch.structs.insert(name.clone(), fields.clone());
+                0
+            }
+            Statement::Typedef(ty, name) => {
+                scratch.aliases.insert(name.clone(), ty.clone());
+                0
+            }
+            Statement::Declaration(ty, _, _, _) => {
+                // Undefined types are reported when the declaration is
+                // emitted.
+                live += scratch.slot(ty).unwrap_or(0);
+                0
+            }
+            Statement::Block(body) => frame_bytes(body, scratch),
+            Statement::If(_, s1, s2) => {
+                let s1 = frame_bytes(std::slice::from_ref(&**s1), scratch);
+                let s2 = s2
+                    .as_ref()
+                    .map_or(0, |s2| frame_bytes(std::slice::from_ref(&**s2), scratch));
+                s1.max(s2)
+            }
+            Statement::While(_, body) | Statement::Switch(_, body) => {
+                frame_bytes(std::slice::from_ref(&**body), scratch)
+            }
+            // The init clause is scoped to the loop.
+            Statement::For(init, _, _, body) => {
+                let init = init.as_ref().map_or(0, |init| {
+                    frame_bytes(std::slice::from_ref(&**init), scratch)
+                });
+                init + frame_bytes(std::slice::from_ref(&**body), scratch)
+            }
+            Statement::Label(_, s) => frame_bytes(std::slice::from_ref(&**s), scratch),
+            Statement::Return(..)
+            | Statement::Expression(_)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => 0,
+        };
+        peak = peak.max(live + nested);
+    }
+    peak
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Count the instructions in emitted code, leaving out labels and
+// assembler directives.
+fn instruction_count(code: &[Instruction]) -> usize {
+    code.iter().filter(|i| i.is_executable()).count()
+}
+
+/// Tidy emitted assembly: strip the stray whitespace the format strings
+/// leave around each line and collapse runs of blank lines. CRLF line
+/// endings are kept if the input uses them.
+pub fn normalize(asm: &str) -> String {
+    let eol = if asm.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut out = String::with_capacity(asm.len());
+    let mut blank = false;
+    for line in asm.lines().map(str::trim) {
+        if line.is_empty() {
+            if !blank && !out.is_empty() {
+                out.push_str(eol);
+            }
+            blank = true;
+            continue;
+        }
 
-fn gen_label() -> String {
-    format!("__ccgen{}", LABEL_COUNT.fetch_add(1, Ordering::Relaxed))
+        out.push_str(line);
+        out.push_str(eol);
+        blank = false;
+    }
+    out
 }
 
 pub trait ASTNode: Sized + std::fmt::Debug {
     fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Self>;
-    fn emit(self, vmap: &mut HashMap<String, usize>, stack_index: &mut usize) -> Result<String>;
+    fn emit(self, ctx: &mut Context) -> Result<Vec<Instruction>>;
 }
 
-#[derive(Debug)]
-pub struct Program(Function);
+/// State threaded through code generation.
+#[derive(Debug, Default)]
+pub struct Context {
+    options: Options,
+    vmap: HashMap<String, usize>,
+    stack_index: usize,
+    // Bytes pushed since the frame was set up, for addressing off rsp.
+    depth: usize,
+    types: HashMap<String, Type>,
+    structs: HashMap<String, Vec<Field>>,
+    sections: SectionBuilder,
+    aliases: HashMap<String, Type>,
+    // The continue and break labels of each enclosing loop or switch. A
+    // switch has no continue label, so `continue` skips it.
+    loops: Vec<(Option<String>, String)>,
+    // Variables whose every access must be emitted.
+    volatiles: HashSet<String>,
+    // Names and struct tags declared in the innermost block, which may not
+    // be redeclared.
+    declared: HashSet<String>,
+    // The case labels of each enclosing switch, with None for `default`.
+    switches: Vec<Vec<(Option<i64>, String)>>,
+    // Functions in the unit declared `static`.
+    statics: HashSet<String>,
+    // The parameter types of each function defined in the unit, by name.
+    // Under `mangle` there may be several.
+    overloads: HashMap<String, Vec<Vec<Type>>>,
+    // The return type of each non-void function defined in the unit.
+    returns: HashMap<String, Type>,
+    // The symbol of the function being emitted.
+    function: String,
+    // Where a self tail call jumps under `tail_calls`: past the prologue,
+    // to where the arguments are spilled. None if the function can't take
+    // one.
+    tail_entry: Option<String>,
+    // The global variables of the unit and their types.
+    globals: HashMap<String, Type>,
+    // The number of labels generated so far. Counting per context rather
+    // than per process keeps the output of a compile deterministic.
+    labels: usize,
+}
 
-impl ASTNode for Program {
-    fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Program> {
-        Ok(Program(Function::parse(t)?))
+impl Context {
+    pub fn new() -> Context {
+        Context::with_options(Options::default())
     }
 
-    fn emit(self, vmap: &mut HashMap<String, usize>, stack_index: &mut usize) -> Result<String> {
-        self.0.emit(vmap, stack_index)
+    pub fn with_options(mut options: Options) -> Context {
+        // Pushes take 16 bytes on AArch64, which would throw off addresses
+        // relative to rsp.
+        if options.target == Target::AArch64 {
+            options.omit_frame_pointer = false;
+        }
+        let mut sections = SectionBuilder::default();
+        if let Some(align) = options.data_alignment {
+            sections.over_align(align);
+        }
+        Context {
+            options,
+            stack_index: 8,
+            sections,
+            ..Default::default()
+        }
     }
-}
 
-#[derive(Debug)]
-struct Function {
-    name: String,
-    body: Vec<Statement>,
-}
+    /// Render emitted code as assembly for the configured target, with the
+    /// configured line ending.
+    pub fn render(&self, code: &[Instruction]) -> String {
+        self.options
+            .line_ending
+            .apply(self.options.target.render(code))
+    }
 
-impl ASTNode for Function {
-    fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Function> {
-        consume_token(t, Token::Keyword(Keyword::Int))?;
+    // Memory operand for the local at the given offset below the frame base.
+    fn local(&self, offset: usize) -> Operand {
+        if !self.options.omit_frame_pointer {
+            Operand::at(Rbp, -(offset as i64))
+        } else {
+            Operand::at(Rsp, self.depth as i64 - offset as i64)
+        }
+    }
 
-        if let Token::Identifier(name) = t.next().unwrap() {
-            consume_token(t, Token::OpenParenthesis)?;
-            consume_token(t, Token::CloseParenthesis)?;
-            consume_token(t, Token::OpenBrace)?;
-            let mut body = Vec::new();
-            loop {
-                let tok = t.next().unwrap();
-                if tok == Token::CloseBrace {
-                    break;
-                }
+    // The callee-saved registers pushed by the prologue, in push order.
+    fn saved_registers(&self) -> &'static [Register] {
+        if self.options.omit_frame_pointer {
+            &[Rbx, R12, R13, R14, R15]
+        } else {
+            &[Rbx, Rbp, R12, R13, R14, R15]
+        }
+    }
 
-                t.put_back(tok);
-                body.push(Statement::parse(t)?);
-            }
+    // The assembly symbol for the function with the given name. Static
+    // functions are prefixed so they can't collide with external symbols.
+    fn symbol(&self, name: &str) -> String {
+        match self.options.renames.get(name) {
+            Some(symbol) => symbol.clone(),
+            None if self.statics.contains(name) => format!("__static_{}", name),
+            None => name.to_string(),
+        }
+    }
 
-            return Ok(Function { name, body });
+    // The symbol for the function of the given name taking the given
+    // parameter types. Under `mangle`, a code for each type is appended, or
+    // `v` if there are none. `main` is left alone, as the C runtime calls
+    // it by name.
+    fn function_symbol(&self, name: &str, params: &[Type]) -> String {
+        let symbol = self.symbol(name);
+        if !self.options.mangle || name == "main" {
+            symbol
+        } else if params.is_empty() {
+            format!("{}_v", symbol)
+        } else {
+            let codes = params.iter().map(|ty| self.type_code(ty));
+            std::iter::once(symbol).chain(codes).join("_")
         }
+    }
 
-        Err(Error::InvalidSyntax)
+    // The symbol a call to `name` with arguments of the given types goes to.
+    // Under `mangle`, that is the function defined with as many parameters,
+    // preferring one whose types match exactly. A function defined
+    // elsewhere is assumed to take the arguments' types.
+    fn call_symbol(&self, name: &str, args: &[Type]) -> String {
+        let params = self.overloads.get(name).and_then(|defined| {
+            let mut arity = defined.iter().filter(|p| p.len() == args.len());
+            arity
+                .clone()
+                .find(|p| p.as_slice() == args)
+                .or_else(|| arity.next())
+        });
+        self.function_symbol(name, params.map_or(args, Vec::as_slice))
+    }
+
+    // The code for a type in a mangled symbol, after the Itanium C++ ABI's
+    // builtin type codes. A struct or typedef name is prefixed with its
+    // length, as an Itanium source name is, so it can't be mistaken for a
+    // builtin code or run into the next parameter's.
+    fn type_code(&self, ty: &Type) -> String {
+        match self.resolve(ty.clone()) {
+            Type::Char => String::from("c"),
+            Type::Int => String::from("i"),
+            Type::UInt => String::from("j"),
+            Type::Long => String::from("l"),
+            Type::ULong => String::from("m"),
+            Type::Pointer(inner) | Type::Array(inner, _) => format!("P{}", self.type_code(&inner)),
+            Type::Struct(name) | Type::Alias(name) => format!("{}{}", name.len(), name),
+        }
+    }
+
+    // Whether calling `name` with `args` calls the function being emitted.
+    fn is_self_call(&self, name: &str, args: &[Expression]) -> bool {
+        let types = args.iter().map(|a| a.ty(self)).collect::<Vec<_>>();
+        let arity = match self.overloads.get(name) {
+            Some(overloads) => overloads.iter().any(|params| params.len() == args.len()),
+            None => false,
+        };
+        arity && self.call_symbol(name, &types) == self.function
+    }
+
+    // A fresh label, unique within the unit.
+    fn gen_label(&mut self) -> String {
+        self.labels += 1;
+        format!("__ccgen{}", self.labels - 1)
+    }
+
+    // The assembly label for a label in the source. It is prefixed with
+    // the function's symbol, so it can't clash with another function's
+    // labels or with `gen_label`.
+    fn user_label(&self, label: &str) -> String {
+        format!("{}.{}", self.function, label)
+    }
+
+    // Run `f` in a new block scope. The locals it declares go out of scope
+    // when it ends, and later scopes reuse their slots.
+    fn scoped<T, F: FnOnce(&mut Context) -> Result<T>>(&mut self, f: F) -> Result<T> {
+        let vmap = self.vmap.clone();
+        let types = self.types.clone();
+        let structs = self.structs.clone();
+        let aliases = self.aliases.clone();
+        let volatiles = self.volatiles.clone();
+        let declared = std::mem::take(&mut self.declared);
+        let stack_index = self.stack_index;
+
+        let result = f(self)?;
+        self.vmap = vmap;
+        self.types = types;
+        self.structs = structs;
+        self.aliases = aliases;
+        self.volatiles = volatiles;
+        self.declared = declared;
+        self.stack_index = stack_index;
+        Ok(result)
+    }
+
+    // Tear down the frame ahead of a `ret`. rax is left alone, so it still
+    // holds the return value at the `ret`.
+    fn epilogue(&self) -> Vec<Instruction> {
+        let mut code = vec![if self.options.omit_frame_pointer {
+            Instruction::Add(Operand::Reg(Rsp), Operand::Imm(self.depth as i64))
+        } else {
+            Instruction::Mov(Operand::Reg(Rsp), Operand::Reg(Rbp))
+        }];
+        code.extend(
+            self.saved_registers()
+                .iter()
+                .rev()
+                .map(|&r| Instruction::Pop(Operand::Reg(r))),
+        );
+        code
+    }
+
+    fn offset(&self, var: &str) -> Result<usize> {
+        self.vmap
+            .get(var)
+            .copied()
+            .ok_or_else(|| Error::UndeclaredVariable {
+                var: var.to_string(),
+            })
+    }
+
+    // The size in bytes of a value of the given type. Pointer size is fixed
+    // at 8, since every target is 64-bit.
+    fn size(&self, ty: &Type) -> Result<usize> {
+        match ty {
+            Type::Char => Ok(1),
+            Type::Int | Type::UInt => Ok(4),
+            Type::Long | Type::ULong | Type::Pointer(_) => Ok(8),
+            Type::Struct(name) => Ok(self.fields(name)?.iter().map(|f| f.width).sum()),
+            Type::Alias(name) => self.size(self.alias(name)?),
+            Type::Array(inner, n) => Ok(self.size(inner)? * n),
+        }
+    }
+
+    // The bytes a local of the given type takes in the frame, which is
+    // always a whole number of 8-byte slots.
+    fn slot(&self, ty: &Type) -> Result<usize> {
+        Ok(self.size(ty)?.div_ceil(8) * 8)
+    }
+
+    // The width a value of the given type is accessed at in memory.
+    fn width(&self, ty: &Type) -> Width {
+        match self.resolve(ty.clone()) {
+            Type::Char => Width::Byte,
+            Type::Int | Type::UInt => Width::Dword,
+            _ => Width::Qword,
+        }
+    }
+
+    // Load the value of the given type at `from` into rax, extending it to
+    // 64 bits as its type requires.
+    fn load(&self, ty: &Type, from: Operand) -> Instruction {
+        match self.resolve(ty.clone()) {
+            Type::Char => Instruction::Movsx(Operand::Reg(Rax), from.sized(Width::Byte)),
+            Type::Int => Instruction::Movsxd(Operand::Reg(Rax), from.sized(Width::Dword)),
+            Type::UInt => Instruction::Mov(Operand::Dword(Rax), from.sized(Width::Dword)),
+            _ => Instruction::Mov(Operand::Reg(Rax), from),
+        }
+    }
+
+    // Store `reg` to `to` as a value of the given type.
+    fn store(&self, ty: &Type, to: Operand, reg: Register) -> Instruction {
+        match self.width(ty) {
+            Width::Byte => Instruction::Mov(to.sized(Width::Byte), Operand::Byte(reg)),
+            Width::Dword => Instruction::Mov(to.sized(Width::Dword), Operand::Dword(reg)),
+            _ => Instruction::Mov(to, Operand::Reg(reg)),
+        }
+    }
+
+    // Follow typedefs to the type they name. Unknown names are left for
+    // emit to report.
+    fn resolve(&self, ty: Type) -> Type {
+        match ty {
+            Type::Alias(name) => match self.aliases.get(&name) {
+                Some(ty) => self.resolve(ty.clone()),
+                None => Type::Alias(name),
+            },
+            ty => ty,
+        }
+    }
+
+    fn alias(&self, name: &str) -> Result<&Type> {
+        self.aliases.get(name).ok_or_else(|| Error::UnknownType {
+            name: name.to_string(),
+        })
+    }
+
+    fn fields(&self, name: &str) -> Result<&Vec<Field>> {
+        self.structs.get(name).ok_or_else(|| Error::UnknownStruct {
+            name: name.to_string(),
+        })
+    }
+
+    fn field(&self, name: &str, member: &str) -> Result<&Field> {
+        self.fields(name)?
+            .iter()
+            .find(|f| f.name == member)
+            .ok_or_else(|| Error::UnknownMember {
+                name: name.to_string(),
+                member: member.to_string(),
+            })
+    }
+
+    fn member_offset(&self, name: &str, member: &str) -> Result<usize> {
+        Ok(self.field(name, member)?.offset)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Type {
+    Int,
+    /// `char`, a signed byte.
+    Char,
+    /// `unsigned int`, held zero-extended in a register.
+    UInt,
+    Long,
+    /// `unsigned long`.
+    ULong,
+    Struct(String),
+    /// A pointer to the inner type; `int **` nests two of these.
+    Pointer(Box<Type>),
+    /// A name introduced by `typedef`, resolved during emit.
+    Alias(String),
+    /// `n` elements of the inner type. Outside of `sizeof`, it's used as a
+    /// pointer to the first element.
+    Array(Box<Type>, usize),
+}
+
+impl Type {
+    // The number of scalars in this type: the product of an array's
+    // dimensions, or 1.
+    fn elements(&self) -> usize {
+        match self {
+            Type::Array(inner, n) => n * inner.elements(),
+            _ => 1,
+        }
+    }
+
+    // The type an array is used as in an expression: a pointer to its first
+    // element. Other types are unchanged.
+    fn decay(self) -> Type {
+        match self {
+            Type::Array(inner, _) => Type::Pointer(inner),
+            ty => ty,
+        }
     }
+}
+
+/// Qualifiers given on a declaration. `register` is only recorded, as a
+/// hint for a future register allocator, and `const` is not enforced.
+#[derive(Debug, Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Qualifiers {
+    pub(crate) register: bool,
+    pub(crate) volatile: bool,
+    pub(crate) constant: bool,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Field {
+    pub(crate) name: String,
+    /// `int`, or an array of them.
+    pub(crate) ty: Type,
+    offset: usize,
+    width: usize,
+}
+
+/// Settings that change how a program is checked and emitted.
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Fail `check`, and the compile, on any warning.
+    pub warnings_as_errors: bool,
+    /// Address locals relative to `rsp` and leave `rbp` untouched.
+    pub omit_frame_pointer: bool,
+    /// Trap with `ud2` on division by zero instead of raising SIGFPE.
+    pub check_division: bool,
+    /// Round the quotient of signed division toward negative infinity, with
+    /// the remainder taking the divisor's sign. By default the quotient is
+    /// truncated toward zero, as C requires.
+    pub floor_division: bool,
+    /// Trap with `ud2` when signed addition, subtraction or multiplication
+    /// overflows, instead of wrapping.
+    pub trap_overflow: bool,
+    /// Emit the function named by each key under the corresponding symbol,
+    /// e.g. `main` as `user_main`.
+    pub renames: HashMap<String, String>,
+    pub line_ending: LineEnding,
+    /// The architecture to render assembly for.
+    pub target: Target,
+    /// Emit Windows x64 unwind directives for the function's prologue.
+    pub seh: bool,
+    /// Fail with `CodeSizeExceeded` if a function emits more instructions.
+    pub max_instructions: Option<usize>,
+    /// Count calls to each function in a global `__prof_<name>` in `.bss`.
+    pub profile: bool,
+    /// Dispatch switches with dense case labels through a jump table in
+    /// `.rodata` instead of a chain of comparisons.
+    pub jump_tables: bool,
+    /// Align every object in the data sections to at least this many bytes.
+    pub data_alignment: Option<usize>,
+    /// Clean up each function's code with `asm::peephole`.
+    pub peephole: bool,
+    /// Reject programs over these limits before emitting anything.
+    pub limits: Limits,
+    /// Warn about `return a, b;`, which returns only `b`. Parenthesizing
+    /// the comma expression marks it as intended.
+    pub lint_return_comma: bool,
+    /// Reject functions and calls with more than six arguments, which would
+    /// be passed on the stack, for bare-metal targets with a register-only
+    /// convention. Emitted code never keeps data below rsp, so there is no
+    /// red zone for an interrupt to clobber in any case.
+    pub register_args_only: bool,
+    /// Append the parameter types to each function's symbol, as in `foo_i_i`
+    /// for `int foo(int, int)`, so that functions may share a name.
+    pub mangle: bool,
+    /// Functions that never return, besides `abort`, `exit` and `_Exit`. A
+    /// call to one ends its basic block and is followed by `ud2`.
+    pub noreturn: Vec<String>,
+    /// Check that rsp is 16-byte aligned before each call, as the ABI
+    /// requires, and trap with `ud2` if not. A debugging aid for the code
+    /// generator.
+    pub check_stack_alignment: bool,
+    /// Emit `return f(...);` within `f` itself as a jump back to the start
+    /// of `f`, so that such recursion runs in constant stack space.
+    /// Functions with arguments on the stack are left alone.
+    pub tail_calls: bool,
+    /// Emit a conditional whose branches are both constants or variables as
+    /// a `cmov` rather than branches. Both branches are evaluated.
+    pub cmov: bool,
+}
 
-    fn emit(self, vmap: &mut HashMap<String, usize>, _stack_index: &mut usize) -> Result<String> {
-        let mut stack_index = 8;
-        Ok(format!(
-            "\
-             global {0}\n\
-             {0}:\n\
-             push rbx \n\
-             push rbp\n\
-             push r12\n\
-             push r13\n\
-             push r14\n\
-             push r15\n\
-             mov rbp, rsp\n\
-             {1} \n\
-             mov rsp, rbp\n\
-             pop r15\n\
-             pop r14\n\
-             pop r13\n\
-             pop r12\n\
-             pop rbp\n\
-             pop rbx\n\
-             mov rax, 0\n\
-             ret
-             ",
-            self.name,
-            self.body
-                .into_iter()
-                .map(|s| s.emit(vmap, &mut stack_index))
-                .collect::<Result<String>>()?
-        ))
+/// Upper bounds on the size of an accepted program, for compiling untrusted
+/// input. `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    pub functions: Option<usize>,
+    pub statements: Option<usize>,
+    pub nesting: Option<usize>,
+}
+
+fn check_limit(value: usize, max: Option<usize>, limit: &'static str) -> Result<()> {
+    match max {
+        Some(max) if value > max => Err(Error::LimitExceeded { limit, max }),
+        _ => Ok(()),
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolKind {
+    Function {
+        signature: String,
+    },
+    /// A global variable.
+    Global,
+    /// A function or variable declared `static`, which only the unit itself
+    /// can refer to.
+    Static,
+}
+
+/// A global symbol defined by the emitted assembly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+}
+
+pub type SymbolTable = Vec<Symbol>;
+
+/// The basic blocks of a function and the control-flow edges between them.
+/// Block 0 is the entry.
 #[derive(Debug)]
-enum Statement {
-    Return(Expression),
-    Declaration(String, Option<Expression>),
-    Expression(Expression),
+pub struct Cfg<'a> {
+    blocks: Vec<Vec<&'a Statement>>,
+    edges: Vec<(usize, usize)>,
+    // The header of each enclosing loop and the blocks that break out of it.
+    // Switches have no header.
+    loops: Vec<(Option<usize>, Vec<usize>)>,
+    // The block dispatching each enclosing switch.
+    switches: Vec<usize>,
+    // The block starting at each label, and the blocks ending in a `goto`
+    // with their targets. Gotos are joined up once every label is known.
+    labels: HashMap<&'a str, usize>,
+    gotos: Vec<(usize, &'a str)>,
+    options: &'a Options,
 }
 
-impl ASTNode for Statement {
-    fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Statement> {
-        match t.next().ok_or(Error::UnexpectedEnd { wanted: "Keyword" })? {
-            Token::Keyword(Keyword::Return) => Ok(Statement::Return(match Expression::parse(t)? {
-                //Expression::Null => Expression::Null,
-                e => {
-                    consume_token(t, Token::Semicolon)?;
-                    e
+impl<'a> Cfg<'a> {
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn edges(&self) -> &[(usize, usize)] {
+        &self.edges
+    }
+
+    /// Whether each block can be reached from the entry.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut pending = vec![0];
+        while let Some(b) = pending.pop() {
+            if !std::mem::replace(&mut reachable[b], true) {
+                pending.extend(
+                    self.edges
+                        .iter()
+                        .filter(|&&(from, _)| from == b)
+                        .map(|&(_, to)| to),
+                );
+            }
+        }
+        reachable
+    }
+
+    fn new_block(&mut self) -> usize {
+        self.blocks.push(Vec::new());
+        self.blocks.len() - 1
+    }
+
+    // Append `s` to the open block, returning the block that is open after
+    // it, or None if control never falls through. Code after that starts a
+    // new block with no predecessors.
+    fn add(&mut self, current: Option<usize>, s: &'a Statement) -> Option<usize> {
+        let current = match current {
+            Some(b) => b,
+            None => self.new_block(),
+        };
+        if let Statement::Block(body) = s {
+            let mut current = Some(current);
+            for s in body.iter() {
+                current = self.add(current, s);
+            }
+            return current;
+        }
+        // A loop condition is jumped back to, so it starts its own block.
+        let current = match s {
+            Statement::While(_, _) if !self.blocks[current].is_empty() => {
+                let header = self.new_block();
+                self.edges.push((current, header));
+                header
+            }
+            // So is a case label, from its switch.
+            Statement::Case(_) | Statement::Default | Statement::Label(_, _) => {
+                let label = if self.blocks[current].is_empty() {
+                    current
+                } else {
+                    let label = self.new_block();
+                    self.edges.push((current, label));
+                    label
+                };
+                match s {
+                    Statement::Label(name, _) => {
+                        self.labels.insert(name, label);
+                    }
+                    _ => {
+                        if let Some(&switch) = self.switches.last() {
+                            self.edges.push((switch, label));
+                        }
+                    }
                 }
-            })),
-            Token::Keyword(Keyword::Int) => match t.next().ok_or(Error::UnexpectedEnd {
-                wanted: "Statement",
-            })? {
-                Token::Identifier(s) => match t.next().ok_or(Error::UnexpectedEnd {
-                    wanted: "Identifier",
+                label
+            }
+            _ => current,
+        };
+        self.blocks[current].push(s);
+        match s {
+            Statement::Return(..) => None,
+            Statement::Expression(Expression::Call(name, _)) if is_noreturn(name, self.options) => {
+                None
+            }
+            Statement::Label(_, s) => self.add(Some(current), s),
+            Statement::Goto(label) => {
+                self.gotos.push((current, label));
+                None
+            }
+            Statement::While(_, body) => {
+                self.loops.push((Some(current), Vec::new()));
+                let start = self.new_block();
+                self.edges.push((current, start));
+                if let Some(end) = self.add(Some(start), body) {
+                    self.edges.push((end, current));
+                }
+                let (_, breaks) = self.loops.pop().unwrap();
+                let exit = self.new_block();
+                self.edges.push((current, exit));
+                for b in breaks {
+                    self.edges.push((b, exit));
+                }
+                Some(exit)
+            }
+            // The init clause stays in the current block, and the post
+            // clause runs at the end of the body.
+            Statement::For(_, condition, _, body) => {
+                let header = self.new_block();
+                self.edges.push((current, header));
+                self.loops.push((Some(header), Vec::new()));
+                let start = self.new_block();
+                self.edges.push((header, start));
+                if let Some(end) = self.add(Some(start), body) {
+                    self.edges.push((end, header));
+                }
+                let (_, breaks) = self.loops.pop().unwrap();
+                if condition.is_none() && breaks.is_empty() {
+                    return None;
+                }
+
+                let exit = self.new_block();
+                if condition.is_some() {
+                    self.edges.push((header, exit));
+                }
+                for b in breaks {
+                    self.edges.push((b, exit));
+                }
+                Some(exit)
+            }
+            Statement::Break => {
+                if let Some((_, breaks)) = self.loops.last_mut() {
+                    breaks.push(current);
+                }
+                None
+            }
+            Statement::Continue => {
+                if let Some(header) = self.loops.iter().rev().find_map(|&(header, _)| header) {
+                    self.edges.push((current, header));
+                }
+                None
+            }
+            Statement::If(_, s1, s2) => {
+                let then = self.new_block();
+                self.edges.push((current, then));
+                let then_end = self.add(Some(then), s1);
+                let else_end = match s2 {
+                    Some(s2) => {
+                        let otherwise = self.new_block();
+                        self.edges.push((current, otherwise));
+                        self.add(Some(otherwise), s2)
+                    }
+                    None => Some(current),
+                };
+                if then_end.is_none() && else_end.is_none() {
+                    return None;
+                }
+
+                let join = self.new_block();
+                for end in then_end.into_iter().chain(else_end) {
+                    self.edges.push((end, join));
+                }
+                Some(join)
+            }
+            // The body is only entered through its case labels. Whether
+            // any label matches isn't known, so the switch may skip it.
+            Statement::Switch(_, body) => {
+                self.switches.push(current);
+                self.loops.push((None, Vec::new()));
+                let end = self.add(None, body);
+                let (_, breaks) = self.loops.pop().unwrap();
+                self.switches.pop();
+                let exit = self.new_block();
+                self.edges.push((current, exit));
+                for b in end.into_iter().chain(breaks) {
+                    self.edges.push((b, exit));
+                }
+                Some(exit)
+            }
+            // Blocks are flattened above.
+            Statement::Block(_) => unreachable!(),
+            Statement::Declaration(_, _, _, _)
+            | Statement::Expression(_)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Case(_)
+            | Statement::Default => Some(current),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program(pub(crate) Vec<Function>, pub(crate) Vec<Global>);
+
+impl Program {
+    /// The symbols the program defines, by their names in the source.
+    pub fn symbols(&self) -> SymbolTable {
+        let functions = self.0.iter().map(|f| Symbol {
+            name: f.name.clone(),
+            kind: if f.is_static {
+                SymbolKind::Static
+            } else {
+                SymbolKind::Function {
+                    signature: signature(f),
+                }
+            },
+        });
+        let globals = self.1.iter().map(|g| Symbol {
+            name: g.name.clone(),
+            kind: if g.is_static {
+                SymbolKind::Static
+            } else {
+                SymbolKind::Global
+            },
+        });
+        functions.chain(globals).collect()
+    }
+
+    /// Emit the program along with the symbols it defines, so that callers
+    /// can link against the output without re-parsing the assembly. The
+    /// symbols are named as they are in the output.
+    pub fn emit_with_symbols(self, ctx: &mut Context) -> Result<(String, SymbolTable)> {
+        let mut symbols = self.symbols();
+        // Functions are listed first, then globals.
+        let params = self
+            .0
+            .iter()
+            .map(|f| Some(f.param_types()))
+            .chain(self.1.iter().map(|_| None))
+            .collect::<Vec<_>>();
+        let code = self.emit(ctx)?;
+        for (s, params) in symbols.iter_mut().zip(params) {
+            s.name = match params {
+                Some(params) => ctx.function_symbol(&s.name, &params),
+                None => ctx.symbol(&s.name),
+            };
+        }
+        Ok((ctx.render(&code), symbols))
+    }
+
+    /// Emit the program along with a 64-bit FNV-1a hash of the output, so that
+    /// build systems can cheaply detect changes in the generated code. The
+    /// same program always hashes the same in a fresh `Context`.
+    pub fn emit_with_hash(self, ctx: &mut Context) -> Result<(String, u64)> {
+        let code = self.emit(ctx)?;
+        let asm = ctx.render(&code);
+        let hash = fnv1a(asm.as_bytes());
+        Ok((asm, hash))
+    }
+
+    /// Replace references to each name in `defs` with the corresponding
+    /// constant, unless the name is shadowed by a local declaration. This lets
+    /// hosts inject `#define`-like compile-time constants.
+    pub fn substitute_constants(&mut self, defs: &HashMap<String, i64>) {
+        for f in self.0.iter_mut() {
+            f.substitute_constants(defs);
+        }
+    }
+
+    /// Evaluate arithmetic on constants at compile time, so that e.g.
+    /// `2 + 3 * 4` is emitted as the constant 14.
+    pub fn fold_constants(&mut self) {
+        for f in self.0.iter_mut() {
+            for s in f.body.iter_mut() {
+                s.fold_constants();
+            }
+        }
+    }
+
+    /// Run the lints over the program. Warnings and notes are returned
+    /// unless `warnings_as_errors` is set, in which case the first warning
+    /// fails the check.
+    pub fn check(&self, options: &Options) -> Result<Vec<Diagnostic>> {
+        self.check_spanned(&[], options)
+    }
+
+    /// As `check`, given the tokens the program was parsed from, so that
+    /// diagnostics about a declaration point at it.
+    pub fn check_spanned(
+        &self,
+        tokens: &[(Token, Span)],
+        options: &Options,
+    ) -> Result<Vec<Diagnostic>> {
+        let mut diagnostics = self
+            .0
+            .iter()
+            .flat_map(|f| f.lint(tokens, options))
+            .collect::<Vec<_>>();
+        if options.warnings_as_errors {
+            for d in diagnostics.iter_mut() {
+                if d.severity == Severity::Warning {
+                    d.severity = Severity::Error;
+                }
+            }
+        }
+
+        match diagnostics.iter().find(|d| d.severity == Severity::Error) {
+            Some(d) => Err(Error::WarningAsError {
+                diagnostic: d.clone(),
+            }),
+            None => Ok(diagnostics),
+        }
+    }
+
+    /// Count the integer constants used by each function, by value, so that
+    /// a host can decide which ones are worth pooling.
+    pub fn constants(&self) -> HashMap<String, HashMap<i64, usize>> {
+        self.0
+            .iter()
+            .map(|f| (f.name.clone(), f.constants()))
+            .collect()
+    }
+
+    /// The variables in each function whose address is taken, which can't
+    /// be kept in registers.
+    pub fn address_taken_variables(&self) -> HashMap<String, HashSet<String>> {
+        self.0
+            .iter()
+            .map(|f| (f.name.clone(), f.address_taken_variables()))
+            .collect()
+    }
+
+    /// Estimate the peak number of simultaneously live values in any
+    /// function, for deciding how many locals to keep in registers.
+    pub fn register_pressure(&self) -> usize {
+        self.0
+            .iter()
+            .map(Function::register_pressure)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The control-flow graph of each function, in definition order.
+    /// `options` names the functions that never return.
+    pub fn cfg<'a>(&'a self, options: &'a Options) -> Vec<Cfg<'a>> {
+        self.0.iter().map(|f| f.cfg(options)).collect()
+    }
+
+    /// The most instructions any path through each function's body can
+    /// run, in definition order, taking every loop at most once. This is a
+    /// rough proxy for worst-case execution time.
+    pub fn max_path_instructions(&self, options: &Options) -> Vec<usize> {
+        self.0
+            .iter()
+            .map(|f| f.max_path_instructions(options))
+            .collect()
+    }
+
+    pub fn check_limits(&self, limits: &Limits) -> Result<()> {
+        check_limit(self.0.len(), limits.functions, "functions")?;
+        for f in self.0.iter() {
+            let statements = f.body.iter().map(Statement::statement_count).sum();
+            check_limit(statements, limits.statements, "statements per function")?;
+            check_limit(f.nesting(), limits.nesting, "levels of block nesting")?;
+        }
+        Ok(())
+    }
+
+    // Record what calls need to know about every function before reaching
+    // its definition: whether it's static, its overloads and its return
+    // type.
+    fn declare(&self, ctx: &mut Context) {
+        ctx.statics = self
+            .0
+            .iter()
+            .filter(|f| f.is_static)
+            .map(|f| f.name.clone())
+            .chain(
+                self.1
+                    .iter()
+                    .filter(|g| g.is_static)
+                    .map(|g| g.name.clone()),
+            )
+            .collect();
+        ctx.overloads.clear();
+        ctx.returns.clear();
+        for f in self.0.iter() {
+            if let Some(ty) = &f.ret {
+                ctx.returns.insert(f.name.clone(), ty.clone());
+            }
+            ctx.overloads
+                .entry(f.name.clone())
+                .or_default()
+                .push(f.param_types());
+        }
+    }
+
+    // Find the first error emitting the program would report, without
+    // generating any code. Only `CodeSizeExceeded` needs the code itself,
+    // so it's left to emit.
+    fn validate(&self, ctx: &mut Context) -> Result<()> {
+        self.check_limits(&ctx.options.limits)?;
+        self.declare(ctx);
+        let mut defined = HashSet::new();
+        for g in self.1.iter() {
+            if !defined.insert(g.name.clone()) {
+                return Err(Error::DuplicateDeclaration {
+                    var: g.name.clone(),
+                });
+            }
+            g.validate(ctx)?;
+        }
+        for f in self.0.iter() {
+            let key = if ctx.options.mangle {
+                ctx.function_symbol(&f.name, &f.param_types())
+            } else {
+                f.name.clone()
+            };
+            if !defined.insert(key) {
+                return Err(Error::DuplicateDeclaration {
+                    var: f.name.clone(),
+                });
+            }
+            f.validate(ctx)?;
+        }
+        Ok(())
+    }
+}
+
+impl ASTNode for Program {
+    fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Program> {
+        let mut functions = Vec::new();
+        let mut globals = Vec::new();
+        while let Some(tok) = t.next() {
+            t.put_back(tok);
+            if is_function(t) {
+                functions.push(Function::parse(t)?);
+            } else {
+                globals.push(Global::parse(t)?);
+            }
+        }
+        Ok(Program(functions, globals))
+    }
+
+    fn emit(self, ctx: &mut Context) -> Result<Vec<Instruction>> {
+        self.check_limits(&ctx.options.limits)?;
+        if ctx.options.warnings_as_errors {
+            self.check(&ctx.options)?;
+        }
+        self.declare(ctx);
+        let mut defined = HashSet::new();
+        // Globals are all visible from every function.
+        for g in self.1.into_iter() {
+            if !defined.insert(g.name.clone()) {
+                return Err(Error::DuplicateDeclaration { var: g.name });
+            }
+            g.emit(ctx)?;
+        }
+        for f in self.0.into_iter() {
+            // Mangled functions may share a name, but not a symbol.
+            let key = if ctx.options.mangle {
+                ctx.function_symbol(&f.name, &f.param_types())
+            } else {
+                f.name.clone()
+            };
+            if !defined.insert(key) {
+                return Err(Error::DuplicateDeclaration { var: f.name });
+            }
+            let code = f.emit(ctx)?;
+            ctx.sections.push(Section::Text, code);
+        }
+        if ctx.options.trap_overflow {
+            ctx.sections.push(
+                Section::Text,
+                vec![
+                    Instruction::Label(String::from(OVERFLOW_HANDLER)),
+                    Instruction::Ud2,
+                ],
+            );
+        }
+        if ctx.options.check_stack_alignment {
+            ctx.sections.push(
+                Section::Text,
+                vec![
+                    Instruction::Label(String::from(MISALIGNED_HANDLER)),
+                    Instruction::Ud2,
+                ],
+            );
+        }
+        Ok(std::mem::take(&mut ctx.sections).render())
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Function {
+    pub(crate) name: String,
+    pub(crate) params: Vec<(Type, String)>,
+    pub(crate) body: Vec<Statement>,
+    // Declared `static`, so not exported from the unit.
+    pub(crate) is_static: bool,
+    // The return type, or None for `void`, which leaves `rax` as it is.
+    pub(crate) ret: Option<Type>,
+}
+
+// Whether the innermost of `names` called `name` is a typedef.
+fn is_typedef(names: &[(String, bool)], name: &str) -> bool {
+    names
+        .iter()
+        .rev()
+        .find(|(n, _)| n == name)
+        .is_some_and(|(_, typedef)| *typedef)
+}
+
+// Whether the tokens after a `*` are the rest of a pointer declarator: any
+// more stars, a name, then what may follow a declared name.
+fn is_pointer_declarator<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> bool {
+    let mut seen = Vec::new();
+    for tok in t.by_ref() {
+        let star = tok == Token::Multiplication;
+        seen.push(tok);
+        if !star {
+            break;
+        }
+    }
+    let declarator = matches!(seen.last(), Some(Token::Identifier(_)))
+        && match t.next() {
+            Some(tok) => {
+                let follows = matches!(tok, Token::Semicolon | Token::Assign | Token::OpenBracket);
+                seen.push(tok);
+                follows
+            }
+            None => false,
+        };
+    for tok in seen.into_iter().rev() {
+        t.put_back(tok);
+    }
+    declarator
+}
+
+// Whether the next top-level item is a function, rather than a global. Its
+// name is followed by the parameter list.
+fn is_function<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> bool {
+    let mut seen = Vec::new();
+    for tok in t.by_ref() {
+        // The identifier after `struct` is its tag, not the name.
+        let tag = seen.last() == Some(&Token::Keyword(Keyword::Struct));
+        let name = matches!(tok, Token::Identifier(_)) && !tag;
+        seen.push(tok);
+        if name {
+            break;
+        }
+    }
+    let function = match t.next() {
+        Some(tok) => {
+            let open = tok == Token::OpenParenthesis;
+            seen.push(tok);
+            open
+        }
+        None => false,
+    };
+    for tok in seen.into_iter().rev() {
+        t.put_back(tok);
+    }
+    function
+}
+
+/// A variable declared outside any function, with static storage.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Global {
+    pub(crate) name: String,
+    pub(crate) ty: Type,
+    pub(crate) init: Option<Expression>,
+    // Declared `static`, so not exported from the unit.
+    pub(crate) is_static: bool,
+}
+
+impl ASTNode for Global {
+    fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Global> {
+        let is_static = match t.next() {
+            Some(Token::Keyword(Keyword::Static)) => true,
+            Some(tok) => {
+                t.put_back(tok);
+                false
+            }
+            None => false,
+        };
+        match Statement::parse(t)? {
+            Statement::Declaration(Type::Struct(_), _, _, _) => Err(Error::Unsupported {
+                feature: "struct globals",
+                hint: "Declare the struct in a function instead.",
+            }),
+            Statement::Declaration(Type::Array(_, _), _, _, _) => Err(Error::Unsupported {
+                feature: "array globals",
+                hint: "Declare the array in a function instead.",
+            }),
+            // The initializer is parsed as an assignment to the variable.
+            Statement::Declaration(ty, name, init, _) => Ok(Global {
+                name,
+                ty,
+                init: init.map(|e| match e {
+                    Expression::Assign(_, e) => *e,
+                    e => e,
+                }),
+                is_static,
+            }),
+            _ => Err(Error::InvalidSyntax),
+        }
+    }
+
+    // Lay out the variable in `.data`, or in `.bss` if it starts at zero.
+    fn emit(self, ctx: &mut Context) -> Result<Vec<Instruction>> {
+        let value = match self.init.map(Expression::fold) {
+            None => 0,
+            Some(Expression::Constant(c)) => c.value(),
+            Some(_) => return Err(Error::NonConstantInitializer { var: self.name }),
+        };
+        let ty = ctx.resolve(self.ty);
+        let size = ctx.size(&ty)?;
+        let symbol = ctx.symbol(&self.name);
+        let section = if value == 0 {
+            Section::Bss
+        } else {
+            Section::Data
+        };
+        if !self.is_static {
+            ctx.sections.push(
+                section,
+                vec![Instruction::Directive(format!("global {}", symbol))],
+            );
+        }
+        ctx.sections.define(&symbol);
+        ctx.sections
+            .object(section, &symbol, size, &[value.to_string()]);
+        ctx.globals.insert(self.name, ty);
+        Ok(Vec::new())
+    }
+}
+
+impl Global {
+    fn validate(&self, ctx: &mut Context) -> Result<()> {
+        if let Some(init) = &self.init {
+            if !matches!(init.clone().fold(), Expression::Constant(_)) {
+                return Err(Error::NonConstantInitializer {
+                    var: self.name.clone(),
+                });
+            }
+        }
+        let ty = ctx.resolve(self.ty.clone());
+        ctx.size(&ty)?;
+        ctx.globals.insert(self.name.clone(), ty);
+        Ok(())
+    }
+}
+
+// Library functions known never to return.
+const NORETURN: [&str; 3] = ["abort", "exit", "_Exit"];
+
+fn is_noreturn(function: &str, options: &Options) -> bool {
+    NORETURN.contains(&function) || options.noreturn.iter().any(|f| f == function)
+}
+
+// Where checked arithmetic jumps on overflow, under `trap_overflow`.
+const OVERFLOW_HANDLER: &str = "__overflow";
+
+// Where a call with a misaligned stack jumps, under `check_stack_alignment`.
+const MISALIGNED_HANDLER: &str = "__misaligned";
+
+// The System V AMD64 integer argument registers, in order.
+const ARG_REGISTERS: [Register; 6] = [Rdi, Rsi, Rdx, Rcx, R8, R9];
+
+impl Function {
+    fn param_types(&self) -> Vec<Type> {
+        self.params.iter().map(|(ty, _)| ty.clone()).collect()
+    }
+
+    // Like emit, reporting the errors it would without generating code.
+    fn validate(&self, ctx: &mut Context) -> Result<()> {
+        ctx.vmap.clear();
+        ctx.types.clear();
+        ctx.structs.clear();
+        ctx.aliases.clear();
+        ctx.declared.clear();
+        if self.params.len() > ARG_REGISTERS.len() && ctx.options.register_args_only {
+            return Err(Error::StackArguments {
+                function: self.name.clone(),
+                count: self.params.len(),
+            });
+        }
+        for (ty, param) in self.params.iter() {
+            if !ctx.declared.insert(param.clone()) {
+                return Err(Error::DuplicateDeclaration { var: param.clone() });
+            }
+            ctx.vmap.insert(param.clone(), 0);
+            ctx.types.insert(param.clone(), ty.clone());
+        }
+        for s in self.body.iter() {
+            s.validate(ctx)?;
+        }
+        Ok(())
+    }
+
+    fn substitute_constants(&mut self, defs: &HashMap<String, i64>) {
+        let locals = self
+            .body
+            .iter()
+            .filter_map(|s| match s {
+                Statement::Declaration(_, name, _, _) => Some(name.clone()),
+                _ => None,
+            })
+            .chain(self.params.iter().map(|(_, name)| name.clone()))
+            .collect::<HashSet<_>>();
+        for s in self.body.iter_mut() {
+            s.substitute_constants(defs, &locals);
+        }
+    }
+
+    fn lint(&self, tokens: &[(Token, Span)], options: &Options) -> Vec<Diagnostic> {
+        let mut diagnostics = self.lint_declarations(tokens);
+        if options.lint_return_comma {
+            diagnostics.extend(self.lint_return_comma(tokens));
+        }
+        diagnostics.extend(self.lint_uninitialized());
+        diagnostics.extend(self.lint_casts());
+        diagnostics.extend(self.lint_indices());
+        diagnostics
+    }
+
+    // Flag declarations that shadow another in an enclosing scope, and those
+    // that are never read. Reads are resolved to the declaration in scope,
+    // so an inner one doesn't count as a read of the outer.
+    fn lint_declarations(&self, tokens: &[(Token, Span)]) -> Vec<Diagnostic> {
+        let mut scopes = Scopes::default();
+        for (_, name) in self.params.iter() {
+            scopes.declare(name);
+        }
+        scopes.enter();
+        for s in self.body.iter() {
+            scopes.statement(s);
+        }
+
+        let at = |d: &Declared| declaration_span(tokens, &self.name, &d.name, d.index);
+        let shadowing = scopes.shadowing.iter().map(|&i| {
+            let d = &scopes.declared[i];
+            Diagnostic::warning(
+                "shadowing",
+                format!("Variable {} shadows an earlier declaration.", d.name),
+            )
+            .with_span(at(d))
+        });
+        let unused = scopes.declared[self.params.len()..]
+            .iter()
+            .filter(|d| !d.read)
+            .map(|d| {
+                Diagnostic::warning(
+                    "unused-variable",
+                    format!("Variable {} is never read.", d.name),
+                )
+                .with_span(at(d))
+            });
+        shadowing.chain(unused).collect()
+    }
+
+    // Flag returns of a comma expression that isn't parenthesized.
+    fn lint_return_comma(&self, tokens: &[(Token, Span)]) -> Vec<Diagnostic> {
+        let mut returns = Vec::new();
+        for s in self.body.iter() {
+            s.returns(&mut returns);
+        }
+        let returns_at = function_start(tokens, &self.name).map_or(Vec::new(), |start| {
+            tokens[start..]
+                .iter()
+                .filter(|(t, _)| *t == Token::Keyword(Keyword::Return))
+                .map(|&(_, span)| span)
+                .take(returns.len())
+                .collect()
+        });
+        returns
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| matches!(r, Statement::Return(_, true)))
+            .map(|(i, _)| {
+                Diagnostic::warning(
+                    "return-comma",
+                    String::from("Return of a comma expression returns only its last operand."),
+                )
+                .with_span(returns_at.get(i).copied())
+            })
+            .collect()
+    }
+
+    // Flag casts from a pointer to `int`, which drop the upper half of the
+    // address, and note casts to the operand's own type, which do nothing.
+    fn lint_casts(&self) -> Vec<Diagnostic> {
+        let mut scratch = Context::new();
+        for s in self.body.iter() {
+            s.declared_types(&mut scratch.types);
+        }
+        scratch.types.extend(
+            self.params
+                .iter()
+                .map(|(ty, name)| (name.clone(), ty.clone())),
+        );
+        let mut diagnostics = Vec::new();
+        for s in self.body.iter() {
+            s.walk(&mut |e| {
+                if let Expression::Cast(ty, inner) = e {
+                    match (ty, inner.ty(&scratch)) {
+                        (Type::Int, Type::Pointer(_)) | (Type::UInt, Type::Pointer(_)) => {
+                            diagnostics.push(Diagnostic::warning(
+                                "pointer-to-int-cast",
+                                format!("Cast of pointer {:?} to int truncates it.", inner),
+                            ))
+                        }
+                        (to, from) if *to == from => diagnostics.push(Diagnostic::note(
+                            "redundant-cast",
+                            format!("Cast of {:?} to its own type does nothing.", inner),
+                        )),
+                        _ => {}
+                    }
+                }
+            });
+        }
+        diagnostics
+    }
+
+    // Flag subscripts by a negative constant, which reach before the start
+    // of the object. Indices computed at run time aren't checked.
+    fn lint_indices(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for s in self.body.iter() {
+            s.walk(&mut |e| {
+                if let Expression::Index(base, index) = e {
+                    match index.eval_const() {
+                        Some(i) if i < 0 => diagnostics.push(Diagnostic::warning(
+                            "negative-index",
+                            format!("Index {} of {:?} is before its first element.", i, base),
+                        )),
+                        _ => {}
+                    }
+                }
+            });
+        }
+        diagnostics
+    }
+
+    // Flag reads of scalars declared without an initializer that are not
+    // assigned on every path to the read.
+    fn lint_uninitialized(&self) -> Vec<Diagnostic> {
+        let mut uninitialized = HashSet::new();
+        let mut diagnostics = Vec::new();
+        for s in self.body.iter() {
+            s.lint_uninitialized(&mut uninitialized, &mut diagnostics);
+        }
+        diagnostics
+    }
+
+    // Locals and parameters whose address is taken with `&`. These must stay
+    // in memory, and may be reached after the function returns.
+    fn address_taken_variables(&self) -> HashSet<String> {
+        let mut taken = HashSet::new();
+        for s in self.body.iter() {
+            s.walk(&mut |e| {
+                if let Expression::Unary(UnaryOperator::AddressOf, e) = e {
+                    if let Some(v) = e.frame_var() {
+                        taken.insert(v.clone());
+                    }
+                }
+            });
+        }
+        taken
+    }
+
+    fn constants(&self) -> HashMap<i64, usize> {
+        let mut counts = HashMap::new();
+        for s in self.body.iter() {
+            s.walk(&mut |e| {
+                if let Expression::Constant(c) = e {
+                    *counts.entry(c.value()).or_insert(0) += 1;
+                }
+            });
+        }
+        counts
+    }
+
+    fn nesting(&self) -> usize {
+        block_nesting(&self.body)
+    }
+
+    fn cfg<'a>(&'a self, options: &'a Options) -> Cfg<'a> {
+        let mut cfg = Cfg {
+            blocks: Vec::new(),
+            edges: Vec::new(),
+            loops: Vec::new(),
+            switches: Vec::new(),
+            labels: HashMap::new(),
+            gotos: Vec::new(),
+            options,
+        };
+        let mut current = Some(cfg.new_block());
+        for s in self.body.iter() {
+            current = cfg.add(current, s);
+        }
+        for (from, label) in std::mem::take(&mut cfg.gotos) {
+            if let Some(&to) = cfg.labels.get(label) {
+                cfg.edges.push((from, to));
+            }
+        }
+        cfg
+    }
+
+    // The longest path through the CFG, weighing each block by the
+    // instructions its statements emit. Blocks are numbered in program
+    // order, so every edge to a lower block closes a loop. Such an edge is
+    // redirected to wherever its target exits to, so that a path runs the
+    // loop once and then leaves it.
+    fn max_path_instructions(&self, options: &Options) -> usize {
+        let mut scratch = Context::new();
+        frame_bytes(&self.body, &mut scratch);
+        let mut types = HashMap::new();
+        for s in self.body.iter() {
+            s.declared_types(&mut types);
+        }
+        types.extend(
+            self.params
+                .iter()
+                .map(|(ty, name)| (name.clone(), ty.clone())),
+        );
+        for (i, name) in types.keys().enumerate() {
+            scratch.vmap.insert(name.clone(), 8 * (i + 1));
+        }
+        scratch.types = types;
+
+        let cfg = self.cfg(options);
+        let cost = cfg
+            .blocks
+            .iter()
+            .map(|b| b.iter().map(|s| s.own_instructions(&mut scratch)).sum())
+            .collect::<Vec<usize>>();
+        let mut longest: Vec<Option<usize>> = vec![None; cost.len()];
+        longest[0] = Some(cost[0]);
+        for b in 0..cost.len() {
+            let here = match longest[b] {
+                Some(here) => here,
+                None => continue,
+            };
+            for &(from, to) in cfg.edges.iter().filter(|&&(from, _)| from == b) {
+                let targets = if to > from {
+                    vec![to]
+                } else {
+                    cfg.edges
+                        .iter()
+                        .filter(|&&(exit_from, exit)| exit_from == to && exit > from)
+                        .map(|&(_, exit)| exit)
+                        .collect()
+                };
+                for to in targets {
+                    let through = here + cost[to];
+                    if longest[to] < Some(through) {
+                        longest[to] = Some(through);
+                    }
+                }
+            }
+        }
+        longest.into_iter().flatten().max().unwrap_or(0)
+    }
+
+    // Estimate the peak number of values live at once: the locals declared
+    // so far, which are all assumed live to the end of the function, plus
+    // the temporaries needed by the statement being evaluated.
+    fn register_pressure(&self) -> usize {
+        let mut locals = self.params.len();
+        let mut peak = 0;
+        for s in self.body.iter() {
+            peak = peak.max(locals + s.eval_depth());
+            match s {
+                Statement::Declaration(Type::Struct(_), _, _, _)
+                | Statement::Declaration(Type::Array(_, _), _, _, _) => {}
+                Statement::Declaration(_, _, _, _) => locals += 1,
+                _ => {}
+            }
+        }
+        peak
+    }
+}
+
+impl ASTNode for Function {
+    fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Function> {
+        let mut tok = t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "Function",
+            span: None,
+        })?;
+        let is_static = tok == Token::Keyword(Keyword::Static);
+        if is_static {
+            tok = t.next().ok_or(Error::UnexpectedEnd {
+                wanted: "Function",
+                span: None,
+            })?;
+        }
+        let base = match tok {
+            Token::Keyword(Keyword::Int) => Some(Type::Int),
+            Token::Keyword(Keyword::Void) => None,
+            Token::Keyword(Keyword::Struct) => match t.next() {
+                Some(Token::Identifier(name)) => Some(Type::Struct(name)),
+                _ => return Err(Error::InvalidSyntax),
+            },
+            tok => {
+                return Err(Error::UnexpectedToken {
+                    wanted: "Function",
+                    expected: vec![Token::Keyword(Keyword::Int), Token::Keyword(Keyword::Void)],
+                    found: tok,
+                    tokens: t.collect(),
+                    span: None,
+                })
+            }
+        };
+        // Pointers are returned like any other scalar, so only a bare struct
+        // is out. The name is put back to be parsed below.
+        let ret = match base {
+            Some(ty) => match parse_declarator(t, ty)? {
+                (Type::Struct(_), _) => {
+                    return Err(Error::Unsupported {
+                        feature: "struct by value",
+                        hint: "Return a pointer to the struct instead.",
+                    })
+                }
+                (ty, name) => {
+                    t.put_back(Token::Identifier(name));
+                    Some(ty)
+                }
+            },
+            None => None,
+        };
+
+        if let Token::Identifier(name) = t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "Function",
+            span: None,
+        })? {
+            consume_token(t, Token::OpenParenthesis)?;
+            let mut params = Vec::new();
+            match t.next() {
+                Some(Token::CloseParenthesis) => {}
+                Some(Token::Keyword(Keyword::Void)) => consume_token(t, Token::CloseParenthesis)?,
+                Some(tok) => {
+                    t.put_back(tok);
+                    loop {
+                        let ty = match t.next().ok_or(Error::UnexpectedEnd {
+                            wanted: "Parameter",
+                            span: None,
+                        })? {
+                            tok if is_specifier(&tok) => parse_specifiers(t, tok)?.0,
+                            Token::Keyword(Keyword::Struct) => match t.next() {
+                                Some(Token::Identifier(name)) => Type::Struct(name),
+                                _ => return Err(Error::InvalidSyntax),
+                            },
+                            Token::Ellipsis => {
+                                return Err(Error::Unsupported {
+                                    feature: "variadic functions",
+                                    hint: "Declare a fixed parameter list instead.",
+                                })
+                            }
+                            tok => {
+                                return Err(Error::UnexpectedToken {
+                                    wanted: "Parameter",
+                                    expected: vec![
+                                        Token::Keyword(Keyword::Int),
+                                        Token::Keyword(Keyword::Char),
+                                        Token::Keyword(Keyword::Long),
+                                        Token::Keyword(Keyword::Unsigned),
+                                        Token::Keyword(Keyword::Struct),
+                                    ],
+                                    found: tok,
+                                    tokens: t.collect(),
+                                    span: None,
+                                })
+                            }
+                        };
+                        match parse_declarator(t, ty)? {
+                            (Type::Struct(_), _) => {
+                                return Err(Error::Unsupported {
+                                    feature: "struct by value",
+                                    hint: "Pass a pointer to the struct instead.",
+                                })
+                            }
+                            param => params.push(param),
+                        }
+                        match t.next() {
+                            Some(Token::Comma) => {}
+                            Some(Token::CloseParenthesis) => break,
+                            _ => return Err(Error::InvalidSyntax),
+                        }
+                    }
+                }
+                None => {
+                    return Err(Error::UnexpectedEnd {
+                        wanted: "Function",
+                        span: None,
+                    })
+                }
+            }
+            consume_token(t, Token::OpenBrace)?;
+            let mut body = Vec::new();
+            loop {
+                let tok = t.next().ok_or(Error::UnexpectedEnd {
+                    wanted: "Function body",
+                    span: None,
+                })?;
+                if tok == Token::CloseBrace {
+                    break;
+                }
+
+                t.put_back(tok);
+                body.push(Statement::parse(t)?);
+            }
+
+            let mut names = params.iter().map(|(_, n)| (n.clone(), false)).collect();
+            for s in body.iter_mut() {
+                s.reread_products(&mut names);
+            }
+
+            let (mut labels, mut gotos) = (Vec::new(), Vec::new());
+            for s in body.iter() {
+                s.labels(&mut labels, &mut gotos);
+            }
+            let mut defined = HashSet::new();
+            for label in labels {
+                if !defined.insert(label) {
+                    return Err(Error::DuplicateLabel {
+                        label: label.clone(),
+                        function: name,
+                    });
+                }
+            }
+            if let Some(label) = gotos.into_iter().find(|l| !defined.contains(l)) {
+                return Err(Error::UndefinedLabel {
+                    label: label.clone(),
+                    function: name,
+                });
+            }
+
+            let mut returns = Vec::new();
+            for s in body.iter() {
+                s.returns(&mut returns);
+            }
+            for r in returns {
+                match r {
+                    Statement::Return(Some(_), _) if ret.is_none() => {
+                        return Err(Error::ReturnValueInVoid { function: name })
+                    }
+                    Statement::Return(None, _) if ret.is_some() => {
+                        return Err(Error::ReturnWithoutValue { function: name })
+                    }
+                    _ => {}
+                }
+            }
+
+            return Ok(Function {
+                name,
+                params,
+                body,
+                is_static,
+                ret,
+            });
+        }
+
+        Err(Error::InvalidSyntax)
+    }
+
+    fn emit(self, ctx: &mut Context) -> Result<Vec<Instruction>> {
+        ctx.stack_index = 8;
+        ctx.depth = 0;
+        ctx.vmap.clear();
+        ctx.types.clear();
+        ctx.structs.clear();
+        ctx.aliases.clear();
+        ctx.volatiles.clear();
+        ctx.declared.clear();
+        let symbol = ctx.function_symbol(&self.name, &self.param_types());
+        ctx.sections.define(&symbol);
+        ctx.function = symbol.clone();
+        let seh = ctx.options.seh;
+        let saved = ctx.saved_registers();
+
+        let mut prologue = Vec::new();
+        if seh {
+            prologue.push(Instruction::Directive(format!(".seh_proc {}", symbol)));
+        }
+        for &reg in saved {
+            prologue.push(Instruction::Push(Operand::Reg(reg)));
+            if seh {
+                prologue.push(Instruction::Directive(format!(".seh_pushreg {}", reg)));
+            }
+        }
+        if !ctx.options.omit_frame_pointer {
+            prologue.push(Instruction::Mov(Operand::Reg(Rbp), Operand::Reg(Rsp)));
+            if seh {
+                prologue.push(Instruction::Directive(String::from(".seh_setframe rbp, 0")));
+            }
+        }
+
+        // Reserve every local's slot up front, rounded so that rsp is
+        // 16-byte aligned between statements. The return address and saved
+        // registers sit above the frame.
+        let mut scratch = Context {
+            structs: ctx.structs.clone(),
+            aliases: ctx.aliases.clone(),
+            ..Default::default()
+        };
+        let locals = 8 * self.params.len() + frame_bytes(&self.body, &mut scratch);
+        let above = 8 * (saved.len() + 1);
+        let frame = locals + (16 - (above + locals) % 16) % 16;
+        if frame > 0 {
+            prologue.push(Instruction::Sub(
+                Operand::Reg(Rsp),
+                Operand::Imm(frame as i64),
+            ));
+            if seh {
+                prologue.push(Instruction::Directive(format!(".seh_stackalloc {}", frame)));
+            }
+        }
+        ctx.depth = frame;
+        if seh {
+            prologue.push(Instruction::Directive(String::from(".seh_endprologue")));
+        }
+        if ctx.options.profile {
+            let counter = format!("__prof_{}", symbol);
+            ctx.sections.push(
+                Section::Bss,
+                vec![Instruction::Directive(format!("global {}", counter))],
+            );
+            ctx.sections
+                .object(Section::Bss, &counter, 8, &[String::from("0")]);
+            prologue.push(Instruction::Inc(Operand::Rel(counter, Width::Qword)));
+        }
+        // Spill the arguments so parameters live in the frame like locals.
+        if self.params.len() > ARG_REGISTERS.len() && ctx.options.register_args_only {
+            return Err(Error::StackArguments {
+                function: self.name,
+                count: self.params.len(),
+            });
+        }
+        ctx.tail_entry = None;
+        if ctx.options.tail_calls && self.params.len() <= ARG_REGISTERS.len() {
+            let entry = ctx.gen_label();
+            prologue.push(Instruction::Label(entry.clone()));
+            ctx.tail_entry = Some(entry);
+        }
+        for (i, (ty, param)) in self.params.into_iter().enumerate() {
+            if !ctx.declared.insert(param.clone()) {
+                return Err(Error::DuplicateDeclaration { var: param });
+            }
+            let reg = match ARG_REGISTERS.get(i) {
+                Some(&reg) => reg,
+                // The rest were passed on the stack, above the return
+                // address.
+                None => {
+                    let above = ctx.depth + 8 * (saved.len() + 1 + i - ARG_REGISTERS.len());
+                    prologue.push(Instruction::Mov(
+                        Operand::Reg(Rax),
+                        Operand::at(Rsp, above as i64),
+                    ));
+                    Rax
+                }
+            };
+            prologue.push(ctx.store(&ty, ctx.local(ctx.stack_index), reg));
+            ctx.vmap.insert(param.clone(), ctx.stack_index);
+            ctx.types.insert(param, ty);
+            ctx.stack_index += 8;
+        }
+
+        let mut out = Vec::new();
+        if !self.is_static {
+            out.push(Instruction::Directive(format!("global {}", symbol)));
+        }
+        out.push(Instruction::Label(symbol));
+        out.extend(prologue);
+        for s in self.body.into_iter() {
+            out.extend(s.emit(ctx)?);
+        }
+        out.extend(ctx.epilogue());
+        if self.ret.is_some() {
+            out.push(Instruction::Mov(Operand::Reg(Rax), Operand::Imm(0)));
+        }
+        out.push(Instruction::Ret);
+        if seh {
+            out.push(Instruction::Directive(String::from(".seh_endproc")));
+        }
+        if ctx.options.peephole {
+            out = peephole(&out);
+        }
+
+        if let Some(limit) = ctx.options.max_instructions {
+            let count = instruction_count(&out);
+            if count > limit {
+                return Err(Error::CodeSizeExceeded { count, limit });
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Statement {
+    /// `return e;`, or a bare `return;`. Set if `e` is a comma expression
+    /// written without parentheses, as in `return 1, 2;`.
+    Return(Option<Expression>, bool),
+    Declaration(Type, String, Option<Expression>, Qualifiers),
+    Expression(Expression),
+    Struct(String, Vec<Field>),
+    Typedef(Type, String),
+    If(Expression, Box<Statement>, Option<Box<Statement>>),
+    While(Expression, Box<Statement>),
+    /// Init, condition, post and body. A missing condition is always true.
+    For(
+        Option<Box<Statement>>,
+        Option<Expression>,
+        Option<Expression>,
+        Box<Statement>,
+    ),
+    Break,
+    Continue,
+    Block(Vec<Statement>),
+    Switch(Expression, Box<Statement>),
+    /// A `case` label, marking the statement after it.
+    Case(i64),
+    Default,
+    /// A statement with a label that `goto` can jump to.
+    Label(String, Box<Statement>),
+    Goto(String),
+}
+
+impl Statement {
+    // Turn each `a *b;` that parsed as a declaration back into a product
+    // where `a` doesn't name a type. `names` holds the names in scope, each
+    // with whether it is a typedef, innermost last.
+    fn reread_products(&mut self, names: &mut Vec<(String, bool)>) {
+        match self {
+            Statement::Declaration(Type::Pointer(inner), name, None, _) => {
+                let mut rhs = Expression::Var(name.clone());
+                let mut base = &**inner;
+                while let Type::Pointer(inner) = base {
+                    rhs = Expression::Unary(UnaryOperator::Dereference, Box::new(rhs));
+                    base = inner;
+                }
+                match base {
+                    Type::Alias(a) if !is_typedef(names, a) => {
+                        let lhs = Box::new(Expression::Var(a.clone()));
+                        *self = Statement::Expression(Expression::Binary(
+                            BinaryOperator::Multiplication,
+                            lhs,
+                            Box::new(rhs),
+                        ))
+                    }
+                    _ => names.push((name.clone(), false)),
+                }
+            }
+            Statement::Declaration(_, name, _, _) => names.push((name.clone(), false)),
+            Statement::Typedef(_, name) => names.push((name.clone(), true)),
+            Statement::If(_, s1, s2) => {
+                s1.reread_products(names);
+                if let Some(s2) = s2 {
+                    s2.reread_products(names);
+                }
+            }
+            Statement::While(_, body) | Statement::Switch(_, body) | Statement::Label(_, body) => {
+                body.reread_products(names)
+            }
+            Statement::For(init, _, _, body) => {
+                let depth = names.len();
+                if let Some(init) = init {
+                    init.reread_products(names);
+                }
+                body.reread_products(names);
+                names.truncate(depth);
+            }
+            Statement::Block(body) => {
+                let depth = names.len();
+                for s in body.iter_mut() {
+                    s.reread_products(names);
+                }
+                names.truncate(depth);
+            }
+            Statement::Return(_, _)
+            | Statement::Expression(_)
+            | Statement::Struct(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => {}
+        }
+    }
+
+    fn substitute_constants(&mut self, defs: &HashMap<String, i64>, locals: &HashSet<String>) {
+        match self {
+            Statement::Return(Some(e), _)
+            | Statement::Declaration(_, _, Some(e), _)
+            | Statement::Expression(e) => e.substitute_constants(defs, locals),
+            Statement::If(c, s1, s2) => {
+                c.substitute_constants(defs, locals);
+                s1.substitute_constants(defs, locals);
+                if let Some(s2) = s2 {
+                    s2.substitute_constants(defs, locals);
+                }
+            }
+            Statement::While(c, body) | Statement::Switch(c, body) => {
+                c.substitute_constants(defs, locals);
+                body.substitute_constants(defs, locals);
+            }
+            // A variable declared by the initializer is in scope for the
+            // rest of the loop.
+            Statement::For(init, c, post, body) => {
+                let mut locals = locals.clone();
+                if let Some(init) = init {
+                    init.substitute_constants(defs, &locals);
+                    if let Statement::Declaration(_, name, _, _) = &**init {
+                        locals.insert(name.clone());
+                    }
+                }
+                for e in c.iter_mut().chain(post.iter_mut()) {
+                    e.substitute_constants(defs, &locals);
+                }
+                body.substitute_constants(defs, &locals);
+            }
+            Statement::Block(body) => {
+                let mut locals = locals.clone();
+                locals.extend(body.iter().filter_map(|s| match s {
+                    Statement::Declaration(_, name, _, _) => Some(name.clone()),
+                    _ => None,
+                }));
+                for s in body.iter_mut() {
+                    s.substitute_constants(defs, &locals);
+                }
+            }
+            Statement::Label(_, s) => s.substitute_constants(defs, locals),
+            Statement::Return(None, _)
+            | Statement::Declaration(_, _, None, _)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => {}
+        }
+    }
+
+    fn fold_constants(&mut self) {
+        let fold = |e: &mut Expression| {
+            *e = std::mem::replace(e, Expression::Constant(Constant::Int(0))).fold()
+        };
+        match self {
+            Statement::Return(Some(e), _)
+            | Statement::Declaration(_, _, Some(e), _)
+            | Statement::Expression(e) => fold(e),
+            Statement::If(c, s1, s2) => {
+                fold(c);
+                s1.fold_constants();
+                if let Some(s2) = s2 {
+                    s2.fold_constants();
+                }
+            }
+            Statement::While(c, body) | Statement::Switch(c, body) => {
+                fold(c);
+                body.fold_constants();
+            }
+            Statement::For(init, c, post, body) => {
+                if let Some(init) = init {
+                    init.fold_constants();
+                }
+                for e in c.iter_mut().chain(post.iter_mut()) {
+                    fold(e);
+                }
+                body.fold_constants();
+            }
+            Statement::Block(body) => {
+                for s in body.iter_mut() {
+                    s.fold_constants();
+                }
+            }
+            Statement::Label(_, s) => s.fold_constants(),
+            Statement::Return(None, _)
+            | Statement::Declaration(_, _, None, _)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => {}
+        }
+    }
+
+    // Visit every expression in this statement.
+    fn walk<F: FnMut(&Expression)>(&self, f: &mut F) {
+        match self {
+            Statement::Return(Some(e), _)
+            | Statement::Declaration(_, _, Some(e), _)
+            | Statement::Expression(e) => e.walk(f),
+            Statement::If(c, s1, s2) => {
+                c.walk(f);
+                s1.walk(f);
+                if let Some(s2) = s2 {
+                    s2.walk(f);
+                }
+            }
+            Statement::While(c, body) | Statement::Switch(c, body) => {
+                c.walk(f);
+                body.walk(f);
+            }
+            Statement::For(init, c, post, body) => {
+                if let Some(init) = init {
+                    init.walk(f);
+                }
+                for e in c.iter().chain(post.iter()) {
+                    e.walk(f);
+                }
+                body.walk(f);
+            }
+            Statement::Block(body) => {
+                for s in body.iter() {
+                    s.walk(f);
+                }
+            }
+            Statement::Label(_, s) => s.walk(f),
+            Statement::Return(None, _)
+            | Statement::Declaration(_, _, None, _)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => {}
+        }
+    }
+
+    // Record the type of every variable declared in this statement, in any
+    // scope.
+    fn declared_types(&self, types: &mut HashMap<String, Type>) {
+        match self {
+            Statement::Declaration(ty, name, _, _) => {
+                types.insert(name.clone(), ty.clone());
+            }
+            Statement::If(_, s1, s2) => {
+                s1.declared_types(types);
+                if let Some(s2) = s2 {
+                    s2.declared_types(types);
+                }
+            }
+            Statement::While(_, body) | Statement::Switch(_, body) => body.declared_types(types),
+            Statement::For(init, _, _, body) => {
+                if let Some(init) = init {
+                    init.declared_types(types);
+                }
+                body.declared_types(types);
+            }
+            Statement::Block(body) => {
+                for s in body.iter() {
+                    s.declared_types(types);
+                }
+            }
+            Statement::Label(_, s) => s.declared_types(types),
+            Statement::Return(..)
+            | Statement::Expression(_)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => {}
+        }
+    }
+
+    // The instructions this statement adds to its own basic block, leaving
+    // out nested statements. Expressions that fail to emit count as none.
+    fn own_instructions(&self, scratch: &mut Context) -> usize {
+        fn count(e: &Expression, scratch: &mut Context) -> usize {
+            e.clone()
+                .emit(scratch)
+                .map_or(0, |code| instruction_count(&code))
+        }
+
+        match self {
+            Statement::Return(e, _) => {
+                e.as_ref().map_or(0, |e| count(e, scratch))
+                    + instruction_count(&scratch.epilogue())
+                    + 1
+            }
+            Statement::Expression(e) => count(e, scratch),
+            Statement::Declaration(_, _, Some(e), _) => count(e, scratch),
+            // Testing the condition takes a compare and a branch.
+            Statement::If(c, _, _) | Statement::While(c, _) | Statement::Switch(c, _) => {
+                count(c, scratch) + 2
+            }
+            Statement::For(init, c, post, _) => {
+                init.as_ref()
+                    .map_or(0, |init| init.own_instructions(scratch))
+                    + c.as_ref().map_or(0, |c| count(c, scratch) + 2)
+                    + post.as_ref().map_or(0, |post| count(post, scratch))
+                    + 1
+            }
+            Statement::Break | Statement::Continue | Statement::Goto(_) => 1,
+            Statement::Declaration(_, _, None, _)
+            | Statement::Block(_)
+            | Statement::Label(_, _)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Case(_)
+            | Statement::Default => 0,
+        }
+    }
+
+    // Warn about reads of variables in `uninitialized`, and update it with
+    // the declarations and assignments in this statement. After an `if`, a
+    // variable stays uninitialized unless both branches assign it, and a
+    // loop body may not run at all.
+    fn lint_uninitialized(
+        &self,
+        uninitialized: &mut HashSet<String>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        match self {
+            Statement::Declaration(Type::Struct(_), _, _, _)
+            | Statement::Declaration(Type::Array(_, _), _, None, _) => {}
+            Statement::Declaration(_, name, None, _) => {
+                uninitialized.insert(name.clone());
+            }
+            Statement::Return(Some(e), _)
+            | Statement::Declaration(_, _, Some(e), _)
+            | Statement::Expression(e) => e.lint_uninitialized(uninitialized, diagnostics),
+            Statement::If(c, s1, s2) => {
+                c.lint_uninitialized(uninitialized, diagnostics);
+                let mut after_else = uninitialized.clone();
+                s1.lint_uninitialized(uninitialized, diagnostics);
+                if let Some(s2) = s2 {
+                    s2.lint_uninitialized(&mut after_else, diagnostics);
+                }
+                uninitialized.extend(after_else);
+            }
+            Statement::While(c, body) | Statement::Switch(c, body) => {
+                c.lint_uninitialized(uninitialized, diagnostics);
+                let skipped = uninitialized.clone();
+                body.lint_uninitialized(uninitialized, diagnostics);
+                uninitialized.extend(skipped);
+            }
+            Statement::For(init, c, post, body) => {
+                if let Some(init) = init {
+                    init.lint_uninitialized(uninitialized, diagnostics);
+                }
+                if let Some(c) = c {
+                    c.lint_uninitialized(uninitialized, diagnostics);
+                }
+                let skipped = uninitialized.clone();
+                body.lint_uninitialized(uninitialized, diagnostics);
+                if let Some(post) = post {
+                    post.lint_uninitialized(uninitialized, diagnostics);
+                }
+                uninitialized.extend(skipped);
+            }
+            Statement::Block(body) => {
+                for s in body.iter() {
+                    s.lint_uninitialized(uninitialized, diagnostics);
+                }
+            }
+            Statement::Label(_, s) => s.lint_uninitialized(uninitialized, diagnostics),
+            Statement::Return(None, _)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => {}
+        }
+    }
+
+    fn eval_depth(&self) -> usize {
+        match self {
+            Statement::Return(Some(e), _)
+            | Statement::Declaration(_, _, Some(e), _)
+            | Statement::Expression(e) => e.eval_depth(),
+            Statement::If(c, s1, s2) => c
+                .eval_depth()
+                .max(s1.eval_depth())
+                .max(s2.as_ref().map_or(0, |s2| s2.eval_depth())),
+            Statement::While(c, body) | Statement::Switch(c, body) => {
+                c.eval_depth().max(body.eval_depth())
+            }
+            Statement::For(init, c, post, body) => c
+                .iter()
+                .chain(post.iter())
+                .map(Expression::eval_depth)
+                .chain(init.iter().map(|init| init.eval_depth()))
+                .fold(body.eval_depth(), usize::max),
+            Statement::Block(body) => body.iter().map(Statement::eval_depth).max().unwrap_or(0),
+            Statement::Label(_, s) => s.eval_depth(),
+            Statement::Return(None, _)
+            | Statement::Declaration(_, _, None, _)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => 0,
+        }
+    }
+
+    // Collect the labels defined in this statement, and the targets of its
+    // `goto`s.
+    fn labels<'a>(&'a self, labels: &mut Vec<&'a String>, gotos: &mut Vec<&'a String>) {
+        match self {
+            Statement::Label(label, s) => {
+                labels.push(label);
+                s.labels(labels, gotos);
+            }
+            Statement::Goto(label) => gotos.push(label),
+            Statement::If(_, s1, s2) => {
+                s1.labels(labels, gotos);
+                if let Some(s2) = s2 {
+                    s2.labels(labels, gotos);
+                }
+            }
+            Statement::While(_, body)
+            | Statement::For(_, _, _, body)
+            | Statement::Switch(_, body) => body.labels(labels, gotos),
+            Statement::Block(body) => {
+                for s in body.iter() {
+                    s.labels(labels, gotos);
+                }
+            }
+            Statement::Return(..)
+            | Statement::Declaration(_, _, _, _)
+            | Statement::Expression(_)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Case(_)
+            | Statement::Default => {}
+        }
+    }
+
+    // Collect each `return` in this statement, in source order.
+    fn returns<'a>(&'a self, returns: &mut Vec<&'a Statement>) {
+        match self {
+            Statement::Return(..) => returns.push(self),
+            Statement::Label(_, s) => s.returns(returns),
+            Statement::If(_, s1, s2) => {
+                s1.returns(returns);
+                if let Some(s2) = s2 {
+                    s2.returns(returns);
+                }
+            }
+            Statement::For(init, _, _, body) => {
+                if let Some(init) = init {
+                    init.returns(returns);
+                }
+                body.returns(returns);
+            }
+            Statement::While(_, body) | Statement::Switch(_, body) => body.returns(returns),
+            Statement::Block(body) => {
+                for s in body.iter() {
+                    s.returns(returns);
+                }
+            }
+            Statement::Declaration(_, _, _, _)
+            | Statement::Expression(_)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => {}
+        }
+    }
+
+    // Depth of blocks nested inside this statement.
+    fn nesting(&self) -> usize {
+        match self {
+            Statement::Label(_, s) => s.nesting(),
+            Statement::Return(..)
+            | Statement::Declaration(_, _, _, _)
+            | Statement::Expression(_)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => 0,
+            Statement::If(_, s1, s2) => {
+                1 + s1.nesting().max(s2.as_ref().map_or(0, |s2| s2.nesting()))
+            }
+            Statement::While(_, body)
+            | Statement::For(_, _, _, body)
+            | Statement::Switch(_, body) => 1 + body.nesting(),
+            // A block is counted by the statement it belongs to.
+            Statement::Block(body) => block_nesting(body),
+        }
+    }
+
+    // The number of statements this is made up of, itself included. A
+    // block only groups the statements in it, so isn't counted.
+    fn statement_count(&self) -> usize {
+        match self {
+            Statement::Label(_, s) => s.statement_count(),
+            Statement::If(_, s1, s2) => {
+                1 + s1.statement_count() + s2.as_ref().map_or(0, |s2| s2.statement_count())
+            }
+            Statement::While(_, body) | Statement::Switch(_, body) => 1 + body.statement_count(),
+            Statement::For(init, _, _, body) => {
+                1 + init.as_ref().map_or(0, |init| init.statement_count()) + body.statement_count()
+            }
+            Statement::Block(body) => body.iter().map(Statement::statement_count).sum(),
+            Statement::Return(..)
+            | Statement::Declaration(_, _, _, _)
+            | Statement::Expression(_)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => 1,
+        }
+    }
+
+    // Like emit, reporting the errors it would without generating code.
+    // Only whether a variable is in scope matters, not where it lives, so
+    // no frame is laid out.
+    fn validate(&self, ctx: &mut Context) -> Result<()> {
+        match self {
+            Statement::Label(_, s) => s.validate(ctx),
+            Statement::Goto(_) => Ok(()),
+            Statement::If(c, s1, s2) => {
+                c.validate(ctx)?;
+                s1.validate(ctx)?;
+                match s2 {
+                    Some(s2) => s2.validate(ctx),
+                    None => Ok(()),
+                }
+            }
+            Statement::While(c, body) => {
+                c.validate(ctx)?;
+                ctx.loops.push((Some(String::new()), String::new()));
+                body.validate(ctx)?;
+                ctx.loops.pop();
+                Ok(())
+            }
+            Statement::For(init, c, post, body) => ctx.scoped(|ctx| {
+                if let Some(init) = init {
+                    init.validate(ctx)?;
+                }
+                if let Some(c) = c {
+                    c.validate(ctx)?;
+                }
+                ctx.loops.push((Some(String::new()), String::new()));
+                body.validate(ctx)?;
+                ctx.loops.pop();
+                match post {
+                    Some(post) => post.validate(ctx),
+                    None => Ok(()),
+                }
+            }),
+            Statement::Switch(value, body) => {
+                value.validate(ctx)?;
+                ctx.switches.push(Vec::new());
+                ctx.loops.push((None, String::new()));
+                body.validate(ctx)?;
+                ctx.loops.pop();
+                ctx.switches.pop();
+                Ok(())
+            }
+            Statement::Case(value) => {
+                let labels = ctx
+                    .switches
+                    .last_mut()
+                    .ok_or(Error::NotInSwitch { label: "case" })?;
+                if labels.iter().any(|&(v, _)| v == Some(*value)) {
+                    return Err(Error::DuplicateCase {
+                        label: value.to_string(),
+                    });
+                }
+                labels.push((Some(*value), String::new()));
+                Ok(())
+            }
+            Statement::Default => {
+                let labels = ctx
+                    .switches
+                    .last_mut()
+                    .ok_or(Error::NotInSwitch { label: "default" })?;
+                if labels.iter().any(|(v, _)| v.is_none()) {
+                    return Err(Error::DuplicateCase {
+                        label: String::from("default"),
+                    });
+                }
+                labels.push((None, String::new()));
+                Ok(())
+            }
+            Statement::Block(body) => ctx.scoped(|ctx| {
+                for s in body.iter() {
+                    s.validate(ctx)?;
+                }
+                Ok(())
+            }),
+            Statement::Break => match ctx.loops.last() {
+                Some(_) => Ok(()),
+                None => Err(Error::NotInLoop { statement: "break" }),
+            },
+            Statement::Continue => match ctx.loops.iter().find(|(next, _)| next.is_some()) {
+                Some(_) => Ok(()),
+                None => Err(Error::NotInLoop {
+                    statement: "continue",
+                }),
+            },
+            Statement::Typedef(ty, name) => match ctx.aliases.get(name) {
+                Some(existing) if existing != ty && ctx.declared.contains(name) => {
+                    Err(Error::ConflictingTypedef { name: name.clone() })
+                }
+                _ => {
+                    ctx.declared.insert(name.clone());
+                    ctx.aliases.insert(name.clone(), ty.clone());
+                    Ok(())
+                }
+            },
+            Statement::Struct(name, fields) => {
+                if !ctx.declared.insert(format!("struct {}", name)) {
+                    return Err(Error::DuplicateDeclaration { var: name.clone() });
+                }
+                ctx.structs.insert(name.clone(), fields.clone());
+                Ok(())
+            }
+            Statement::Declaration(ty, s, init, _) => {
+                let mut ty = ty.clone();
+                while let Type::Alias(alias) = ty {
+                    ty = ctx.alias(&alias)?.clone();
+                }
+                if !ctx.declared.insert(s.clone()) {
+                    return Err(Error::DuplicateDeclaration { var: s.clone() });
+                }
+                if let Type::Struct(_) | Type::Array(_, _) = ty {
+                    ctx.slot(&ty)?;
+                }
+                ctx.vmap.insert(s.clone(), 0);
+                ctx.types.insert(s.clone(), ty.clone());
+                match (ty, init) {
+                    // A struct's initializer is ignored.
+                    (Type::Struct(_), _) | (_, None) => Ok(()),
+                    (Type::Array(inner, n), Some(Expression::Initializer(items))) => {
+                        if items.len() > n {
+                            return Err(Error::ExcessInitializers { var: s.clone() });
+                        }
+                        if let Type::Struct(_) | Type::Array(_, _) = ctx.resolve((*inner).clone()) {
+                            return Err(Error::Unsupported {
+                                feature: "initializers for arrays of aggregates",
+                                hint: "Assign each element instead.",
+                            });
+                        }
+                        ctx.size(&inner)?;
+                        items.iter().try_for_each(|e| e.validate(ctx))
+                    }
+                    (Type::Array(_, _), Some(_)) => Err(Error::InvalidSyntax),
+                    (_, Some(e)) => e.validate(ctx),
+                }
+            }
+            Statement::Expression(e) | Statement::Return(Some(e), _) => e.validate(ctx),
+            Statement::Return(None, _) => Ok(()),
+        }
+    }
+}
+
+// A variable declared in a function, for the lints that must tell apart
+// declarations of the same name.
+struct Declared {
+    name: String,
+    // How many declarations of the name come before this one in the
+    // function, parameters included.
+    index: usize,
+    read: bool,
+}
+
+// The declarations in scope while walking a function body in source order.
+#[derive(Default)]
+struct Scopes {
+    // Each maps a name to its entry in `declared`, innermost last.
+    scopes: Vec<HashMap<String, usize>>,
+    declared: Vec<Declared>,
+    // Entries in `declared` that shadow one in an enclosing scope.
+    shadowing: Vec<usize>,
+}
+
+impl Scopes {
+    fn enter(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn declare(&mut self, name: &str) {
+        if self.scopes.is_empty() {
+            self.enter();
+        }
+        let i = self.declared.len();
+        if self.scopes.iter().any(|s| s.contains_key(name)) {
+            self.shadowing.push(i);
+        }
+        self.declared.push(Declared {
+            name: name.to_string(),
+            index: self.declared.iter().filter(|d| d.name == name).count(),
+            read: false,
+        });
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), i);
+        }
+    }
+
+    fn read(&mut self, e: &Expression) {
+        let mut read = HashSet::new();
+        e.vars_read(&mut read);
+        for v in read {
+            if let Some(&i) = self.scopes.iter().rev().find_map(|s| s.get(&v)) {
+                self.declared[i].read = true;
+            }
+        }
+    }
+
+    fn statement(&mut self, s: &Statement) {
+        match s {
+            Statement::Declaration(_, name, init, _) => {
+                if let Some(init) = init {
+                    self.read(init);
+                }
+                self.declare(name);
+            }
+            Statement::Return(Some(e), _) | Statement::Expression(e) => self.read(e),
+            Statement::If(c, s1, s2) => {
+                self.read(c);
+                self.statement(s1);
+                if let Some(s2) = s2 {
+                    self.statement(s2);
+                }
+            }
+            Statement::While(c, body) | Statement::Switch(c, body) => {
+                self.read(c);
+                self.statement(body);
+            }
+            Statement::For(init, c, post, body) => {
+                self.enter();
+                if let Some(init) = init {
+                    self.statement(init);
+                }
+                for e in c.iter().chain(post.iter()) {
+                    self.read(e);
+                }
+                self.statement(body);
+                self.scopes.pop();
+            }
+            Statement::Block(body) => {
+                self.enter();
+                for s in body.iter() {
+                    self.statement(s);
+                }
+                self.scopes.pop();
+            }
+            Statement::Label(_, s) => self.statement(s),
+            Statement::Return(None, _)
+            | Statement::Struct(_, _)
+            | Statement::Typedef(_, _)
+            | Statement::Break
+            | Statement::Continue
+            | Statement::Goto(_)
+            | Statement::Case(_)
+            | Statement::Default => {}
+        }
+    }
+}
+
+// Whether the name at `tokens[i]` is being declared, rather than used. A
+// declared name follows its type, past any pointer stars. Two names are
+// never next to each other in an expression, so a typedef name or struct
+// tag can only come before a declaration; after stars, it must also start
+// the statement, as `a * b` is a product.
+fn declares(tokens: &[(Token, Span)], i: usize) -> bool {
+    let mut j = i;
+    while j > 0 && tokens[j - 1].0 == Token::Multiplication {
+        j -= 1;
+    }
+    let before = |n: usize| j.checked_sub(n).map(|k| &tokens[k].0);
+    match before(1) {
+        Some(Token::Keyword(Keyword::Int))
+        | Some(Token::Keyword(Keyword::Char))
+        | Some(Token::Keyword(Keyword::Long))
+        | Some(Token::Keyword(Keyword::Unsigned))
+        | Some(Token::Keyword(Keyword::Signed))
+        | Some(Token::Keyword(Keyword::Const))
+        | Some(Token::Keyword(Keyword::Void)) => true,
+        Some(Token::Identifier(_)) => {
+            j == i
+                || matches!(
+                    before(2),
+                    None | Some(Token::Keyword(Keyword::Struct))
+                        | Some(Token::OpenBrace)
+                        | Some(Token::CloseBrace)
+                        | Some(Token::Semicolon)
+                )
+        }
+        _ => false,
+    }
+}
+
+fn is_name(tokens: &[(Token, Span)], i: usize, name: &str) -> bool {
+    matches!(&tokens[i].0, Token::Identifier(n) if n == name)
+}
+
+// The index of the token naming `function` in its definition.
+fn function_start(tokens: &[(Token, Span)], function: &str) -> Option<usize> {
+    (0..tokens.len()).find(|&i| {
+        is_name(tokens, i, function)
+            && declares(tokens, i)
+            && tokens.get(i + 1).map(|(t, _)| t) == Some(&Token::OpenParenthesis)
+    })
+}
+
+// Where the declaration of `var` is in function `function`, given how many
+// declarations of the name come before it there, parameters included.
+fn declaration_span(
+    tokens: &[(Token, Span)],
+    function: &str,
+    var: &str,
+    index: usize,
+) -> Option<Span> {
+    let is = |i: usize, name: &str| is_name(tokens, i, name);
+    let start = function_start(tokens, function)?;
+    (start + 1..tokens.len())
+        .filter(|&i| is(i, var) && declares(tokens, i))
+        .nth(index)
+        .map(|i| tokens[i].1)
+}
+
+// The deepest nesting in a block's statements. A block directly inside
+// another nests a level deeper, like the body of a statement.
+fn block_nesting(body: &[Statement]) -> usize {
+    body.iter()
+        .map(|s| match s {
+            Statement::Block(_) => 1 + s.nesting(),
+            s => s.nesting(),
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+impl ASTNode for Statement {
+    fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Statement> {
+        match t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "Keyword",
+            span: None,
+        })? {
+            Token::OpenBrace => {
+                let mut body = Vec::new();
+                loop {
+                    match t.next().ok_or(Error::UnexpectedEnd {
+                        wanted: "Block",
+                        span: None,
+                    })? {
+                        Token::CloseBrace => break,
+                        tok => t.put_back(tok),
+                    }
+                    body.push(Statement::parse(t)?);
+                }
+                Ok(Statement::Block(body))
+            }
+            Token::Keyword(Keyword::If) => {
+                consume_token(t, Token::OpenParenthesis)?;
+                let condition = Expression::parse(t)?;
+                consume_token(t, Token::CloseParenthesis)?;
+                let body = Statement::parse(t)?;
+                // An `else` binds to the nearest `if`, which is the one
+                // whose body was just parsed.
+                let otherwise = match t.next() {
+                    Some(Token::Keyword(Keyword::Else)) => Some(Box::new(Statement::parse(t)?)),
+                    Some(tok) => {
+                        t.put_back(tok);
+                        None
+                    }
+                    None => None,
+                };
+                Ok(Statement::If(condition, Box::new(body), otherwise))
+            }
+            Token::Keyword(Keyword::While) => {
+                consume_token(t, Token::OpenParenthesis)?;
+                let condition = Expression::parse(t)?;
+                consume_token(t, Token::CloseParenthesis)?;
+                Ok(Statement::While(condition, Box::new(Statement::parse(t)?)))
+            }
+            Token::Keyword(Keyword::For) => {
+                consume_token(t, Token::OpenParenthesis)?;
+                // The init clause is a declaration or expression statement,
+                // and consumes its own semicolon.
+                let init = match t.next().ok_or(Error::UnexpectedEnd {
+                    wanted: "For",
+                    span: None,
+                })? {
+                    Token::Semicolon => None,
+                    tok => {
+                        t.put_back(tok);
+                        match Statement::parse(t)? {
+                            init @ Statement::Declaration(_, _, _, _)
+                            | init @ Statement::Expression(_) => Some(Box::new(init)),
+                            _ => return Err(Error::InvalidSyntax),
+                        }
+                    }
+                };
+                let condition = match t.next().ok_or(Error::UnexpectedEnd {
+                    wanted: "For",
+                    span: None,
+                })? {
+                    Token::Semicolon => None,
+                    tok => {
+                        t.put_back(tok);
+                        let c = Expression::parse(t)?;
+                        consume_token(t, Token::Semicolon)?;
+                        Some(c)
+                    }
+                };
+                let post = match t.next().ok_or(Error::UnexpectedEnd {
+                    wanted: "For",
+                    span: None,
+                })? {
+                    Token::CloseParenthesis => None,
+                    tok => {
+                        t.put_back(tok);
+                        let e = Expression::parse(t)?;
+                        consume_token(t, Token::CloseParenthesis)?;
+                        Some(e)
+                    }
+                };
+                Ok(Statement::For(
+                    init,
+                    condition,
+                    post,
+                    Box::new(Statement::parse(t)?),
+                ))
+            }
+            tok @ Token::Keyword(Keyword::Register) | tok @ Token::Keyword(Keyword::Volatile) => {
+                let mut qualifiers = Qualifiers::default();
+                let mut tok = tok;
+                loop {
+                    match tok {
+                        Token::Keyword(Keyword::Register) => qualifiers.register = true,
+                        Token::Keyword(Keyword::Volatile) => qualifiers.volatile = true,
+                        tok => {
+                            t.put_back(tok);
+                            break;
+                        }
+                    }
+                    tok = t.next().ok_or(Error::UnexpectedEnd {
+                        wanted: "Declaration",
+                        span: None,
+                    })?;
+                }
+                match Statement::parse(t)? {
+                    Statement::Declaration(ty, name, init, q) => {
+                        let qualifiers = Qualifiers {
+                            constant: q.constant,
+                            ..qualifiers
+                        };
+                        Ok(Statement::Declaration(ty, name, init, qualifiers))
+                    }
+                    _ => Err(Error::InvalidSyntax),
+                }
+            }
+            Token::Keyword(Keyword::Switch) => {
+                consume_token(t, Token::OpenParenthesis)?;
+                let value = Expression::parse(t)?;
+                consume_token(t, Token::CloseParenthesis)?;
+                Ok(Statement::Switch(value, Box::new(Statement::parse(t)?)))
+            }
+            Token::Keyword(Keyword::Case) => {
+                // A label may be any expression that folds to a constant,
+                // like `-1` or `1 << 2`.
+                let value = match Expression::parse(t)?.fold() {
+                    Expression::Constant(c) => c.value(),
+                    e => {
+                        return Err(Error::NonConstantCase {
+                            expr: format!("{:?}", e),
+                        })
+                    }
+                };
+                consume_token(t, Token::Colon)?;
+                Ok(Statement::Case(value))
+            }
+            Token::Keyword(Keyword::Default) => {
+                consume_token(t, Token::Colon)?;
+                Ok(Statement::Default)
+            }
+            Token::Keyword(Keyword::Break) => {
+                consume_token(t, Token::Semicolon)?;
+                Ok(Statement::Break)
+            }
+            Token::Keyword(Keyword::Continue) => {
+                consume_token(t, Token::Semicolon)?;
+                Ok(Statement::Continue)
+            }
+            Token::Keyword(Keyword::Goto) => {
+                match t.next().ok_or(Error::UnexpectedEnd {
+                    wanted: "Label",
+                    span: None,
+                })? {
+                    Token::Identifier(label) => {
+                        consume_token(t, Token::Semicolon)?;
+                        Ok(Statement::Goto(label))
+                    }
+                    tok => Err(Error::UnexpectedToken {
+                        wanted: "Label",
+                        expected: vec![Token::Identifier(String::from("_"))],
+                        found: tok,
+                        tokens: t.collect(),
+                        span: None,
+                    }),
+                }
+            }
+            Token::Keyword(Keyword::Return) => match t.next() {
+                Some(Token::Semicolon) => Ok(Statement::Return(None, false)),
+                tok => {
+                    if let Some(tok) = tok {
+                        t.put_back(tok);
+                    }
+                    // As in C, this is a full expression, so `return a = 5,
+                    // a + 1;` returns `(a = 5), (a + 1)`: the comma binds
+                    // looser than the assignment. The commas are taken here
+                    // to note that they weren't parenthesized.
+                    let mut e = Expression::parse_bounded(t, 1)?;
+                    let mut bare_comma = false;
+                    while let Some(tok) = t.next() {
+                        if tok != Token::Comma {
+                            t.put_back(tok);
+                            break;
+                        }
+                        bare_comma = true;
+                        let rhs = Expression::parse_bounded(t, 1)?;
+                        e = Expression::Comma(Box::new(e), Box::new(rhs));
+                    }
+                    consume_token(t, Token::Semicolon)?;
+                    Ok(Statement::Return(Some(e), bare_comma))
+                }
+            },
+            tok if is_specifier(&tok) => {
+                let (ty, constant) = parse_specifiers(t, tok)?;
+                let (ty, s) = parse_declarator(t, ty)?;
+                match parse_declaration(t, ty, s)? {
+                    Statement::Declaration(ty, name, init, qualifiers) => {
+                        let qualifiers = Qualifiers {
+                            constant,
+                            ..qualifiers
+                        };
+                        Ok(Statement::Declaration(ty, name, init, qualifiers))
+                    }
+                    s => Ok(s),
+                }
+            }
+            Token::Keyword(Keyword::Typedef) => {
+                let ty = match t.next().ok_or(Error::UnexpectedEnd {
+                    wanted: "Typedef",
+                    span: None,
+                })? {
+                    tok if is_specifier(&tok) => parse_specifiers(t, tok)?.0,
+                    Token::Identifier(alias) => Type::Alias(alias),
+                    Token::Keyword(Keyword::Struct) => {
+                        match t.next().ok_or(Error::UnexpectedEnd {
+                            wanted: "Struct name",
+                            span: None,
+                        })? {
+                            Token::Identifier(name) => Type::Struct(name),
+                            tok => {
+                                return Err(Error::UnexpectedToken {
+                                    wanted: "Struct name",
+                                    expected: vec![Token::Identifier(String::from("_"))],
+                                    found: tok,
+                                    tokens: t.collect(),
+                                    span: None,
+                                })
+                            }
+                        }
+                    }
+                    tok => {
+                        return Err(Error::UnexpectedToken {
+                            wanted: "Typedef",
+                            expected: vec![
+                                Token::Keyword(Keyword::Int),
+                                Token::Keyword(Keyword::Struct),
+                                Token::Identifier(String::from("_")),
+                            ],
+                            found: tok,
+                            tokens: t.collect(),
+                            span: None,
+                        })
+                    }
+                };
+                let (ty, name) = parse_declarator(t, ty)?;
+                consume_token(t, Token::Semicolon)?;
+                Ok(Statement::Typedef(ty, name))
+            }
+            // A name followed by another name declares a variable of a
+            // typedef'd type, as does one followed by a pointer declarator.
+            // Which names are types isn't known yet, so `a * b;` is taken as
+            // a declaration until the function has been parsed.
+            Token::Identifier(alias) => match t.next() {
+                Some(Token::Identifier(s)) => parse_declaration(t, Type::Alias(alias), s),
+                Some(Token::Multiplication) if is_pointer_declarator(t) => {
+                    t.put_back(Token::Multiplication);
+                    let (ty, s) = parse_declarator(t, Type::Alias(alias))?;
+                    parse_declaration(t, ty, s)
+                }
+                Some(Token::Colon) => Ok(Statement::Label(alias, Box::new(Statement::parse(t)?))),
+                tok => {
+                    if let Some(tok) = tok {
+                        t.put_back(tok);
+                    }
+                    t.put_back(Token::Identifier(alias));
+                    let ret = Ok(Statement::Expression(Expression::parse(t)?));
+                    consume_token(t, Token::Semicolon)?;
+                    ret
+                }
+            },
+            Token::Keyword(Keyword::Struct) => {
+                let name = match t.next().ok_or(Error::UnexpectedEnd {
+                    wanted: "Struct name",
+                    span: None,
+                })? {
+                    Token::Identifier(name) => name,
+                    tok => {
+                        return Err(Error::UnexpectedToken {
+                            wanted: "Struct name",
+                            expected: vec![Token::Identifier(String::from("_"))],
+                            found: tok,
+                            tokens: t.collect(),
+                            span: None,
+                        })
+                    }
+                };
+
+                match t.next().ok_or(Error::UnexpectedEnd {
+                    wanted: "Struct declaration",
+                    span: None,
                 })? {
-                    Token::Semicolon => Ok(Statement::Declaration(s, None)),
-                    Token::Assign => {
-                        t.put_back(Token::Assign);
-                        t.put_back(Token::Identifier(s.clone()));
-                        let ret = Ok(Statement::Declaration(s, Some(Expression::parse(t)?)));
+                    Token::OpenBrace => {
+                        let mut fields = Vec::new();
+                        loop {
+                            match t.next().ok_or(Error::UnexpectedEnd {
+                                wanted: "Struct member",
+                                span: None,
+                            })? {
+                                Token::CloseBrace => break,
+                                Token::Keyword(Keyword::Int) => {
+                                    match t.next().ok_or(Error::UnexpectedEnd {
+                                        wanted: "Struct member",
+                                        span: None,
+                                    })? {
+                                        Token::Identifier(member) => {
+                                            let mut ty = Type::Int;
+                                            for dim in
+                                                parse_dimensions(t, &member)?.into_iter().rev()
+                                            {
+                                                match dim {
+                                                    Some(n) => ty = Type::Array(Box::new(ty), n),
+                                                    None => {
+                                                        return Err(Error::IncompleteArrayType {
+                                                            var: member,
+                                                        })
+                                                    }
+                                                }
+                                            }
+                                            fields.push(Field {
+                                                offset: fields
+                                                    .iter()
+                                                    .map(|f: &Field| f.width)
+                                                    .sum(),
+                                                width: 4 * ty.elements(),
+                                                name: member,
+                                                ty,
+                                            })
+                                        }
+                                        tok => {
+                                            return Err(Error::UnexpectedToken {
+                                                wanted: "Struct member",
+                                                expected: vec![Token::Identifier(String::from(
+                                                    "_",
+                                                ))],
+                                                found: tok,
+                                                tokens: t.collect(),
+                                                span: None,
+                                            })
+                                        }
+                                    }
+                                    consume_token(t, Token::Semicolon)?;
+                                }
+                                tok => {
+                                    return Err(Error::UnexpectedToken {
+                                        wanted: "Struct member",
+                                        expected: vec![
+                                            Token::Keyword(Keyword::Int),
+                                            Token::CloseBrace,
+                                        ],
+                                        found: tok,
+                                        tokens: t.collect(),
+                                        span: None,
+                                    })
+                                }
+                            }
+                        }
+                        consume_token(t, Token::Semicolon)?;
+                        Ok(Statement::Struct(name, fields))
+                    }
+                    Token::Identifier(var) => {
                         consume_token(t, Token::Semicolon)?;
-                        ret
+                        Ok(Statement::Declaration(
+                            Type::Struct(name),
+                            var,
+                            None,
+                            Qualifiers::default(),
+                        ))
+                    }
+                    // A pointer to a struct is a scalar, so may be
+                    // initialized.
+                    Token::Multiplication => {
+                        t.put_back(Token::Multiplication);
+                        let (ty, var) = parse_declarator(t, Type::Struct(name))?;
+                        parse_declaration(t, ty, var)
                     }
                     tok => Err(Error::UnexpectedToken {
-                        wanted: "Statement part",
-                        expected: vec![Token::Semicolon, Token::Assign],
+                        wanted: "Struct declaration",
+                        expected: vec![
+                            Token::OpenBrace,
+                            Token::Identifier(String::from("_")),
+                            Token::Multiplication,
+                        ],
                         found: tok,
                         tokens: t.collect(),
+                        span: None,
                     }),
-                },
-                tok => Err(Error::UnexpectedToken {
-                    wanted: "Identifier",
-                    expected: vec![Token::Identifier(String::from("_"))],
-                    found: tok,
-                    tokens: t.collect(),
-                }),
-            },
-            tok @ Token::Identifier(_) => {
-                t.put_back(tok);
-                let ret = Ok(Statement::Expression(Expression::parse(t)?));
-                consume_token(t, Token::Semicolon)?;
-                ret
+                }
             }
-            tok @ Token::Literal(_) => {
+            tok @ Token::Literal(_)
+            | tok @ Token::Increment
+            | tok @ Token::Decrement
+            | tok @ Token::Multiplication
+            | tok @ Token::OpenParenthesis => {
                 t.put_back(tok);
                 let ret = Statement::Expression(Expression::parse(t)?);
                 consume_token(t, Token::Semicolon)?;
@@ -197,100 +3408,1304 @@ impl ASTNode for Statement {
                 ],
                 found: tok,
                 tokens: t.collect(),
+                span: None,
             }),
         }
     }
 
-    fn emit(self, vmap: &mut HashMap<String, usize>, stack_index: &mut usize) -> Result<String> {
+    fn emit(self, ctx: &mut Context) -> Result<Vec<Instruction>> {
         match self {
-            Statement::Declaration(s, v) => {
-                if vmap.contains_key(&s) {
+            Statement::Label(label, s) => {
+                let mut code = vec![Instruction::Label(ctx.user_label(&label))];
+                code.extend(s.emit(ctx)?);
+                Ok(code)
+            }
+            Statement::Goto(label) => Ok(vec![Instruction::Jmp(Operand::Symbol(
+                ctx.user_label(&label),
+            ))]),
+            Statement::If(c, s1, s2) => {
+                let otherwise = ctx.gen_label();
+                let end = ctx.gen_label();
+                let mut code = c.emit(ctx)?;
+                code.push(Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(0)));
+                code.push(Instruction::Jcc(Condition::Equal, otherwise.clone()));
+                code.extend(s1.emit(ctx)?);
+                code.push(Instruction::Jmp(Operand::Symbol(end.clone())));
+                code.push(Instruction::Label(otherwise));
+                if let Some(s2) = s2 {
+                    code.extend(s2.emit(ctx)?);
+                }
+                code.push(Instruction::Label(end));
+                Ok(code)
+            }
+            Statement::While(c, body) => {
+                let start = ctx.gen_label();
+                let end = ctx.gen_label();
+                let c = c.emit(ctx)?;
+                ctx.loops.push((Some(start.clone()), end.clone()));
+                let body = body.emit(ctx)?;
+                ctx.loops.pop();
+                let mut code = vec![Instruction::Label(start.clone())];
+                code.extend(c);
+                code.push(Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(0)));
+                code.push(Instruction::Jcc(Condition::Equal, end.clone()));
+                code.extend(body);
+                code.push(Instruction::Jmp(Operand::Symbol(start)));
+                code.push(Instruction::Label(end));
+                Ok(code)
+            }
+            // The init clause declares into a scope around the whole loop.
+            Statement::For(init, c, post, body) => ctx.scoped(|ctx| {
+                let start = ctx.gen_label();
+                let end = ctx.gen_label();
+                let mut code = match init {
+                    Some(init) => init.emit(ctx)?,
+                    None => Vec::new(),
+                };
+                code.push(Instruction::Label(start.clone()));
+                if let Some(c) = c {
+                    code.extend(c.emit(ctx)?);
+                    code.push(Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(0)));
+                    code.push(Instruction::Jcc(Condition::Equal, end.clone()));
+                }
+                let next = ctx.gen_label();
+                ctx.loops.push((Some(next.clone()), end.clone()));
+                code.extend(body.emit(ctx)?);
+                ctx.loops.pop();
+                code.push(Instruction::Label(next));
+                if let Some(post) = post {
+                    code.extend(post.emit(ctx)?);
+                }
+                code.push(Instruction::Jmp(Operand::Symbol(start)));
+                code.push(Instruction::Label(end));
+                Ok(code)
+            }),
+            Statement::Switch(value, body) => {
+                let mut code = value.emit(ctx)?;
+                let end = ctx.gen_label();
+                ctx.switches.push(Vec::new());
+                ctx.loops.push((None, end.clone()));
+                let body = body.emit(ctx)?;
+                ctx.loops.pop();
+                let labels = ctx.switches.pop().unwrap();
+                let default = labels
+                    .iter()
+                    .find(|(value, _)| value.is_none())
+                    .map_or(end.clone(), |(_, label)| label.clone());
+                let cases = labels
+                    .iter()
+                    .filter_map(|(value, label)| value.map(|v| (v, label)))
+                    .collect::<Vec<_>>();
+
+                let values = cases.iter().map(|&(v, _)| v).collect::<Vec<_>>();
+                if ctx.options.jump_tables && is_dense(&values) {
+                    let min = *values.iter().min().unwrap();
+                    let max = *values.iter().max().unwrap();
+                    let targets = (min..=max)
+                        .map(|v| {
+                            cases
+                                .iter()
+                                .find(|&&(w, _)| w == v)
+                                .map_or(default.clone(), |(_, label)| label.to_string())
+                        })
+                        .collect::<Vec<_>>();
+                    let table = ctx.gen_label();
+                    ctx.sections.object(Section::Rodata, &table, 8, &targets);
+                    code.extend(vec![
+                        Instruction::Sub(Operand::Reg(Rax), Operand::Imm(min)),
+                        Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(max - min)),
+                        Instruction::Jcc(Condition::Above, default),
+                        Instruction::Lea(Operand::Reg(Rcx), Operand::Rel(table, Width::Qword)),
+                        Instruction::Jmp(Operand::Mem {
+                            base: Rcx,
+                            index: Some((Rax, 8)),
+                            offset: 0,
+                            width: Width::Qword,
+                        }),
+                    ]);
+                } else {
+                    for (v, label) in cases.iter() {
+                        code.push(Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(*v)));
+                        code.push(Instruction::Jcc(Condition::Equal, label.to_string()));
+                    }
+                    code.push(Instruction::Jmp(Operand::Symbol(default)));
+                }
+                code.extend(body);
+                code.push(Instruction::Label(end));
+                Ok(code)
+            }
+            Statement::Case(value) => {
+                let label = ctx.gen_label();
+                let labels = ctx
+                    .switches
+                    .last_mut()
+                    .ok_or(Error::NotInSwitch { label: "case" })?;
+                if labels.iter().any(|&(v, _)| v == Some(value)) {
+                    return Err(Error::DuplicateCase {
+                        label: value.to_string(),
+                    });
+                }
+                labels.push((Some(value), label.clone()));
+                Ok(vec![Instruction::Label(label)])
+            }
+            Statement::Default => {
+                let label = ctx.gen_label();
+                let labels = ctx
+                    .switches
+                    .last_mut()
+                    .ok_or(Error::NotInSwitch { label: "default" })?;
+                if labels.iter().any(|(v, _)| v.is_none()) {
+                    return Err(Error::DuplicateCase {
+                        label: String::from("default"),
+                    });
+                }
+                labels.push((None, label.clone()));
+                Ok(vec![Instruction::Label(label)])
+            }
+            Statement::Block(body) => ctx.scoped(|ctx| {
+                let mut code = Vec::new();
+                for s in body.into_iter() {
+                    code.extend(s.emit(ctx)?);
+                }
+                Ok(code)
+            }),
+            Statement::Break => match ctx.loops.last() {
+                Some((_, end)) => Ok(vec![Instruction::Jmp(Operand::Symbol(end.clone()))]),
+                None => Err(Error::NotInLoop { statement: "break" }),
+            },
+            Statement::Continue => match ctx.loops.iter().rev().find_map(|(next, _)| next.as_ref())
+            {
+                Some(next) => Ok(vec![Instruction::Jmp(Operand::Symbol(next.clone()))]),
+                None => Err(Error::NotInLoop {
+                    statement: "continue",
+                }),
+            },
+            // A typedef may be repeated with the same type in its own block,
+            // and shadowed by any in an inner one.
+            Statement::Typedef(ty, name) => match ctx.aliases.get(&name) {
+                Some(existing) if *existing != ty && ctx.declared.contains(&name) => {
+                    Err(Error::ConflictingTypedef { name })
+                }
+                _ => {
+                    ctx.declared.insert(name.clone());
+                    ctx.aliases.insert(name, ty);
+                    Ok(Vec::new())
+                }
+            },
+            Statement::Declaration(Type::Alias(alias), s, v, q) => {
+                let ty = ctx.alias(&alias)?.clone();
+                Statement::Declaration(ty, s, v, q).emit(ctx)
+            }
+            // Like a local, a struct may shadow one from an enclosing block
+            // but not be redefined in its own. Tags are kept apart from
+            // variable names by the space, which no name contains.
+            Statement::Struct(name, fields) => {
+                if !ctx.declared.insert(format!("struct {}", name)) {
+                    return Err(Error::DuplicateDeclaration { var: name });
+                }
+                ctx.structs.insert(name, fields);
+                Ok(Vec::new())
+            }
+            Statement::Declaration(Type::Struct(name), s, _, q) => {
+                if !ctx.declared.insert(s.clone()) {
+                    return Err(Error::DuplicateDeclaration { var: s });
+                }
+                if q.volatile {
+                    ctx.volatiles.insert(s.clone());
+                }
+
+                let size = ctx.slot(&Type::Struct(name.clone()))?;
+                // Members are laid out upwards from the lowest slot, so the
+                // variable itself refers to the struct's base address.
+                ctx.vmap.insert(s.clone(), ctx.stack_index + size - 8);
+                ctx.types.insert(s, Type::Struct(name));
+                ctx.stack_index += size;
+                Ok(Vec::new())
+            }
+            Statement::Declaration(Type::Array(inner, n), s, init, q) => {
+                if !ctx.declared.insert(s.clone()) {
+                    return Err(Error::DuplicateDeclaration { var: s });
+                }
+                if q.volatile {
+                    ctx.volatiles.insert(s.clone());
+                }
+
+                let ty = Type::Array(inner.clone(), n);
+                let size = ctx.slot(&ty)?;
+                // Like a struct's members, elements are laid out upwards.
+                let offset = ctx.stack_index + size - 8;
+                ctx.vmap.insert(s.clone(), offset);
+                ctx.types.insert(s.clone(), ty);
+                ctx.stack_index += size;
+
+                let items = match init {
+                    Some(Expression::Initializer(items)) => items,
+                    Some(_) => return Err(Error::InvalidSyntax),
+                    None => return Ok(Vec::new()),
+                };
+                if items.len() > n {
+                    return Err(Error::ExcessInitializers { var: s });
+                }
+                if let Type::Struct(_) | Type::Array(_, _) = ctx.resolve((*inner).clone()) {
+                    return Err(Error::Unsupported {
+                        feature: "initializers for arrays of aggregates",
+                        hint: "Assign each element instead.",
+                    });
+                }
+                // Elements without an initializer are zeroed.
+                let given = items.len();
+                let width = ctx.size(&inner)?;
+                let mut code = Vec::new();
+                for (i, e) in items.into_iter().enumerate() {
+                    code.extend(e.emit(ctx)?);
+                    code.push(ctx.store(&inner, ctx.local(offset - i * width), Rax));
+                }
+                if given < n {
+                    code.push(Instruction::Mov(Operand::Reg(Rax), Operand::Imm(0)));
+                    for i in given..n {
+                        code.push(ctx.store(&inner, ctx.local(offset - i * width), Rax));
+                    }
+                }
+                Ok(code)
+            }
+            Statement::Declaration(ty, s, v, q) => {
+                if !ctx.declared.insert(s.clone()) {
                     Err(Error::DuplicateDeclaration { var: s })
                 } else {
-                    vmap.insert(s, *stack_index);
-                    *stack_index += 8;
+                    if q.volatile {
+                        ctx.volatiles.insert(s.clone());
+                    }
+                    let offset = ctx.stack_index;
+                    ctx.vmap.insert(s.clone(), offset);
+                    ctx.types.insert(s, ty.clone());
+                    ctx.stack_index += 8;
                     match v {
-                        Some(e) => Ok(format!(
-                            "\
-                             {}\n\
-                             push rax\n\
-                             ",
-                            e.emit(vmap, stack_index)?
-                        )),
-                        None => Ok(String::from("")),
+                        Some(e) => {
+                            let mut code = e.emit(ctx)?;
+                            code.push(ctx.store(&ty, ctx.local(offset), Rax));
+                            Ok(code)
+                        }
+                        None => Ok(Vec::new()),
                     }
                 }
             }
-            Statement::Expression(e) => e.emit(vmap, stack_index),
-            Statement::Return(e) => Ok(format!(
-                "\
-                 {}\n\
-                 mov rsp, rbp\n\
-                 pop r15\n\
-                 pop r14\n\
-                 pop r13\n\
-                 pop r12\n\
-                 pop rbp\n\
-                 pop rbx\n\
-                 ret",
-                e.emit(vmap, stack_index)?
-            )),
+            Statement::Expression(e) => {
+                let mut read = HashSet::new();
+                e.vars_read(&mut read);
+                let has_side_effects =
+                    e.has_side_effects() || read.iter().any(|v| ctx.volatiles.contains(v));
+                let code = e.emit(ctx)?;
+                // The result is discarded, so only keep code that does
+                // something. It's still emitted above to catch errors.
+                if has_side_effects {
+                    Ok(code)
+                } else {
+                    Ok(Vec::new())
+                }
+            }
+            // The arguments go where the function expects its own, and the
+            // frame is reused as it is.
+            Statement::Return(Some(Expression::Call(name, args)), _)
+                if ctx.tail_entry.is_some() && ctx.is_self_call(&name, &args) =>
+            {
+                let mut code = Vec::new();
+                let count = args.len();
+                for a in args.into_iter() {
+                    code.extend(a.emit(ctx)?);
+                    code.push(Instruction::Push(Operand::Reg(Rax)));
+                    ctx.depth += 8;
+                }
+                for &reg in ARG_REGISTERS[..count].iter().rev() {
+                    code.push(Instruction::Pop(Operand::Reg(reg)));
+                }
+                ctx.depth -= 8 * count;
+                let entry = ctx.tail_entry.clone().unwrap_or_default();
+                code.push(Instruction::Jmp(Operand::Symbol(entry)));
+                Ok(code)
+            }
+            // A bare `return` leaves rax as it is.
+            Statement::Return(e, _) => {
+                let mut code = match e {
+                    Some(e) => e.emit(ctx)?,
+                    None => Vec::new(),
+                };
+                code.extend(ctx.epilogue());
+                code.push(Instruction::Ret);
+                Ok(code)
+            }
         }
     }
 }
 
 #[derive(Debug, Clone)]
-enum Expression {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Expression {
     Constant(Constant),
     Var(String),
     Unary(UnaryOperator, Box<Expression>),
     Binary(BinaryOperator, Box<Expression>, Box<Expression>),
-    Assign(String, Box<Expression>),
+    Assign(Box<Expression>, Box<Expression>),
+    /// `lhs op= rhs`. Unlike `lhs = lhs op rhs`, lhs is only evaluated once.
+    CompoundAssign(BinaryOperator, Box<Expression>, Box<Expression>),
+    /// `e++` or `e--`, with the operator applied. Evaluates to e's value
+    /// from before the update.
+    Postfix(BinaryOperator, Box<Expression>),
+    Member(Box<Expression>, String),
+    /// `base[index]`, which is `*(base + index)`.
+    Index(Box<Expression>, Box<Expression>),
+    /// The size of the operand's type. The operand is not evaluated.
+    Sizeof(Box<Expression>),
+    /// `sizeof(type)`.
+    SizeofType(Type),
+    /// A string literal, which is a `char` array in `.rodata`.
+    String(Vec<u8>),
+    Conditional(Box<Expression>, Box<Expression>, Box<Expression>),
+    /// `e1, e2`: e1 is evaluated for its side effects, then e2 gives the
+    /// value.
+    Comma(Box<Expression>, Box<Expression>),
+    Call(String, Vec<Expression>),
+    /// `{e1, e2}`, which only initializes an array in its declaration.
+    Initializer(Vec<Expression>),
+    /// `(type)e`. Only the conversion to `int` changes the value.
+    Cast(Type, Box<Expression>),
     //    Null,
 }
 
+impl Expression {
+    fn has_side_effects(&self) -> bool {
+        match self {
+            Expression::Assign(_, _)
+            | Expression::CompoundAssign(_, _, _)
+            | Expression::Postfix(_, _)
+            | Expression::Call(_, _) => true,
+            Expression::Constant(_)
+            | Expression::Var(_)
+            | Expression::Sizeof(_)
+            | Expression::SizeofType(_)
+            | Expression::String(_) => false,
+            Expression::Unary(_, e) | Expression::Member(e, _) | Expression::Cast(_, e) => {
+                e.has_side_effects()
+            }
+            Expression::Binary(_, e1, e2)
+            | Expression::Index(e1, e2)
+            | Expression::Comma(e1, e2) => e1.has_side_effects() || e2.has_side_effects(),
+            Expression::Initializer(items) => items.iter().any(Expression::has_side_effects),
+            Expression::Conditional(c, e1, e2) => {
+                c.has_side_effects() || e1.has_side_effects() || e2.has_side_effects()
+            }
+        }
+    }
+
+    // Whether this is a constant or a variable, which can be loaded without
+    // side effects or faults.
+    fn is_simple(&self) -> bool {
+        matches!(self, Expression::Constant(_) | Expression::Var(_))
+    }
+
+    // Visit this expression and all of its subexpressions, outermost first.
+    fn walk<F: FnMut(&Expression)>(&self, f: &mut F) {
+        f(self);
+        match self {
+            Expression::Constant(_)
+            | Expression::Var(_)
+            | Expression::SizeofType(_)
+            | Expression::String(_) => {}
+            Expression::Unary(_, e)
+            | Expression::Postfix(_, e)
+            | Expression::Member(e, _)
+            | Expression::Sizeof(e)
+            | Expression::Cast(_, e) => e.walk(f),
+            Expression::Binary(_, e1, e2)
+            | Expression::Assign(e1, e2)
+            | Expression::CompoundAssign(_, e1, e2)
+            | Expression::Index(e1, e2)
+            | Expression::Comma(e1, e2) => {
+                e1.walk(f);
+                e2.walk(f);
+            }
+            Expression::Conditional(c, e1, e2) => {
+                c.walk(f);
+                e1.walk(f);
+                e2.walk(f);
+            }
+            Expression::Call(_, args) | Expression::Initializer(args) => {
+                for a in args.iter() {
+                    a.walk(f);
+                }
+            }
+        }
+    }
+
+    fn vars_read(&self, read: &mut HashSet<String>) {
+        match self {
+            Expression::Var(v) => {
+                read.insert(v.clone());
+            }
+            Expression::Constant(_) | Expression::SizeofType(_) | Expression::String(_) => {}
+            // Storing through a computed address reads its operands.
+            Expression::Assign(lhs, e) => {
+                if !lhs.in_frame() {
+                    lhs.vars_read(read);
+                }
+                e.vars_read(read);
+            }
+            Expression::Unary(_, e)
+            | Expression::Postfix(_, e)
+            | Expression::Member(e, _)
+            | Expression::Sizeof(e)
+            | Expression::Cast(_, e) => e.vars_read(read),
+            Expression::Binary(_, e1, e2)
+            | Expression::CompoundAssign(_, e1, e2)
+            | Expression::Index(e1, e2)
+            | Expression::Comma(e1, e2) => {
+                e1.vars_read(read);
+                e2.vars_read(read);
+            }
+            Expression::Conditional(c, e1, e2) => {
+                c.vars_read(read);
+                e1.vars_read(read);
+                e2.vars_read(read);
+            }
+            Expression::Call(_, args) | Expression::Initializer(args) => {
+                for a in args.iter() {
+                    a.vars_read(read);
+                }
+            }
+        }
+    }
+
+    // Warn about reads of variables in `uninitialized`, then remove the ones
+    // this expression assigns. Each variable is reported once.
+    fn lint_uninitialized(
+        &self,
+        uninitialized: &mut HashSet<String>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        // A variable whose address is taken may be initialized through the
+        // pointer, so it is given the benefit of the doubt.
+        self.walk(&mut |e| {
+            if let Expression::Unary(UnaryOperator::AddressOf, e) = e {
+                if let Some(v) = e.frame_var() {
+                    uninitialized.remove(v);
+                }
+            }
+        });
+
+        let mut read = HashSet::new();
+        self.vars_read(&mut read);
+        let mut read = read
+            .into_iter()
+            .filter(|v| uninitialized.contains(v))
+            .collect::<Vec<_>>();
+        read.sort();
+        for v in read {
+            uninitialized.remove(&v);
+            diagnostics.push(Diagnostic::warning(
+                "uninitialized",
+                format!("Variable {} may be used uninitialized.", v),
+            ));
+        }
+
+        self.walk(&mut |e| {
+            if let Expression::Assign(lhs, _) = e {
+                if let Expression::Var(v) = &**lhs {
+                    uninitialized.remove(v);
+                }
+            }
+        });
+    }
+
+    // Number of values that must be held at once to evaluate this
+    // expression. The left operand of a binary operator is kept while the
+    // right one is evaluated.
+    fn eval_depth(&self) -> usize {
+        match self {
+            Expression::Constant(_)
+            | Expression::Var(_)
+            | Expression::Sizeof(_)
+            | Expression::SizeofType(_)
+            | Expression::String(_) => 1,
+            Expression::Unary(_, e)
+            | Expression::Member(e, _)
+            | Expression::Assign(_, e)
+            | Expression::Cast(_, e) => e.eval_depth(),
+            Expression::Binary(_, e1, e2)
+            | Expression::CompoundAssign(_, e1, e2)
+            | Expression::Index(e1, e2) => e1.eval_depth().max(e2.eval_depth() + 1),
+            // Nothing is kept from the left operand.
+            Expression::Comma(e1, e2) => e1.eval_depth().max(e2.eval_depth()),
+            // The old value is kept while the new one is computed.
+            Expression::Postfix(_, e) => e.eval_depth() + 1,
+            Expression::Conditional(c, e1, e2) => {
+                c.eval_depth().max(e1.eval_depth()).max(e2.eval_depth())
+            }
+            // Each argument is kept while the later ones are evaluated.
+            Expression::Call(_, args) => args
+                .iter()
+                .enumerate()
+                .map(|(i, a)| a.eval_depth() + i)
+                .max()
+                .unwrap_or(1),
+            // Each element is stored as soon as it's computed.
+            Expression::Initializer(items) => {
+                items.iter().map(Expression::eval_depth).max().unwrap_or(1)
+            }
+        }
+    }
+
+    // The type this expression evaluates to.
+    fn ty(&self, ctx: &Context) -> Type {
+        match self {
+            Expression::Var(v) => ctx
+                .types
+                .get(v)
+                .or_else(|| ctx.globals.get(v))
+                .cloned()
+                .unwrap_or(Type::Int),
+            Expression::Assign(lhs, _)
+            | Expression::CompoundAssign(_, lhs, _)
+            | Expression::Postfix(_, lhs) => lhs.ty(ctx),
+            Expression::Cast(ty, _) => ty.clone(),
+            Expression::String(bytes) => Type::Array(Box::new(Type::Char), bytes.len() + 1),
+            Expression::Conditional(_, e1, _) => e1.ty(ctx),
+            Expression::Comma(_, e) => e.ty(ctx),
+            Expression::Unary(UnaryOperator::AddressOf, e) => Type::Pointer(Box::new(e.ty(ctx))),
+            Expression::Unary(UnaryOperator::Dereference, e) => match e.ty(ctx).decay() {
+                Type::Pointer(inner) => *inner,
+                _ => Type::Int,
+            },
+            Expression::Binary(BinaryOperator::Subtraction, e1, e2) => {
+                match (e1.ty(ctx).decay(), e2.ty(ctx).decay()) {
+                    (Type::Pointer(_), Type::Pointer(_)) => Type::Int,
+                    (ty @ Type::Pointer(_), _) => ty,
+                    (ty1, ty2) => arithmetic_type(ctx.resolve(ty1), ctx.resolve(ty2)),
+                }
+            }
+            Expression::Binary(BinaryOperator::Addition, e1, e2) => {
+                match (e1.ty(ctx).decay(), e2.ty(ctx).decay()) {
+                    (ty @ Type::Pointer(_), _) | (_, ty @ Type::Pointer(_)) => ty,
+                    (ty1, ty2) => arithmetic_type(ctx.resolve(ty1), ctx.resolve(ty2)),
+                }
+            }
+            Expression::Binary(op, e1, e2) if op.is_arithmetic() => {
+                arithmetic_type(ctx.resolve(e1.ty(ctx)), ctx.resolve(e2.ty(ctx)))
+            }
+            Expression::Binary(BinaryOperator::ShiftLeft, e, _)
+            | Expression::Binary(BinaryOperator::ShiftRight, e, _) => {
+                arithmetic_type(ctx.resolve(e.ty(ctx)), Type::Int)
+            }
+            Expression::Unary(UnaryOperator::Negative, e)
+            | Expression::Unary(UnaryOperator::Complement, e) => {
+                arithmetic_type(ctx.resolve(e.ty(ctx)), Type::Int)
+            }
+            Expression::Constant(Constant::UInt(_)) => Type::UInt,
+            Expression::Constant(Constant::Long(_)) => Type::Long,
+            Expression::Constant(Constant::ULong(_)) => Type::ULong,
+            Expression::Index(e1, e2) => match (e1.ty(ctx).decay(), e2.ty(ctx).decay()) {
+                (Type::Pointer(inner), _) | (_, Type::Pointer(inner)) => *inner,
+                _ => Type::Int,
+            },
+            Expression::Member(e, member) => match e.ty(ctx) {
+                Type::Struct(name) => ctx.field(&name, member).map_or(Type::Int, |f| f.ty.clone()),
+                _ => Type::Int,
+            },
+            Expression::Constant(_)
+            | Expression::Unary(_, _)
+            | Expression::Binary(_, _, _)
+            | Expression::Sizeof(_)
+            | Expression::SizeofType(_)
+            | Expression::Initializer(_) => Type::Int,
+            // A function defined elsewhere is assumed to return int.
+            Expression::Call(name, _) => ctx.returns.get(name).cloned().unwrap_or(Type::Int),
+        }
+    }
+
+    // The instruction that applies this expression to `lhs` in place, if this
+    // is a negation or complement of `lhs` itself.
+    fn in_place_op(&self, lhs: &Expression) -> Option<fn(Operand) -> Instruction> {
+        match (self, lhs) {
+            (Expression::Unary(op, e), Expression::Var(v)) => match (op, &**e) {
+                (UnaryOperator::Negative, Expression::Var(w)) if v == w => Some(Instruction::Neg),
+                (UnaryOperator::Complement, Expression::Var(w)) if v == w => Some(Instruction::Not),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn is_lvalue(&self) -> bool {
+        matches!(
+            self,
+            Expression::Var(_)
+                | Expression::Member(_, _)
+                | Expression::Index(_, _)
+                | Expression::Unary(UnaryOperator::Dereference, _)
+        )
+    }
+
+    // The variable whose storage an lvalue in the frame is part of.
+    fn frame_var(&self) -> Option<&String> {
+        match self {
+            Expression::Var(v) => Some(v),
+            Expression::Member(e, _) => e.frame_var(),
+            _ => None,
+        }
+    }
+
+    // Whether this is an lvalue at a fixed place, in the frame or global, as
+    // opposed to one reached through a pointer.
+    fn in_frame(&self) -> bool {
+        match self {
+            Expression::Var(_) => true,
+            Expression::Member(e, _) => e.in_frame(),
+            _ => false,
+        }
+    }
+
+    // The rbp offset of an lvalue.
+    fn frame_offset(&self, ctx: &Context) -> Result<usize> {
+        match self {
+            Expression::Var(v) => ctx.offset(v),
+            Expression::Member(e, member) => {
+                let name = match &**e {
+                    Expression::Var(v) => match ctx.types.get(v) {
+                        Some(Type::Struct(name)) => name.clone(),
+                        _ => return Err(Error::NotAStruct { expr: v.clone() }),
+                    },
+                    e => {
+                        return Err(Error::NotAStruct {
+                            expr: format!("{:?}", e),
+                        })
+                    }
+                };
+                Ok(e.frame_offset(ctx)? - ctx.member_offset(&name, member)?)
+            }
+            _ => Err(Error::InvalidSyntax),
+        }
+    }
+
+    // The memory operand for an lvalue at a fixed place. A local shadows a
+    // global of the same name.
+    fn location(&self, ctx: &Context) -> Result<Operand> {
+        match self {
+            Expression::Var(v) if !ctx.vmap.contains_key(v) && ctx.globals.contains_key(v) => {
+                Ok(Operand::Rel(ctx.symbol(v), Width::Qword))
+            }
+            e => Ok(ctx.local(e.frame_offset(ctx)?)),
+        }
+    }
+
+    // Emit code storing `e` to this lvalue, leaving the value in rax.
+    fn assign(self, e: Expression, ctx: &mut Context) -> Result<Vec<Instruction>> {
+        let ty = self.ty(ctx);
+        if let Type::Array(_, _) = ctx.resolve(ty.clone()) {
+            return Err(Error::NotAssignable {
+                op: "=",
+                expr: format!("{:?}", self),
+            });
+        }
+        // `x = -x` and `x = ~x` can modify x in memory directly. Working
+        // at x's own width wraps the result as its type requires.
+        if let Some(op) = e.in_place_op(&self) {
+            let local = self.location(ctx)?;
+            return Ok(vec![
+                op(local.clone().sized(ctx.width(&ty))),
+                ctx.load(&ty, local),
+            ]);
+        }
+
+        let mut code = e.emit(ctx)?;
+        if self.in_frame() {
+            code.push(ctx.store(&ty, self.location(ctx)?, Rax));
+            return Ok(code);
+        }
+        code.push(Instruction::Push(Operand::Reg(Rax)));
+        ctx.depth += 8;
+        code.extend(self.address(ctx)?);
+        ctx.depth -= 8;
+        code.extend(vec![
+            Instruction::Mov(Operand::Reg(Rcx), Operand::Reg(Rax)),
+            Instruction::Pop(Operand::Reg(Rax)),
+            ctx.store(&ty, Operand::at(Rcx, 0), Rax),
+        ]);
+        Ok(code)
+    }
+
+    // Emit code applying `op` to this lvalue and `rhs` and storing the
+    // result back, leaving it in rax. The lvalue is evaluated once, so that
+    // `a[f()] += 1` calls f once.
+    fn update(
+        self,
+        op: BinaryOperator,
+        rhs: Expression,
+        ctx: &mut Context,
+    ) -> Result<Vec<Instruction>> {
+        // Finding a local has no side effects, so it can simply be read
+        // and then assigned.
+        if self.in_frame() {
+            let value = Expression::Binary(op, Box::new(self.clone()), Box::new(rhs));
+            return self.assign(value, ctx);
+        }
+
+        let ty = self.ty(ctx);
+        let mut code = self.address(ctx)?;
+        code.push(Instruction::Push(Operand::Reg(Rax)));
+        ctx.depth += 8;
+        let lhs = vec![
+            Instruction::Mov(Operand::Reg(Rax), Operand::at(Rsp, 0)),
+            ctx.load(&ty, Operand::at(Rax, 0)),
+        ];
+        code.extend(op.apply(lhs, ty.clone(), rhs, ctx)?);
+        ctx.depth -= 8;
+        code.extend(vec![
+            Instruction::Pop(Operand::Reg(Rcx)),
+            ctx.store(&ty, Operand::at(Rcx, 0), Rax),
+        ]);
+        Ok(code)
+    }
+
+    // Emit code leaving the address of an lvalue in rax.
+    fn address(self, ctx: &mut Context) -> Result<Vec<Instruction>> {
+        match self {
+            e if e.in_frame() => Ok(vec![Instruction::Lea(Operand::Reg(Rax), e.location(ctx)?)]),
+            Expression::Unary(UnaryOperator::Dereference, e) => e.emit(ctx),
+            Expression::Index(base, index) => match (base.ty(ctx).decay(), index.ty(ctx).decay()) {
+                (Type::Pointer(_), _) | (_, Type::Pointer(_)) => {
+                    Expression::Binary(BinaryOperator::Addition, base, index).emit(ctx)
+                }
+                _ => Err(Error::NotAPointer {
+                    expr: format!("{:?}", base),
+                }),
+            },
+            Expression::Member(e, member) => {
+                let name = match e.ty(ctx) {
+                    Type::Struct(name) => name,
+                    _ => {
+                        return Err(Error::NotAStruct {
+                            expr: format!("{:?}", e),
+                        })
+                    }
+                };
+                let offset = ctx.member_offset(&name, &member)?;
+                let mut code = e.address(ctx)?;
+                code.push(Instruction::Add(
+                    Operand::Reg(Rax),
+                    Operand::Imm(offset as i64),
+                ));
+                Ok(code)
+            }
+            _ => Err(Error::InvalidSyntax),
+        }
+    }
+
+    // Like emit, reporting the errors it would without generating code.
+    fn validate(&self, ctx: &Context) -> Result<()> {
+        match self {
+            e @ Expression::Var(_) | e @ Expression::Member(_, _) | e @ Expression::Index(_, _)
+                if matches!(ctx.resolve(e.ty(ctx)), Type::Array(_, _)) =>
+            {
+                e.validate_address(ctx)
+            }
+            e @ Expression::Var(_)
+            | e @ Expression::Member(_, _)
+            | e @ Expression::Index(_, _)
+            | e @ Expression::Unary(UnaryOperator::Dereference, _) => e.validate_address(ctx),
+            Expression::Assign(v, e) => {
+                if let Type::Array(_, _) = ctx.resolve(v.ty(ctx)) {
+                    return Err(Error::NotAssignable {
+                        op: "=",
+                        expr: format!("{:?}", v),
+                    });
+                }
+                e.validate(ctx)?;
+                v.validate_address(ctx)
+            }
+            Expression::CompoundAssign(op, v, e) if v.in_frame() => {
+                let value = Expression::Binary(*op, v.clone(), e.clone());
+                Expression::Assign(v.clone(), Box::new(value)).validate(ctx)
+            }
+            Expression::CompoundAssign(op, v, e) => {
+                v.validate_address(ctx)?;
+                op.validate(v.ty(ctx), e, ctx)
+            }
+            Expression::Postfix(op, e) => {
+                e.validate(ctx)?;
+                let one = Box::new(Expression::Constant(Constant::Int(1)));
+                let value = Expression::Binary(*op, e.clone(), one);
+                Expression::Assign(e.clone(), Box::new(value)).validate(ctx)
+            }
+            Expression::Constant(_) | Expression::String(_) => Ok(()),
+            Expression::Unary(UnaryOperator::AddressOf, e) => e.validate_address(ctx),
+            Expression::Unary(_, e) => e.validate(ctx),
+            Expression::Binary(BinaryOperator::And, e1, e2)
+            | Expression::Binary(BinaryOperator::Or, e1, e2)
+            | Expression::Comma(e1, e2) => {
+                e1.validate(ctx)?;
+                e2.validate(ctx)
+            }
+            Expression::Binary(op, e1, e2) => {
+                e1.validate(ctx)?;
+                op.validate(e1.ty(ctx), e2, ctx)
+            }
+            Expression::Call(name, args) => {
+                if args.len() > ARG_REGISTERS.len() && ctx.options.register_args_only {
+                    return Err(Error::StackArguments {
+                        function: name.clone(),
+                        count: args.len(),
+                    });
+                }
+                args.iter().try_for_each(|a| a.validate(ctx))
+            }
+            Expression::Conditional(c, e1, e2) => {
+                c.validate(ctx)?;
+                e1.validate(ctx)?;
+                e2.validate(ctx)
+            }
+            Expression::Cast(ty, e) => {
+                let from = match e.ty(ctx) {
+                    Type::Alias(name) => ctx.alias(&name)?.clone(),
+                    from => from,
+                };
+                if let (Type::Struct(_), _) | (_, Type::Struct(_)) = (ty, &from) {
+                    return Err(Error::InvalidCast {
+                        from: format!("{:?}", from),
+                        to: format!("{:?}", ty),
+                    });
+                }
+                e.validate(ctx)
+            }
+            // The operand is not evaluated, so only its type must be known.
+            Expression::Sizeof(e) => ctx.size(&e.ty(ctx)).map(|_| ()),
+            Expression::SizeofType(ty) => ctx.size(ty).map(|_| ()),
+            Expression::Initializer(_) => Err(Error::InvalidSyntax),
+        }
+    }
+
+    // Like address, reporting the errors it would without generating code.
+    fn validate_address(&self, ctx: &Context) -> Result<()> {
+        match self {
+            e if e.in_frame() => e.location(ctx).map(|_| ()),
+            Expression::Unary(UnaryOperator::Dereference, e) => e.validate(ctx),
+            Expression::Index(base, index) => match (base.ty(ctx).decay(), index.ty(ctx).decay()) {
+                (Type::Pointer(_), _) | (_, Type::Pointer(_)) => {
+                    base.validate(ctx)?;
+                    BinaryOperator::Addition.validate(base.ty(ctx), index, ctx)
+                }
+                _ => Err(Error::NotAPointer {
+                    expr: format!("{:?}", base),
+                }),
+            },
+            Expression::Member(e, member) => match e.ty(ctx) {
+                Type::Struct(name) => {
+                    ctx.member_offset(&name, member)?;
+                    e.validate_address(ctx)
+                }
+                _ => Err(Error::NotAStruct {
+                    expr: format!("{:?}", e),
+                }),
+            },
+            _ => Err(Error::InvalidSyntax),
+        }
+    }
+
+    fn substitute_constants(&mut self, defs: &HashMap<String, i64>, locals: &HashSet<String>) {
+        match self {
+            Expression::Var(v) if !locals.contains(v) => {
+                // A value too big for an int is a long, as it would be
+                // written as a literal.
+                if let Some(&value) = defs.get(v) {
+                    *self = Expression::Constant(match i32::try_from(value) {
+                        Ok(value) => Constant::Int(value as u32),
+                        Err(_) => Constant::Long(value as u64),
+                    });
+                }
+            }
+            Expression::Constant(_)
+            | Expression::Var(_)
+            | Expression::Postfix(_, _)
+            | Expression::SizeofType(_)
+            | Expression::String(_) => {}
+            Expression::Unary(_, e)
+            | Expression::Assign(_, e)
+            | Expression::CompoundAssign(_, _, e)
+            | Expression::Member(e, _)
+            | Expression::Sizeof(e)
+            | Expression::Cast(_, e) => e.substitute_constants(defs, locals),
+            Expression::Binary(_, e1, e2)
+            | Expression::Index(e1, e2)
+            | Expression::Comma(e1, e2) => {
+                e1.substitute_constants(defs, locals);
+                e2.substitute_constants(defs, locals);
+            }
+            Expression::Conditional(c, e1, e2) => {
+                c.substitute_constants(defs, locals);
+                e1.substitute_constants(defs, locals);
+                e2.substitute_constants(defs, locals);
+            }
+            Expression::Call(_, args) | Expression::Initializer(args) => {
+                for a in args.iter_mut() {
+                    a.substitute_constants(defs, locals);
+                }
+            }
+        }
+    }
+
+    // The value of a constant expression, or None if it depends on
+    // anything only known at run time.
+    fn eval_const(&self) -> Option<i64> {
+        match self.clone().fold() {
+            Expression::Constant(c) => Some(c.value()),
+            _ => None,
+        }
+    }
+
+    /// Replace each unary or binary operation on constants with its value.
+    /// Arithmetic wraps at 32 bits, as an int does. Division by zero and
+    /// out-of-range shifts are left for run time.
+    fn fold(self) -> Expression {
+        match self {
+            Expression::Unary(op, e) => match (op, e.fold()) {
+                (UnaryOperator::Dereference, e) | (UnaryOperator::AddressOf, e) => {
+                    Expression::Unary(op, Box::new(e))
+                }
+                (_, Expression::Constant(Constant::Int(i))) => {
+                    Expression::Constant(Constant::Int(match op {
+                        UnaryOperator::Negative => i.wrapping_neg(),
+                        UnaryOperator::Complement => !i,
+                        _ => (i == 0) as u32,
+                    }))
+                }
+                (_, e) => Expression::Unary(op, Box::new(e)),
+            },
+            Expression::Binary(op, e1, e2) => {
+                let (e1, e2) = (e1.fold(), e2.fold());
+                // A constant left operand of `&&` or `||` either decides the
+                // result, so that the right one is never evaluated, or leaves
+                // it to the right one alone.
+                if let (BinaryOperator::And, Expression::Constant(c))
+                | (BinaryOperator::Or, Expression::Constant(c)) = (op, &e1)
+                {
+                    let is_or = op == BinaryOperator::Or;
+                    if (c.value() != 0) == is_or {
+                        return Expression::Constant(Constant::Int(is_or as u32));
+                    }
+                    let not = |e| Expression::Unary(UnaryOperator::Negation, Box::new(e));
+                    return not(not(e2)).fold();
+                }
+                let value = match (&e1, &e2) {
+                    (
+                        Expression::Constant(Constant::Int(a)),
+                        Expression::Constant(Constant::Int(b)),
+                    ) => fold_binary(op, *a, *b),
+                    _ => None,
+                };
+                match value {
+                    Some(i) => Expression::Constant(Constant::Int(i)),
+                    None => Expression::Binary(op, Box::new(e1), Box::new(e2)),
+                }
+            }
+            Expression::Assign(v, e) => Expression::Assign(v, Box::new(e.fold())),
+            Expression::CompoundAssign(op, v, e) => {
+                Expression::CompoundAssign(op, v, Box::new(e.fold()))
+            }
+            Expression::Member(e, m) => Expression::Member(Box::new(e.fold()), m),
+            Expression::Index(e1, e2) => {
+                Expression::Index(Box::new(e1.fold()), Box::new(e2.fold()))
+            }
+            Expression::Comma(e1, e2) => {
+                Expression::Comma(Box::new(e1.fold()), Box::new(e2.fold()))
+            }
+            Expression::Conditional(c, e1, e2) => Expression::Conditional(
+                Box::new(c.fold()),
+                Box::new(e1.fold()),
+                Box::new(e2.fold()),
+            ),
+            Expression::Call(name, args) => {
+                Expression::Call(name, args.into_iter().map(Expression::fold).collect())
+            }
+            Expression::Initializer(items) => {
+                Expression::Initializer(items.into_iter().map(Expression::fold).collect())
+            }
+            Expression::Cast(ty, e) => match (ty, e.fold()) {
+                (Type::UInt, Expression::Constant(Constant::Int(i))) => {
+                    Expression::Constant(Constant::UInt(i))
+                }
+                (ty, e) => Expression::Cast(ty, Box::new(e)),
+            },
+            e @ Expression::Constant(_)
+            | e @ Expression::Var(_)
+            | e @ Expression::Sizeof(_)
+            | e @ Expression::SizeofType(_)
+            | e @ Expression::String(_)
+            | e @ Expression::Postfix(_, _) => e,
+        }
+    }
+}
+
+// The value of `a op b` for two int constants, if it can be known at
+// compile time.
+fn fold_binary(op: BinaryOperator, a: u32, b: u32) -> Option<u32> {
+    let (sa, sb) = (a as i32, b as i32);
+    Some(match op {
+        BinaryOperator::Addition => a.wrapping_add(b),
+        BinaryOperator::Subtraction => a.wrapping_sub(b),
+        BinaryOperator::Multiplication => a.wrapping_mul(b),
+        // Truncating and flooring differ when there is a remainder of the
+        // other sign to the divisor, so that case is left to emit, which
+        // knows which the options ask for.
+        BinaryOperator::Division | BinaryOperator::Modulo
+            if b != 0 && sa.wrapping_rem(sb) != 0 && (sa < 0) != (sb < 0) =>
+        {
+            return None
+        }
+        BinaryOperator::Division if b != 0 => sa.wrapping_div(sb) as u32,
+        BinaryOperator::Modulo if b != 0 => sa.wrapping_rem(sb) as u32,
+        BinaryOperator::Division | BinaryOperator::Modulo => return None,
+        BinaryOperator::BitAnd => a & b,
+        BinaryOperator::BitOr => a | b,
+        BinaryOperator::BitXor => a ^ b,
+        BinaryOperator::ShiftLeft if b < 32 => a << b,
+        BinaryOperator::ShiftRight if b < 32 => (sa >> b) as u32,
+        BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight => return None,
+        BinaryOperator::LessThan => (sa < sb) as u32,
+        BinaryOperator::LessThanEqual => (sa <= sb) as u32,
+        BinaryOperator::GreaterThan => (sa > sb) as u32,
+        BinaryOperator::GreaterThanEqual => (sa >= sb) as u32,
+        BinaryOperator::Equal => (a == b) as u32,
+        BinaryOperator::NotEqual => (a != b) as u32,
+        BinaryOperator::And => (a != 0 && b != 0) as u32,
+        BinaryOperator::Or => (a != 0 || b != 0) as u32,
+    })
+}
+
 #[derive(PartialEq)]
 enum Associativity {
     Left,
     Right,
 }
 
-impl ASTNode for Expression {
-    fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Expression> {
+impl Expression {
+    // Parse an expression, stopping at the first operator that binds less
+    // tightly than `min_precedence`. At 1, that is a comma, which leaves
+    // the rest of a list of arguments or declarators.
+    fn parse_bounded<I: Iterator<Item = Token>>(
+        t: &mut PutBackN<I>,
+        min_precedence: u8,
+    ) -> Result<Expression> {
+        // Apply any `.member`, `->member` and `[index]` suffixes to `e`, left
+        // to right.
+        fn parse_postfix<I: Iterator<Item = Token>>(
+            t: &mut PutBackN<I>,
+            mut e: Expression,
+        ) -> Result<Expression> {
+            while let Some(tok) = t.next() {
+                e = match tok {
+                    tok @ Token::Dot | tok @ Token::Arrow => {
+                        let member = match t.next().ok_or(Error::UnexpectedEnd {
+                            wanted: "Member",
+                            span: None,
+                        })? {
+                            Token::Identifier(member) => member,
+                            tok => {
+                                return Err(Error::UnexpectedToken {
+                                    wanted: "Member",
+                                    expected: vec![Token::Identifier(String::from("_"))],
+                                    found: tok,
+                                    tokens: t.collect(),
+                                    span: None,
+                                })
+                            }
+                        };
+                        if tok == Token::Arrow {
+                            e = Expression::Unary(UnaryOperator::Dereference, Box::new(e));
+                        }
+                        Expression::Member(Box::new(e), member)
+                    }
+                    Token::OpenBracket => {
+                        let index = parse_expr(t, 0)?;
+                        consume_token(t, Token::CloseBracket)?;
+                        Expression::Index(Box::new(e), Box::new(index))
+                    }
+                    tok @ Token::Increment | tok @ Token::Decrement => {
+                        let (op, name) = match tok {
+                            Token::Increment => (BinaryOperator::Addition, "++"),
+                            _ => (BinaryOperator::Subtraction, "--"),
+                        };
+                        match e {
+                            e if e.is_lvalue() => Expression::Postfix(op, Box::new(e)),
+                            e => {
+                                return Err(Error::NotAssignable {
+                                    op: name,
+                                    expr: format!("{:?}", e),
+                                })
+                            }
+                        }
+                    }
+                    tok => {
+                        t.put_back(tok);
+                        break;
+                    }
+                };
+            }
+            Ok(e)
+        }
+
         fn parse_atom<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Expression> {
             match t.next().ok_or(Error::UnexpectedEnd {
                 wanted: "Expression",
+                span: None,
             })? {
-                tok @ Token::Negative | tok @ Token::Negation | tok @ Token::Complement => {
+                tok @ Token::Negative
+                | tok @ Token::Negation
+                | tok @ Token::Complement
+                | tok @ Token::Multiplication
+                | tok @ Token::BitAnd => {
                     t.put_back(tok);
                     let op = UnaryOperator::parse(t)?;
                     let e = parse_atom(t)?;
+                    if let (UnaryOperator::AddressOf, false) = (op, e.is_lvalue()) {
+                        return Err(Error::NotAssignable {
+                            op: "&",
+                            expr: format!("{:?}", e),
+                        });
+                    }
                     Ok(Expression::Unary(op, Box::new(e)))
                 }
+                // `++x` is `x += 1`.
+                tok @ Token::Increment | tok @ Token::Decrement => {
+                    let (op, name) = match tok {
+                        Token::Increment => (BinaryOperator::Addition, "++"),
+                        _ => (BinaryOperator::Subtraction, "--"),
+                    };
+                    match parse_atom(t)? {
+                        e if e.is_lvalue() => Ok(Expression::CompoundAssign(
+                            op,
+                            Box::new(e),
+                            Box::new(Expression::Constant(Constant::Int(1))),
+                        )),
+                        e => Err(Error::NotAssignable {
+                            op: name,
+                            expr: format!("{:?}", e),
+                        }),
+                    }
+                }
+                Token::Keyword(Keyword::Sizeof) => match t.next() {
+                    Some(Token::OpenParenthesis) => match t.next() {
+                        Some(tok) if is_type_keyword(&tok) => {
+                            Ok(Expression::SizeofType(parse_type_name(t, tok)?))
+                        }
+                        tok => {
+                            if let Some(tok) = tok {
+                                t.put_back(tok);
+                            }
+                            t.put_back(Token::OpenParenthesis);
+                            Ok(Expression::Sizeof(Box::new(parse_atom(t)?)))
+                        }
+                    },
+                    tok => {
+                        if let Some(tok) = tok {
+                            t.put_back(tok);
+                        }
+                        Ok(Expression::Sizeof(Box::new(parse_atom(t)?)))
+                    }
+                },
+                // Adjacent string literals are concatenated.
+                Token::Literal(Literal::String(mut bytes)) => {
+                    while let Some(tok) = t.next() {
+                        match tok {
+                            Token::Literal(Literal::String(more)) => bytes.extend(more),
+                            tok => {
+                                t.put_back(tok);
+                                break;
+                            }
+                        }
+                    }
+                    Ok(Expression::String(bytes))
+                }
                 tok @ Token::Literal(_) => {
                     t.put_back(tok);
                     Ok(Expression::Constant(Constant::parse(t)?))
                 }
-                Token::OpenParenthesis => {
-                    let v = parse_expr(t, 1);
-                    consume_token(t, Token::CloseParenthesis)?;
-                    v
-                }
-                Token::Identifier(s) => Ok(Expression::Var(s)),
-                tok => Err(Error::UnexpectedToken {
-                    wanted: "Expression atom",
-                    expected: vec![
-                        Token::Negative,
-                        Token::Negation,
-                        Token::Complement,
-                        Token::OpenParenthesis,
-                        Token::Literal(Literal::None),
-                    ],
-                    found: tok,
-                    tokens: t.collect(),
-                }),
+                Token::OpenParenthesis => match t.next() {
+                    Some(tok) if is_type_keyword(&tok) => {
+                        let ty = parse_type_name(t, tok)?;
+                        Ok(Expression::Cast(ty, Box::new(parse_atom(t)?)))
+                    }
+                    tok => {
+                        if let Some(tok) = tok {
+                            t.put_back(tok);
+                        }
+                        let v = parse_expr(t, 0)?;
+                        consume_token(t, Token::CloseParenthesis)?;
+                        parse_postfix(t, v)
+                    }
+                },
+                Token::Identifier(s) => match t.next() {
+                    Some(Token::OpenParenthesis) => {
+                        let mut args = Vec::new();
+                        match t.next() {
+                            Some(Token::CloseParenthesis) => {}
+                            tok => {
+                                if let Some(tok) = tok {
+                                    t.put_back(tok);
+                                }
+                                loop {
+                                    args.push(parse_expr(t, 1)?);
+                                    match t.next().ok_or(Error::UnexpectedEnd {
+                                        wanted: "Argument list",
+                                        span: None,
+                                    })? {
+                                        Token::Comma => {}
+                                        Token::CloseParenthesis => break,
+                                        tok => {
+                                            return Err(Error::UnexpectedToken {
+                                                wanted: "Argument list",
+                                                expected: vec![
+                                                    Token::Comma,
+                                                    Token::CloseParenthesis,
+                                                ],
+                                                found: tok,
+                                                tokens: t.collect(),
+                                                span: None,
+                                            })
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        parse_postfix(t, Expression::Call(s, args))
+                    }
+                    tok => {
+                        if let Some(tok) = tok {
+                            t.put_back(tok);
+                        }
+                        parse_postfix(t, Expression::Var(s))
+                    }
+                },
+                tok => match unary_suggestion(&tok) {
+                    Some(suggested) => Err(Error::MisplacedOperator {
+                        wanted: "Expression atom",
+                        found: tok,
+                        suggested,
+                        tokens: t.collect(),
+                    }),
+                    None => Err(Error::UnexpectedToken {
+                        wanted: "Expression atom",
+                        expected: vec![
+                            Token::Negative,
+                            Token::Negation,
+                            Token::Complement,
+                            Token::Multiplication,
+                            Token::OpenParenthesis,
+                            Token::Literal(Literal::None),
+                        ],
+                        found: tok,
+                        tokens: t.collect(),
+                        span: None,
+                    }),
+                },
             }
-        };
+        }
 
         fn parse_expr<I: Iterator<Item = Token>>(
             t: &mut PutBackN<I>,
@@ -301,12 +4716,12 @@ impl ASTNode for Expression {
             enum Symb {
                 Bin(BinaryOperator),
                 Assign(Option<Token>),
+                Conditional,
+                Comma,
             }
 
-            loop {
-                let (op, prec, assoc, pbtok) = match t.next().ok_or(Error::UnexpectedEnd {
-                    wanted: "Expression",
-                })? {
+            while let Some(tok) = t.next() {
+                let (op, prec, assoc, pbtok) = match tok {
                     Token::Addition => (
                         Symb::Bin(BinaryOperator::Addition),
                         11,
@@ -331,6 +4746,24 @@ impl ASTNode for Expression {
                         Associativity::Left,
                         Token::Division,
                     ),
+                    Token::Modulo => (
+                        Symb::Bin(BinaryOperator::Modulo),
+                        12,
+                        Associativity::Left,
+                        Token::Modulo,
+                    ),
+                    Token::ShiftLeft => (
+                        Symb::Bin(BinaryOperator::ShiftLeft),
+                        10,
+                        Associativity::Left,
+                        Token::ShiftLeft,
+                    ),
+                    Token::ShiftRight => (
+                        Symb::Bin(BinaryOperator::ShiftRight),
+                        10,
+                        Associativity::Left,
+                        Token::ShiftRight,
+                    ),
                     Token::LessThan => (
                         Symb::Bin(BinaryOperator::LessThan),
                         9,
@@ -341,7 +4774,7 @@ impl ASTNode for Expression {
                         Symb::Bin(BinaryOperator::LessThanEqual),
                         9,
                         Associativity::Left,
-                        Token::LessThan,
+                        Token::LessThanEqual,
                     ),
                     Token::GreaterThan => (
                         Symb::Bin(BinaryOperator::GreaterThan),
@@ -367,6 +4800,24 @@ impl ASTNode for Expression {
                         Associativity::Left,
                         Token::NotEqual,
                     ),
+                    Token::BitAnd => (
+                        Symb::Bin(BinaryOperator::BitAnd),
+                        7,
+                        Associativity::Left,
+                        Token::BitAnd,
+                    ),
+                    Token::BitXor => (
+                        Symb::Bin(BinaryOperator::BitXor),
+                        6,
+                        Associativity::Left,
+                        Token::BitXor,
+                    ),
+                    Token::BitOr => (
+                        Symb::Bin(BinaryOperator::BitOr),
+                        5,
+                        Associativity::Left,
+                        Token::BitOr,
+                    ),
                     Token::And => (
                         Symb::Bin(BinaryOperator::And),
                         4,
@@ -379,6 +4830,13 @@ impl ASTNode for Expression {
                         Associativity::Left,
                         Token::Or,
                     ),
+                    Token::QuestionMark => (
+                        Symb::Conditional,
+                        2,
+                        Associativity::Right,
+                        Token::QuestionMark,
+                    ),
+                    Token::Comma => (Symb::Comma, 0, Associativity::Left, Token::Comma),
                     Token::Assign => (Symb::Assign(None), 1, Associativity::Right, Token::Assign),
                     Token::AssignAdd => (
                         Symb::Assign(Some(Token::AssignAdd)),
@@ -458,181 +4916,460 @@ impl ASTNode for Expression {
                     prec
                 };
 
+                // The middle operand of `?:` is delimited like a
+                // parenthesized expression.
+                let middle = match op {
+                    Symb::Conditional => {
+                        let middle = parse_expr(t, 0)?;
+                        consume_token(t, Token::Colon)?;
+                        Some(Box::new(middle))
+                    }
+                    _ => None,
+                };
+
                 let rhs = Box::new(parse_expr(t, next_min)?);
                 //                lhs = Expression::Binary(op, Box::new(lhs), Box::new(parse_expr(t, next_min)?));
                 lhs = match op {
                     Symb::Bin(op) => Expression::Binary(op, Box::new(lhs), rhs),
+                    Symb::Comma => Expression::Comma(Box::new(lhs), rhs),
+                    Symb::Conditional => Expression::Conditional(
+                        Box::new(lhs),
+                        middle.expect("Missing conditional operand... Should be unreachable."),
+                        rhs,
+                    ),
                     Symb::Assign(s) => match lhs {
-//                        Expression::Var(v) => Expression::Assign(v, rhs),
-                        Expression::Var(v) => Expression::Assign(v.clone(), s.map_or_else(|| rhs.clone(), |s| Box::new(Expression::Binary(match s {
-                            Token::AssignAdd => BinaryOperator::Addition,
-                            Token::AssignSub => BinaryOperator::Subtraction,
-                            Token::AssignMul => BinaryOperator::Multiplication,
-                            Token::AssignDiv => BinaryOperator::Division,
-                            Token::AssignMod => BinaryOperator::Modulo,
-                            Token::AssignAnd => BinaryOperator::BitAnd,
-                            Token::AssignOr => BinaryOperator::BitOr,
-                            Token::AssignXor => BinaryOperator::BitXor,
-                            Token::AssignShiftLeft => BinaryOperator::ShiftLeft,
-                            Token::AssignShiftRight => BinaryOperator::ShiftRight,
-                            _ => panic!("Invalid compound assignment type... Should be unreachable."),
-                        }, Box::new(Expression::Var(v.clone())), rhs.clone())))),
+                        _ if lhs.is_lvalue() => match s {
+                            None => Expression::Assign(Box::new(lhs), rhs),
+                            Some(s) => Expression::CompoundAssign(
+                                match s {
+                                    Token::AssignAdd => BinaryOperator::Addition,
+                                    Token::AssignSub => BinaryOperator::Subtraction,
+                                    Token::AssignMul => BinaryOperator::Multiplication,
+                                    Token::AssignDiv => BinaryOperator::Division,
+                                    Token::AssignMod => BinaryOperator::Modulo,
+                                    Token::AssignAnd => BinaryOperator::BitAnd,
+                                    Token::AssignOr => BinaryOperator::BitOr,
+                                    Token::AssignXor => BinaryOperator::BitXor,
+                                    Token::AssignShiftLeft => BinaryOperator::ShiftLeft,
+                                    Token::AssignShiftRight => BinaryOperator::ShiftRight,
+                                    _ => panic!(
+                                        "Invalid compound assignment type... Should be unreachable."
+                                    ),
+                                },
+                                Box::new(lhs),
+                                rhs,
+                            ),
+                        },
                         _ => Err(Error::InvalidSyntax)?,
                     },
                 };
             }
             Ok(lhs)
-        };
-        parse_expr(t, 1)
-    }
-
-    fn emit(self, vmap: &mut HashMap<String, usize>, stack_index: &mut usize) -> Result<String> {
-        match self {
-            Expression::Var(s) => Ok(format!(
-                "mov rax, [rbp - {}]\n",
-                vmap.get(&s).ok_or(Error::UndeclaredVariable { var: s })?
-            )),
-            Expression::Assign(v, e) => Ok(format!(
-                "\
-                 {}\
-                 mov [rbp - {}], rax\n\
-                 ",
-                e.emit(vmap, stack_index)?,
-                vmap.get(&v).ok_or(Error::UndeclaredVariable { var: v })?
-            )),
-            Expression::Constant(c) => Ok(format!("mov rax, {}\n", c.emit(vmap, stack_index)?)),
-            Expression::Unary(op, e) => Ok(format!(
-                "\
-                 {} \
-                 {} \
-                 ",
-                e.emit(vmap, stack_index)?,
-                op.emit(vmap, stack_index)?
-            )),
+        }
+        parse_expr(t, min_precedence)
+    }
+}
+
+impl ASTNode for Expression {
+    fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Expression> {
+        Expression::parse_bounded(t, 0)
+    }
+
+    fn emit(self, ctx: &mut Context) -> Result<Vec<Instruction>> {
+        match self {
+            // An array is used as the address of its first element.
+            e @ Expression::Var(_) | e @ Expression::Member(_, _) | e @ Expression::Index(_, _)
+                if matches!(ctx.resolve(e.ty(ctx)), Type::Array(_, _)) =>
+            {
+                e.address(ctx)
+            }
+            e @ Expression::Var(_) | e @ Expression::Member(_, _) if e.in_frame() => {
+                let ty = e.ty(ctx);
+                Ok(vec![ctx.load(&ty, e.location(ctx)?)])
+            }
+            e @ Expression::Var(_)
+            | e @ Expression::Member(_, _)
+            | e @ Expression::Index(_, _)
+            | e @ Expression::Unary(UnaryOperator::Dereference, _) => {
+                let ty = e.ty(ctx);
+                let mut code = e.address(ctx)?;
+                code.push(ctx.load(&ty, Operand::at(Rax, 0)));
+                Ok(code)
+            }
+            Expression::Assign(v, e) => v.assign(*e, ctx),
+            Expression::CompoundAssign(op, v, e) => v.update(op, *e, ctx),
+            // Save the old value, then assign `e + 1` or `e - 1` as usual.
+            Expression::Postfix(op, e) => {
+                let mut code = e.clone().emit(ctx)?;
+                code.push(Instruction::Push(Operand::Reg(Rax)));
+                ctx.depth += 8;
+                let one = Box::new(Expression::Constant(Constant::Int(1)));
+                let update =
+                    Expression::Assign(e.clone(), Box::new(Expression::Binary(op, e, one)));
+                code.extend(update.emit(ctx)?);
+                ctx.depth -= 8;
+                code.push(Instruction::Pop(Operand::Reg(Rax)));
+                Ok(code)
+            }
+            Expression::Constant(c) => c.emit(ctx),
+            Expression::Unary(UnaryOperator::AddressOf, e) => e.address(ctx),
+            Expression::Comma(e1, e2) => {
+                let mut code = e1.emit(ctx)?;
+                code.extend(e2.emit(ctx)?);
+                Ok(code)
+            }
+            // `!!e` only normalizes e to 0 or 1, which takes one test.
+            Expression::Unary(UnaryOperator::Negation, e)
+                if matches!(*e, Expression::Unary(UnaryOperator::Negation, _)) =>
+            {
+                let e = match *e {
+                    Expression::Unary(_, e) => e,
+                    _ => unreachable!(),
+                };
+                let mut code = e.emit(ctx)?;
+                code.extend(vec![
+                    Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(0)),
+                    Instruction::Mov(Operand::Reg(Rax), Operand::Imm(0)),
+                    Instruction::Set(Condition::NotEqual, Operand::Byte(Rax)),
+                ]);
+                Ok(code)
+            }
+            Expression::Unary(op, e) => {
+                let unsigned = ctx.resolve(e.ty(ctx)) == Type::UInt;
+                let mut code = e.emit(ctx)?;
+                code.extend(op.emit(ctx)?);
+                // Wrap an unsigned result back into 32 bits.
+                if let (true, UnaryOperator::Negative) | (true, UnaryOperator::Complement) =
+                    (unsigned, op)
+                {
+                    code.push(Instruction::Mov(Operand::Dword(Rax), Operand::Dword(Rax)));
+                }
+                Ok(code)
+            }
             Expression::Binary(op, e1, e2)
                 if op != BinaryOperator::And && op != BinaryOperator::Or =>
             {
-                Ok(format!(
-                    "\
-                     {}\
-                     push rax\n\
-                     {}\
-                     pop rcx\n\
-                     {}\
-                     ",
-                    e1.emit(vmap, stack_index)?,
-                    e2.emit(vmap, stack_index)?,
-                    op.emit(vmap, stack_index)?
-                ))
+                let ty = e1.ty(ctx);
+                let lhs = e1.emit(ctx)?;
+                op.apply(lhs, ty, *e2, ctx)
             }
-            Expression::Binary(op, e1, e2) => match op {
-                BinaryOperator::And => Ok(format!(
-                    "\
-                     {0}\
-                     cmp rax, 0\n\
-                     jne {2}\n\
-                     jmp {3}\n\
-                     {2}:\n\
-                     {1}\
-                     cmp rax, 0\n\
-                     mov rax, 0\n\
-                     setne al\n\
-                     {3}:\n\
-                     ",
-                    e1.emit(vmap, stack_index)?,
-                    e2.emit(vmap, stack_index)?,
-                    gen_label(),
-                    gen_label()
-                )),
-                BinaryOperator::Or => Ok(format!(
-                    "\
-                     {0}\
-                     cmp rax, 0\n\
-                     je {2}\n\
-                     jmp {3}\n\
-                     {2}:\n\
-                     {1}\
-                     cmp rax, 0\n\
-                     mov rax, 0\n\
-                     setne al\n\
-                     {3}:\n\
-                     ",
-                    e1.emit(vmap, stack_index)?,
-                    e2.emit(vmap, stack_index)?,
-                    gen_label(),
-                    gen_label()
-                )),
-                _ => panic!("invalid syntax"),
-            },
+            // Short-circuit: the right operand is only evaluated if the left
+            // one doesn't already decide the result.
+            Expression::Binary(op, e1, e2) => {
+                let (skip, rhs, end) = (
+                    match op {
+                        BinaryOperator::And => Condition::NotEqual,
+                        BinaryOperator::Or => Condition::Equal,
+                        _ => panic!("invalid syntax"),
+                    },
+                    ctx.gen_label(),
+                    ctx.gen_label(),
+                );
+                let mut code = e1.emit(ctx)?;
+                code.extend(vec![
+                    Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(0)),
+                    Instruction::Jcc(skip, rhs.clone()),
+                    Instruction::Jmp(Operand::Symbol(end.clone())),
+                    Instruction::Label(rhs),
+                ]);
+                code.extend(e2.emit(ctx)?);
+                code.extend(vec![
+                    Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(0)),
+                    Instruction::Mov(Operand::Reg(Rax), Operand::Imm(0)),
+                    Instruction::Set(Condition::NotEqual, Operand::Byte(Rax)),
+                    Instruction::Label(end),
+                ]);
+                Ok(code)
+            }
+            Expression::Call(name, args) => {
+                let count = args.len();
+                if count > ARG_REGISTERS.len() && ctx.options.register_args_only {
+                    return Err(Error::StackArguments {
+                        function: name,
+                        count,
+                    });
+                }
+                let types = if ctx.options.mangle {
+                    args.iter().map(|a| a.ty(ctx)).collect()
+                } else {
+                    Vec::new()
+                };
+                // rsp must be 16-byte aligned at the call. It was 8 off at
+                // entry, before the saved registers and the locals went on.
+                // Arguments past the sixth go at the top of the stack,
+                // lowest first, so their space is reserved along with the
+                // padding before any argument is evaluated.
+                let on_stack = count.saturating_sub(ARG_REGISTERS.len());
+                let padding =
+                    (8 * (ctx.saved_registers().len() + 1) + ctx.depth + 8 * on_stack) % 16;
+                let reserved = 8 * on_stack + padding;
+                let mut code = Vec::new();
+                if reserved != 0 {
+                    code.push(Instruction::Sub(
+                        Operand::Reg(Rsp),
+                        Operand::Imm(reserved as i64),
+                    ));
+                    ctx.depth += reserved;
+                }
+                for (i, a) in args.into_iter().enumerate() {
+                    code.extend(a.emit(ctx)?);
+                    if i < ARG_REGISTERS.len() {
+                        code.push(Instruction::Push(Operand::Reg(Rax)));
+                        ctx.depth += 8;
+                    } else {
+                        // The six register arguments are pushed below it.
+                        code.push(Instruction::Mov(
+                            Operand::at(Rsp, 8 * i as i64),
+                            Operand::Reg(Rax),
+                        ));
+                    }
+                }
+                let in_registers = count - on_stack;
+                for &reg in ARG_REGISTERS[..in_registers].iter().rev() {
+                    code.push(Instruction::Pop(Operand::Reg(reg)));
+                }
+                ctx.depth -= 8 * in_registers;
+
+                let symbol = ctx.call_symbol(&name, &types);
+                ctx.sections.reference(&symbol);
+                if ctx.options.check_stack_alignment {
+                    code.extend(vec![
+                        Instruction::Test(Operand::Reg(Rsp), Operand::Imm(15)),
+                        Instruction::Jcc(Condition::NotEqual, String::from(MISALIGNED_HANDLER)),
+                    ]);
+                }
+                code.push(Instruction::Call(symbol));
+                // Should the function return after all, trap rather than
+                // run on into whatever follows.
+                if is_noreturn(&name, &ctx.options) {
+                    code.push(Instruction::Ud2);
+                } else if reserved != 0 {
+                    code.push(Instruction::Add(
+                        Operand::Reg(Rsp),
+                        Operand::Imm(reserved as i64),
+                    ));
+                }
+                ctx.depth -= reserved;
+                Ok(code)
+            }
+            // Loading a constant or variable leaves the flags alone, so both
+            // branches can be loaded after the comparison.
+            Expression::Conditional(c, e1, e2)
+                if ctx.options.cmov && e1.is_simple() && e2.is_simple() =>
+            {
+                let mut code = c.emit(ctx)?;
+                code.push(Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(0)));
+                code.extend(e2.emit(ctx)?);
+                code.push(Instruction::Mov(Operand::Reg(Rcx), Operand::Reg(Rax)));
+                code.extend(e1.emit(ctx)?);
+                code.push(Instruction::Cmov(
+                    Condition::Equal,
+                    Operand::Reg(Rax),
+                    Operand::Reg(Rcx),
+                ));
+                Ok(code)
+            }
+            Expression::Conditional(c, e1, e2) => {
+                let otherwise = ctx.gen_label();
+                let end = ctx.gen_label();
+                let mut code = c.emit(ctx)?;
+                code.push(Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(0)));
+                code.push(Instruction::Jcc(Condition::Equal, otherwise.clone()));
+                code.extend(e1.emit(ctx)?);
+                code.push(Instruction::Jmp(Operand::Symbol(end.clone())));
+                code.push(Instruction::Label(otherwise));
+                code.extend(e2.emit(ctx)?);
+                code.push(Instruction::Label(end));
+                Ok(code)
+            }
+            // Every scalar is already held in all of rax, so only narrowing
+            // needs code: the upper bits are replaced with the sign of the
+            // rest for int and char, or cleared for unsigned.
+            Expression::Cast(ty, e) => {
+                let from = match e.ty(ctx) {
+                    Type::Alias(name) => ctx.alias(&name)?.clone(),
+                    from => from,
+                };
+                match (&ty, &from) {
+                    (Type::Struct(_), _) | (_, Type::Struct(_)) => Err(Error::InvalidCast {
+                        from: format!("{:?}", from),
+                        to: format!("{:?}", ty),
+                    }),
+                    // A cast to the operand's own type changes nothing.
+                    (to, from) if to == from => e.emit(ctx),
+                    (Type::Int, Type::Long)
+                    | (Type::Int, Type::UInt)
+                    | (Type::Int, Type::ULong)
+                    | (Type::Int, Type::Pointer(_)) => {
+                        let mut code = e.emit(ctx)?;
+                        code.push(Instruction::Movsxd(Operand::Reg(Rax), Operand::Dword(Rax)));
+                        Ok(code)
+                    }
+                    (Type::Char, _) => {
+                        let mut code = e.emit(ctx)?;
+                        code.push(Instruction::Movsx(Operand::Reg(Rax), Operand::Byte(Rax)));
+                        Ok(code)
+                    }
+                    (Type::UInt, _) => {
+                        let mut code = e.emit(ctx)?;
+                        code.push(Instruction::Mov(Operand::Dword(Rax), Operand::Dword(Rax)));
+                        Ok(code)
+                    }
+                    _ => e.emit(ctx),
+                }
+            }
+            // Pointers are always 8 bytes, whatever they point to.
+            Expression::Sizeof(e) => Ok(vec![Instruction::Mov(
+                Operand::Reg(Rax),
+                Operand::Imm(ctx.size(&e.ty(ctx))? as i64),
+            )]),
+            Expression::SizeofType(ty) => Ok(vec![Instruction::Mov(
+                Operand::Reg(Rax),
+                Operand::Imm(ctx.size(&ty)? as i64),
+            )]),
+            Expression::String(bytes) => {
+                let label = ctx.gen_label();
+                let values = bytes
+                    .iter()
+                    .chain(std::iter::once(&0))
+                    .map(u8::to_string)
+                    .collect::<Vec<_>>();
+                ctx.sections.object(Section::Rodata, &label, 1, &values);
+                Ok(vec![Instruction::Lea(
+                    Operand::Reg(Rax),
+                    Operand::Rel(label, Width::Byte),
+                )])
+            }
+            Expression::Initializer(_) => Err(Error::InvalidSyntax),
             //Expression::Null => String::from(""),
         }
     }
 }
 
+/// An integer constant, stored as its two's complement bit pattern.
 #[derive(Debug, Copy, Clone)]
-enum Constant {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Constant {
     Int(u32),
+    UInt(u32),
+    Long(u64),
+    ULong(u64),
+}
+
+impl Constant {
+    // The value, sign-extended to 64 bits if it is signed.
+    fn value(self) -> i64 {
+        match self {
+            Constant::Int(i) => i64::from(i as i32),
+            Constant::UInt(i) => i64::from(i),
+            Constant::Long(i) | Constant::ULong(i) => i as i64,
+        }
+    }
 }
 
 impl ASTNode for Constant {
     fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Constant> {
-        match t.next().unwrap() {
+        match t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "Constant",
+            span: None,
+        })? {
             Token::Literal(Literal::Int(i)) => Ok(Constant::Int(i)),
+            Token::Literal(Literal::UInt(i)) => Ok(Constant::UInt(i)),
+            Token::Literal(Literal::Long(i)) => Ok(Constant::Long(i)),
+            Token::Literal(Literal::ULong(i)) => Ok(Constant::ULong(i)),
+            Token::Literal(Literal::Invalid(literal)) => Err(Error::InvalidLiteral { literal }),
+            Token::Literal(Literal::InvalidSuffix(literal)) => {
+                Err(Error::InvalidSuffix { literal })
+            }
+            // A character constant is an int in C.
+            Token::Literal(Literal::Char(c)) => Ok(Constant::Int(u32::from(c))),
             tok => Err(Error::UnexpectedToken {
                 wanted: "Constant",
                 expected: vec![Token::Literal(Literal::Int(0))],
                 found: tok,
                 tokens: t.collect(),
+                span: None,
             }),
         }
     }
 
-    fn emit(self, _vmap: &mut HashMap<String, usize>, _stack_index: &mut usize) -> Result<String> {
+    fn emit(self, _ctx: &mut Context) -> Result<Vec<Instruction>> {
         match self {
-            Constant::Int(i) => Ok(i.to_string()),
+            // Sign-extend, so that e.g. 0xffffffff loads as -1.
+            Constant::Int(i) => Ok(vec![Instruction::Mov(
+                Operand::Reg(Rax),
+                Operand::Imm(i64::from(i as i32)),
+            )]),
+            Constant::UInt(i) => Ok(vec![Instruction::Mov(
+                Operand::Reg(Rax),
+                Operand::Imm(i64::from(i)),
+            )]),
+            Constant::Long(i) | Constant::ULong(i) => Ok(vec![Instruction::Mov(
+                Operand::Reg(Rax),
+                Operand::Imm(i as i64),
+            )]),
         }
     }
 }
 
 #[derive(Debug, Copy, Clone)]
-enum UnaryOperator {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum UnaryOperator {
     Negative,
     Complement,
     Negation,
+    Dereference,
+    AddressOf,
 }
 
 impl ASTNode for UnaryOperator {
     fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<UnaryOperator> {
-        match t.next().unwrap() {
+        match t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "UnaryOperator",
+            span: None,
+        })? {
             Token::Complement => Ok(UnaryOperator::Complement),
             Token::Negative => Ok(UnaryOperator::Negative),
             Token::Negation => Ok(UnaryOperator::Negation),
+            Token::Multiplication => Ok(UnaryOperator::Dereference),
+            Token::BitAnd => Ok(UnaryOperator::AddressOf),
             tok => Err(Error::UnexpectedToken {
                 wanted: "UnaryOperator",
-                expected: vec![Token::Complement, Token::Negation, Token::Negative],
+                expected: vec![
+                    Token::Complement,
+                    Token::Negation,
+                    Token::Negative,
+                    Token::Multiplication,
+                    Token::BitAnd,
+                ],
                 found: tok,
                 tokens: t.collect(),
+                span: None,
             }),
         }
     }
 
-    fn emit(self, _vmap: &mut HashMap<String, usize>, _stack_index: &mut usize) -> Result<String> {
+    fn emit(self, _ctx: &mut Context) -> Result<Vec<Instruction>> {
         Ok(match self {
-            UnaryOperator::Negative => String::from("neg rax\n"),
-            UnaryOperator::Complement => String::from("not rax\n"),
-            UnaryOperator::Negation => String::from(
-                "\
-                 cmp rax, 0 \n\
-                 mov rax, 0 \n\
-                 sete al \n\
-                 ",
-            ),
+            UnaryOperator::Negative => vec![Instruction::Neg(Operand::Reg(Rax))],
+            UnaryOperator::Complement => vec![Instruction::Not(Operand::Reg(Rax))],
+            UnaryOperator::Negation => vec![
+                Instruction::Cmp(Operand::Reg(Rax), Operand::Imm(0)),
+                Instruction::Mov(Operand::Reg(Rax), Operand::Imm(0)),
+                Instruction::Set(Condition::Equal, Operand::Byte(Rax)),
+            ],
+            // These need the operand's type or address rather than its
+            // value, which `Expression::emit` handles itself.
+            UnaryOperator::Dereference | UnaryOperator::AddressOf => {
+                return Err(Error::InvalidSyntax)
+            }
         })
     }
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
-enum BinaryOperator {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum BinaryOperator {
     Addition,
     Subtraction,
     Multiplication,
@@ -655,7 +5392,10 @@ enum BinaryOperator {
 
 impl ASTNode for BinaryOperator {
     fn parse<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<BinaryOperator> {
-        match t.next().unwrap() {
+        match t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "BinaryOperator",
+            span: None,
+        })? {
             Token::Addition => Ok(BinaryOperator::Addition),
             Token::Negative => Ok(BinaryOperator::Subtraction),
             Token::Multiplication => Ok(BinaryOperator::Multiplication),
@@ -682,122 +5422,633 @@ impl ASTNode for BinaryOperator {
                 ],
                 found: tok,
                 tokens: t.collect(),
+                span: None,
             }),
         }
     }
 
-    fn emit(self, _vmap: &mut HashMap<String, usize>, _stack_index: &mut usize) -> Result<String> {
+    fn emit(self, ctx: &mut Context) -> Result<Vec<Instruction>> {
+        let (rax, rcx) = (Operand::Reg(Rax), Operand::Reg(Rcx));
         Ok(match self {
-            BinaryOperator::Addition => String::from("add rax, rcx\n"),
-            BinaryOperator::Subtraction => String::from(
-                "\
-                 sub rcx, rax\n\
-                 mov rax, rcx\n\
-                 ",
-            ),
-            BinaryOperator::Multiplication => String::from("imul rax, rcx\n"),
-            BinaryOperator::Division => String::from(
-                "\
-                 mov rbx, rax\n\
-                 mov rax, rcx\n\
-                 cqo\n\
-                 idiv rbx\n\
-                 ",
-            ),
-            BinaryOperator::Modulo => String::from(
-                "\
-                 mov rbx, rax\n\
-                 mov rax, rcx\n\
-                 cqo\n\
-                 idiv rbx\n\
-                 mov rax, rdx\n\
-                 ",
-            ),
-            BinaryOperator::BitAnd => String::from(
-                "\
-                 and rcx, rax
-                 mov rax, rcx
-                 ",
-            ),
-            BinaryOperator::BitOr => String::from(
-                "\
-                 or rcx, rax
-                 mov rax, rcx
-                 ",
-            ),
-            BinaryOperator::BitXor => String::from(
-                "\
-                 xor rcx, rax
-                 mov rax, rcx
-                 ",
-            ),
-            BinaryOperator::ShiftLeft => String::from(
-                "\
-                 shl rcx, rax
-                 mov rax, rcx
-                 ",
-            ),
-            BinaryOperator::ShiftRight => String::from(
-                "\
-                 shr rcx, rax
-                 mov rax, rcx
-                 ",
-            ),
-            BinaryOperator::LessThan => String::from(
-                "\
-                 cmp rcx, rax\n\
-                 mov rax, 0\n\
-                 setl al\n\
-                 ",
-            ),
-            BinaryOperator::LessThanEqual => String::from(
-                "\
-                 cmp rcx, rax\n\
-                 mov rax, 0\n\
-                 setle al\n\
-                 ",
-            ),
-            BinaryOperator::GreaterThan => String::from(
-                "\
-                 cmp rcx, rax\n\
-                 mov rax, 0\n\
-                 setg al\n\
-                 ",
-            ),
-            BinaryOperator::GreaterThanEqual => String::from(
-                "\
-                 cmp rcx, rax\n\
-                 mov rax, 0\n\
-                 setge al\n\
-                 ",
+            BinaryOperator::Addition => vec![Instruction::Add(rax, rcx)],
+            BinaryOperator::Subtraction => vec![
+                Instruction::Sub(rcx.clone(), rax.clone()),
+                Instruction::Mov(rax, rcx),
+            ],
+            BinaryOperator::Multiplication => vec![Instruction::Imul(rax, rcx)],
+            BinaryOperator::Division | BinaryOperator::Modulo => self.divide(ctx, true),
+            BinaryOperator::BitAnd => vec![
+                Instruction::And(rcx.clone(), rax.clone()),
+                Instruction::Mov(rax, rcx),
+            ],
+            BinaryOperator::BitOr => vec![
+                Instruction::Or(rcx.clone(), rax.clone()),
+                Instruction::Mov(rax, rcx),
+            ],
+            BinaryOperator::BitXor => vec![
+                Instruction::Xor(rcx.clone(), rax.clone()),
+                Instruction::Mov(rax, rcx),
+            ],
+            // The shift count can only come from `cl`.
+            BinaryOperator::ShiftLeft => vec![
+                Instruction::Xchg(rax.clone(), rcx),
+                Instruction::Shl(rax, Operand::Byte(Rcx)),
+            ],
+            BinaryOperator::ShiftRight => vec![
+                Instruction::Xchg(rax.clone(), rcx),
+                Instruction::Sar(rax, Operand::Byte(Rcx)),
+            ],
+            BinaryOperator::LessThan => compare(Condition::Less),
+            BinaryOperator::LessThanEqual => compare(Condition::LessEqual),
+            BinaryOperator::GreaterThan => compare(Condition::Greater),
+            BinaryOperator::GreaterThanEqual => compare(Condition::GreaterEqual),
+            BinaryOperator::Equal => compare(Condition::Equal),
+            BinaryOperator::NotEqual => compare(Condition::NotEqual),
+            _ => unimplemented!(),
+        })
+    }
+}
+
+// Compare rcx with rax, leaving 1 in rax if `condition` holds and 0 if not.
+fn compare(condition: Condition) -> Vec<Instruction> {
+    vec![
+        Instruction::Cmp(Operand::Reg(Rcx), Operand::Reg(Rax)),
+        Instruction::Mov(Operand::Reg(Rax), Operand::Imm(0)),
+        Instruction::Set(condition, Operand::Byte(Rax)),
+    ]
+}
+
+// The type of arithmetic on operands of types `a` and `b`: the wider of
+// the two, or unsigned between two of the same width. A long holds every
+// unsigned int.
+fn arithmetic_type(a: Type, b: Type) -> Type {
+    match (a, b) {
+        (Type::ULong, _) | (_, Type::ULong) => Type::ULong,
+        (Type::Long, _) | (_, Type::Long) => Type::Long,
+        (Type::UInt, _) | (_, Type::UInt) => Type::UInt,
+        _ => Type::Int,
+    }
+}
+
+impl BinaryOperator {
+    // Emit `lhs op rhs`, given code leaving the left operand, of type
+    // `lhs_ty`, in rax. This covers every operator but `&&` and `||`.
+    fn apply(
+        self,
+        lhs: Vec<Instruction>,
+        lhs_ty: Type,
+        rhs: Expression,
+        ctx: &mut Context,
+    ) -> Result<Vec<Instruction>> {
+        // Arithmetic on a pointer moves in units of the pointee, and
+        // the difference of two pointers counts elements.
+        let lhs_ty = lhs_ty.decay();
+        let (scale, unscale) = match (self, lhs_ty.clone(), rhs.ty(ctx).decay()) {
+            (BinaryOperator::Subtraction, Type::Pointer(inner), Type::Pointer(_)) => (
+                Vec::new(),
+                vec![
+                    Instruction::Cqo,
+                    Instruction::Mov(Operand::Reg(Rbx), Operand::Imm(ctx.size(&inner)? as i64)),
+                    Instruction::Idiv(Operand::Reg(Rbx)),
+                ],
             ),
-            BinaryOperator::Equal => String::from(
-                "\
-                 cmp rcx, rax\n\
-                 mov rax, 0\n\
-                 sete al\n\
-                 ",
+            (BinaryOperator::Addition, Type::Pointer(inner), _)
+            | (BinaryOperator::Subtraction, Type::Pointer(inner), _) => (
+                vec![Instruction::Imul(
+                    Operand::Reg(Rax),
+                    Operand::Imm(ctx.size(&inner)? as i64),
+                )],
+                Vec::new(),
             ),
-            BinaryOperator::NotEqual => String::from(
-                "\
-                 cmp rcx, rax\n\
-                 mov rax, 0\n\
-                 setne al\n\
-                 ",
+            (BinaryOperator::Addition, _, Type::Pointer(inner)) => (
+                vec![Instruction::Imul(
+                    Operand::Reg(Rcx),
+                    Operand::Imm(ctx.size(&inner)? as i64),
+                )],
+                Vec::new(),
             ),
-            _ => unimplemented!(),
+            _ => (Vec::new(), Vec::new()),
+        };
+
+        // The type the operands are converted to, if not pointers.
+        // Only the left operand of a shift matters.
+        let ty = match (self, lhs_ty, rhs.ty(ctx).decay()) {
+            (_, Type::Pointer(_), _) | (_, _, Type::Pointer(_)) => None,
+            (BinaryOperator::ShiftLeft, ty, _) | (BinaryOperator::ShiftRight, ty, _) => {
+                Some(arithmetic_type(ctx.resolve(ty), Type::Int))
+            }
+            (_, ty1, ty2) => Some(arithmetic_type(ctx.resolve(ty1), ctx.resolve(ty2))),
+        };
+
+        let mut code = lhs;
+        code.push(Instruction::Push(Operand::Reg(Rax)));
+        ctx.depth += 8;
+        code.extend(rhs.emit(ctx)?);
+        ctx.depth -= 8;
+        code.push(Instruction::Pop(Operand::Reg(Rcx)));
+        if ty == Some(Type::UInt) {
+            // An int operand becomes unsigned by dropping its sign.
+            code.push(Instruction::Mov(Operand::Dword(Rax), Operand::Dword(Rax)));
+            code.push(Instruction::Mov(Operand::Dword(Rcx), Operand::Dword(Rcx)));
+        }
+        code.extend(scale);
+        code.extend(match ty {
+            Some(Type::UInt) => self.emit_unsigned(ctx, false)?,
+            Some(Type::ULong) => self.emit_unsigned(ctx, true)?,
+            Some(ty) if ctx.options.trap_overflow && self.can_overflow() => {
+                self.emit_checked(ty == Type::Long)
+            }
+            _ => self.emit(ctx)?,
+        });
+        code.extend(unscale);
+        Ok(code)
+    }
+
+    // Like apply, reporting the errors it would without generating code.
+    fn validate(self, lhs_ty: Type, rhs: &Expression, ctx: &Context) -> Result<()> {
+        match (self, lhs_ty.decay(), rhs.ty(ctx).decay()) {
+            (BinaryOperator::Addition, Type::Pointer(inner), _)
+            | (BinaryOperator::Subtraction, Type::Pointer(inner), _)
+            | (BinaryOperator::Addition, _, Type::Pointer(inner)) => {
+                ctx.size(&inner)?;
+            }
+            _ => {}
+        }
+        rhs.validate(ctx)
+    }
+
+    // Whether the result has the operands' arithmetic type, rather than
+    // being a truth value.
+    fn is_arithmetic(self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Addition
+                | BinaryOperator::Subtraction
+                | BinaryOperator::Multiplication
+                | BinaryOperator::Division
+                | BinaryOperator::Modulo
+                | BinaryOperator::BitAnd
+                | BinaryOperator::BitOr
+                | BinaryOperator::BitXor
+        )
+    }
+
+    // Like emit, for unsigned operands in rax and rcx. Unless they are
+    // `long`, they are zero-extended from 32 bits.
+    fn emit_unsigned(self, ctx: &mut Context, long: bool) -> Result<Vec<Instruction>> {
+        Ok(match self {
+            BinaryOperator::LessThan => compare(Condition::Below),
+            BinaryOperator::LessThanEqual => compare(Condition::BelowEqual),
+            BinaryOperator::GreaterThan => compare(Condition::Above),
+            BinaryOperator::GreaterThanEqual => compare(Condition::AboveEqual),
+            BinaryOperator::Division | BinaryOperator::Modulo => self.divide(ctx, false),
+            BinaryOperator::ShiftRight => vec![
+                Instruction::Xchg(Operand::Reg(Rax), Operand::Reg(Rcx)),
+                Instruction::Shr(Operand::Reg(Rax), Operand::Byte(Rcx)),
+            ],
+            // These can carry out of the low 32 bits, which then wrap.
+            BinaryOperator::Addition
+            | BinaryOperator::Subtraction
+            | BinaryOperator::Multiplication
+            | BinaryOperator::ShiftLeft
+                if !long =>
+            {
+                let mut code = self.emit(ctx)?;
+                code.push(Instruction::Mov(Operand::Dword(Rax), Operand::Dword(Rax)));
+                code
+            }
+            _ => self.emit(ctx)?,
         })
     }
+
+    // Divide rcx by rax, leaving the quotient or remainder in rax.
+    fn divide(self, ctx: &mut Context, signed: bool) -> Vec<Instruction> {
+        let (rax, rbx, rdx) = (Operand::Reg(Rax), Operand::Reg(Rbx), Operand::Reg(Rdx));
+        let mut code = Vec::new();
+        if ctx.options.check_division {
+            let ok = ctx.gen_label();
+            code.extend(vec![
+                Instruction::Test(rax.clone(), rax.clone()),
+                Instruction::Jcc(Condition::NotEqual, ok.clone()),
+                Instruction::Ud2,
+                Instruction::Label(ok),
+            ]);
+        }
+        code.extend(vec![
+            Instruction::Mov(rbx.clone(), rax.clone()),
+            Instruction::Mov(rax.clone(), Operand::Reg(Rcx)),
+        ]);
+        if signed {
+            code.extend(vec![Instruction::Cqo, Instruction::Idiv(rbx.clone())]);
+        } else {
+            // The upper half of the dividend is 0 rather than the sign.
+            code.extend(vec![
+                Instruction::Xor(Operand::Dword(Rdx), Operand::Dword(Rdx)),
+                Instruction::Div(rbx.clone()),
+            ]);
+        }
+        // idiv truncates toward zero. Where the remainder is nonzero and its
+        // sign differs from the divisor's, flooring takes one more off the
+        // quotient and moves the remainder across by the divisor.
+        if signed && ctx.options.floor_division {
+            let done = ctx.gen_label();
+            code.extend(vec![
+                Instruction::Test(rdx.clone(), rdx.clone()),
+                Instruction::Jcc(Condition::Equal, done.clone()),
+                Instruction::Mov(Operand::Reg(Rcx), rdx.clone()),
+                Instruction::Xor(Operand::Reg(Rcx), rbx.clone()),
+                Instruction::Jcc(Condition::NotSign, done.clone()),
+                Instruction::Sub(rax.clone(), Operand::Imm(1)),
+                Instruction::Add(rdx.clone(), rbx),
+                Instruction::Label(done),
+            ]);
+        }
+        if let BinaryOperator::Modulo = self {
+            code.push(Instruction::Mov(rax, rdx));
+        }
+        code
+    }
+
+    fn can_overflow(self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Addition | BinaryOperator::Subtraction | BinaryOperator::Multiplication
+        )
+    }
+
+    // Like emit, but jump to the overflow handler if the result doesn't fit
+    // in a long, or in an int unless `long`. An int result is computed in
+    // the low halves, so that the flags reflect 32-bit overflow, and then
+    // sign-extended back into rax.
+    fn emit_checked(self, long: bool) -> Vec<Instruction> {
+        let reg = |r| {
+            if long {
+                Operand::Reg(r)
+            } else {
+                Operand::Dword(r)
+            }
+        };
+        // Subtraction runs the other way round: rcx holds the left operand.
+        let (arithmetic, result) = match self {
+            BinaryOperator::Addition => (Instruction::Add(reg(Rax), reg(Rcx)), None),
+            BinaryOperator::Subtraction => (
+                Instruction::Sub(reg(Rcx), reg(Rax)),
+                Some(Instruction::Mov(Operand::Reg(Rax), Operand::Reg(Rcx))),
+            ),
+            BinaryOperator::Multiplication => (Instruction::Imul(reg(Rax), reg(Rcx)), None),
+            _ => unreachable!(),
+        };
+        let mut code = vec![
+            arithmetic,
+            Instruction::Jcc(Condition::Overflow, String::from(OVERFLOW_HANDLER)),
+        ];
+        code.extend(result);
+        if !long {
+            code.push(Instruction::Movsxd(Operand::Reg(Rax), Operand::Dword(Rax)));
+        }
+        code
+    }
+}
+
+// Guess which unary operator was meant when a binary-only operator shows up
+// where an operand should be (e.g. `return != x;` or `return ^x;`).
+fn unary_suggestion(tok: &Token) -> Option<Token> {
+    match tok {
+        Token::Addition
+        | Token::Division
+        | Token::Modulo
+        | Token::ShiftLeft
+        | Token::ShiftRight
+        | Token::AssignSub
+        | Token::AssignAdd => Some(Token::Negative),
+        Token::And | Token::Or | Token::Equal | Token::NotEqual => Some(Token::Negation),
+        Token::BitOr | Token::BitXor | Token::AssignXor => Some(Token::Complement),
+        _ => None,
+    }
+}
+
+// Whether a type name may start with this token.
+fn is_type_keyword(tok: &Token) -> bool {
+    is_specifier(tok) || *tok == Token::Keyword(Keyword::Struct)
+}
+
+// Whether this token is one of the type specifiers, or `const`, that make
+// up a builtin type.
+fn is_specifier(tok: &Token) -> bool {
+    matches!(
+        tok,
+        Token::Keyword(Keyword::Int)
+            | Token::Keyword(Keyword::Char)
+            | Token::Keyword(Keyword::Long)
+            | Token::Keyword(Keyword::Unsigned)
+            | Token::Keyword(Keyword::Signed)
+            | Token::Keyword(Keyword::Const)
+    )
+}
+
+// Parse a run of type specifiers and `const`, starting with `first`, into
+// the builtin type they name together and whether it is `const`. They may
+// come in any order, as in `long unsigned int`.
+fn parse_specifiers<I: Iterator<Item = Token>>(
+    t: &mut PutBackN<I>,
+    first: Token,
+) -> Result<(Type, bool)> {
+    let mut specifiers = Vec::new();
+    let mut constant = false;
+    let mut next = Some(first);
+    while let Some(tok) = next {
+        match tok {
+            Token::Keyword(Keyword::Const) => constant = true,
+            Token::Keyword(k) if is_specifier(&tok) => specifiers.push(k),
+            tok => {
+                t.put_back(tok);
+                break;
+            }
+        }
+        next = t.next();
+    }
+
+    let count = |k: Keyword| specifiers.iter().filter(|&s| *s == k).count();
+    let (int, char, long) = (
+        count(Keyword::Int),
+        count(Keyword::Char),
+        count(Keyword::Long),
+    );
+    let (unsigned, signed) = (count(Keyword::Unsigned), count(Keyword::Signed));
+    if specifiers.is_empty() {
+        return Err(Error::UnexpectedToken {
+            wanted: "Type",
+            expected: vec![Token::Keyword(Keyword::Int)],
+            found: t.next().unwrap_or(Token::Semicolon),
+            tokens: t.collect(),
+            span: None,
+        });
+    }
+    if int > 1 || char > 1 || long > 2 || unsigned + signed > 1 || (char > 0 && int + long > 0) {
+        return Err(Error::ConflictingSpecifiers {
+            specifiers: specifiers
+                .iter()
+                .map(|k| format!("{:?}", k).to_lowercase())
+                .join(" "),
+        });
+    }
+    let ty = match (char > 0, long > 0, unsigned > 0) {
+        (true, _, true) => {
+            return Err(Error::Unsupported {
+                feature: "unsigned char",
+                hint: "Use char or unsigned int instead.",
+            })
+        }
+        (true, _, false) => Type::Char,
+        (false, true, true) => Type::ULong,
+        (false, true, false) => Type::Long,
+        (false, false, true) => Type::UInt,
+        (false, false, false) => Type::Int,
+    };
+    Ok((ty, constant))
+}
+
+// Parse a type name in parentheses, as in a cast or `sizeof(int *)`, given
+// its first keyword. The opening parenthesis has already been read.
+fn parse_type_name<I: Iterator<Item = Token>>(t: &mut PutBackN<I>, first: Token) -> Result<Type> {
+    let mut ty = match first {
+        tok if is_specifier(&tok) => parse_specifiers(t, tok)?.0,
+        _ => match t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "Type name",
+            span: None,
+        })? {
+            Token::Identifier(name) => Type::Struct(name),
+            tok => {
+                return Err(Error::UnexpectedToken {
+                    wanted: "Struct name",
+                    expected: vec![Token::Identifier(String::from("_"))],
+                    found: tok,
+                    tokens: t.collect(),
+                    span: None,
+                })
+            }
+        },
+    };
+    loop {
+        match t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "Type name",
+            span: None,
+        })? {
+            Token::Multiplication => ty = Type::Pointer(Box::new(ty)),
+            Token::CloseParenthesis => break,
+            tok => {
+                return Err(Error::UnexpectedToken {
+                    wanted: "Type name",
+                    expected: vec![Token::Multiplication, Token::CloseParenthesis],
+                    found: tok,
+                    tokens: t.collect(),
+                    span: None,
+                })
+            }
+        }
+    }
+    Ok(ty)
+}
+
+// Parse the declarator following a declaration's base type `ty`: any number
+// of `*`, then the name, possibly grouped in parentheses as in `int (*p)`.
+fn parse_declarator<I: Iterator<Item = Token>>(
+    t: &mut PutBackN<I>,
+    mut ty: Type,
+) -> Result<(Type, String)> {
+    loop {
+        match t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "Declarator",
+            span: None,
+        })? {
+            Token::Multiplication => ty = Type::Pointer(Box::new(ty)),
+            Token::Identifier(name) => return Ok((ty, name)),
+            Token::OpenParenthesis => {
+                let declared = parse_declarator(t, ty)?;
+                consume_token(t, Token::CloseParenthesis)?;
+                match t.next() {
+                    Some(Token::OpenParenthesis) => {
+                        return Err(Error::Unsupported {
+                            feature: "function pointers",
+                            hint: "Call the function by name instead.",
+                        })
+                    }
+                    Some(tok) => t.put_back(tok),
+                    None => {}
+                }
+                return Ok(declared);
+            }
+            tok => {
+                return Err(Error::UnexpectedToken {
+                    wanted: "Declarator",
+                    expected: vec![
+                        Token::Identifier(String::from("_")),
+                        Token::Multiplication,
+                        Token::OpenParenthesis,
+                    ],
+                    found: tok,
+                    tokens: t.collect(),
+                    span: None,
+                })
+            }
+        }
+    }
+}
+
+// Parse the dimensions following an array's name, as in `a[2][3]`. The
+// outermost one may be left out, to be taken from the initializer.
+fn parse_dimensions<I: Iterator<Item = Token>>(
+    t: &mut PutBackN<I>,
+    name: &str,
+) -> Result<Vec<Option<usize>>> {
+    let mut dims = Vec::new();
+    loop {
+        match t.next() {
+            Some(Token::OpenBracket) => {}
+            Some(tok) => {
+                t.put_back(tok);
+                return Ok(dims);
+            }
+            None => return Ok(dims),
+        }
+        match t.next() {
+            Some(Token::CloseBracket) if dims.is_empty() => {
+                dims.push(None);
+                continue;
+            }
+            Some(tok) => t.put_back(tok),
+            None => {}
+        }
+        match Constant::parse(t)?.value() {
+            n if n > 0 => dims.push(Some(n as usize)),
+            _ => {
+                return Err(Error::InvalidArraySize {
+                    var: name.to_string(),
+                })
+            }
+        }
+        consume_token(t, Token::CloseBracket)?;
+    }
+}
+
+// Parse a brace-enclosed initializer list, which may end with a comma.
+fn parse_initializer<I: Iterator<Item = Token>>(t: &mut PutBackN<I>) -> Result<Vec<Expression>> {
+    consume_token(t, Token::OpenBrace)?;
+    let mut items = Vec::new();
+    loop {
+        match t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "Initializer",
+            span: None,
+        })? {
+            Token::CloseBrace => return Ok(items),
+            tok => t.put_back(tok),
+        }
+        items.push(Expression::parse_bounded(t, 1)?);
+        match t.next().ok_or(Error::UnexpectedEnd {
+            wanted: "Initializer",
+            span: None,
+        })? {
+            Token::Comma => {}
+            Token::CloseBrace => return Ok(items),
+            tok => {
+                return Err(Error::UnexpectedToken {
+                    wanted: "Initializer",
+                    expected: vec![Token::Comma, Token::CloseBrace],
+                    found: tok,
+                    tokens: t.collect(),
+                    span: None,
+                })
+            }
+        }
+    }
+}
+
+// An array declaration, of the element type `ty` with the given dimensions.
+fn parse_array_declaration<I: Iterator<Item = Token>>(
+    t: &mut PutBackN<I>,
+    mut ty: Type,
+    name: String,
+    dims: Vec<Option<usize>>,
+) -> Result<Statement> {
+    let init = match t.next() {
+        Some(Token::Assign) => Some(parse_initializer(t)?),
+        Some(tok) => {
+            t.put_back(tok);
+            None
+        }
+        None => None,
+    };
+    consume_token(t, Token::Semicolon)?;
+
+    for (i, dim) in dims.into_iter().enumerate().rev() {
+        let n = match (dim, &init) {
+            (Some(n), _) => n,
+            (None, Some(items)) if i == 0 => items.len(),
+            (None, _) => return Err(Error::IncompleteArrayType { var: name }),
+        };
+        ty = Type::Array(Box::new(ty), n);
+    }
+    Ok(Statement::Declaration(
+        ty,
+        name,
+        init.map(Expression::Initializer),
+        Qualifiers::default(),
+    ))
+}
+
+fn parse_declaration<I: Iterator<Item = Token>>(
+    t: &mut PutBackN<I>,
+    ty: Type,
+    name: String,
+) -> Result<Statement> {
+    let dims = parse_dimensions(t, &name)?;
+    if !dims.is_empty() {
+        return parse_array_declaration(t, ty, name, dims);
+    }
+    match t.next().ok_or(Error::UnexpectedEnd {
+        wanted: "Identifier",
+        span: None,
+    })? {
+        Token::Semicolon => Ok(Statement::Declaration(
+            ty,
+            name,
+            None,
+            Qualifiers::default(),
+        )),
+        Token::Assign => {
+            t.put_back(Token::Assign);
+            t.put_back(Token::Identifier(name.clone()));
+            let ret = Ok(Statement::Declaration(
+                ty,
+                name,
+                Some(Expression::parse_bounded(t, 1)?),
+                Qualifiers::default(),
+            ));
+            consume_token(t, Token::Semicolon)?;
+            ret
+        }
+        tok => Err(Error::UnexpectedToken {
+            wanted: "Statement part",
+            expected: vec![Token::Semicolon, Token::Assign],
+            found: tok,
+            tokens: t.collect(),
+            span: None,
+        }),
+    }
 }
 
 fn consume_token<I: Iterator<Item = Token>>(t: &mut I, tok: Token) -> Result<()> {
-    let next = t.next().unwrap();
+    let next = t.next().ok_or(Error::UnexpectedEnd {
+        wanted: "Token",
+        span: None,
+    })?;
     if next != tok {
         Err(Error::UnexpectedToken {
             wanted: "",
             expected: vec![tok],
             found: next,
             tokens: t.collect(),
+            span: None,
         })
     } else {
         Ok(())
@@ -805,5 +6056,52 @@ fn consume_token<I: Iterator<Item = Token>>(t: &mut I, tok: Token) -> Result<()>
 }
 
 pub fn parse(t: Vec<Token>) -> Result<Program> {
-    Program::parse(&mut put_back_n(t.into_iter()))
+    Program::parse(&mut put_back_n(t))
+}
+
+/// Like `parse`, but takes the position of each token, as from
+/// `lex_spanned`, so that parse errors say where they happened.
+pub fn parse_spanned(t: Vec<(Token, Span)>) -> Result<Program> {
+    let (tokens, spans): (Vec<_>, Vec<_>) = t.into_iter().unzip();
+    parse(tokens).map_err(|e| e.locate(&spans))
+}
+
+/// Compile a lone expression, leaving its value in `rax`. `vars` maps
+/// variable names to their offsets below the frame base, as in a function
+/// body.
+pub fn compile_expression(t: Vec<Token>, vars: &HashMap<String, usize>) -> Result<String> {
+    let mut t = put_back_n(t);
+    let e = Expression::parse(&mut t)?;
+    if let Some(tok) = t.next() {
+        return Err(Error::UnexpectedToken {
+            wanted: "end of expression",
+            expected: vec![],
+            found: tok,
+            tokens: t.collect(),
+            span: None,
+        });
+    }
+
+    let mut ctx = Context {
+        vmap: vars.clone(),
+        stack_index: vars.values().max().map_or(8, |&i| i + 8),
+        ..Default::default()
+    };
+    Ok(to_nasm(&e.emit(&mut ctx)?))
+}
+
+/// Report whether `tokens` form a program that compiles, returning the
+/// first error. The tokens are cloned as they are parsed, so the caller
+/// keeps them. The program is walked as it would be emitted, to find the
+/// same semantic errors, but no code is generated.
+pub fn can_compile(tokens: &[Token]) -> Result<()> {
+    let program = Program::parse(&mut put_back_n(tokens.iter().cloned()))?;
+    program.check(&Options::default())?;
+    program.validate(&mut Context::new())
+}
+
+/// Compile C source to tidied assembly with the default options.
+pub fn compile_str(src: &str) -> Result<String> {
+    let program = parse_spanned(lex_spanned(src))?;
+    Ok(to_nasm(&program.emit(&mut Context::new())?))
 }
@@ -0,0 +1,97 @@
+use std::fmt;
+
+use super::lex::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Information that needs no action, which never fails the compile.
+    Note,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Note => write!(f, "note"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A non-fatal finding from a lint or semantic check. Diagnostics with
+/// `Severity::Error` fail the compile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn warning(code: &'static str, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            code,
+            message,
+            span: None,
+        }
+    }
+
+    pub fn note(code: &'static str, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Note,
+            code,
+            message,
+            span: None,
+        }
+    }
+
+    pub fn with_span(self, span: Option<Span>) -> Diagnostic {
+        Diagnostic { span, ..self }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity, self.code, self.message)?;
+        if let Some(span) = self.span {
+            write!(f, " (at {}:{})", span.line, span.col)?;
+        }
+        Ok(())
+    }
+}
+
+/// The line of `src` that `span` points into, followed by a caret under the
+/// column it names. Tabs are expanded to stops every `tab_width` columns, so
+/// the caret lines up however the line is indented.
+pub fn snippet(src: &str, span: Span, tab_width: usize) -> String {
+    let line = src.lines().nth(span.line.saturating_sub(1)).unwrap_or("");
+    let tab_width = tab_width.max(1);
+    let mut text = String::new();
+    let mut width = 0;
+    let mut caret = None;
+    for (i, c) in line.chars().enumerate() {
+        if i + 1 == span.col {
+            caret = Some(width);
+        }
+        if c == '\t' {
+            let stop = tab_width - width % tab_width;
+            text.push_str(&" ".repeat(stop));
+            width += stop;
+        } else {
+            text.push(c);
+            width += 1;
+        }
+    }
+    let gutter = span.line.to_string();
+    format!(
+        "{} | {}\n{} | {}^",
+        gutter,
+        text,
+        " ".repeat(gutter.len()),
+        " ".repeat(caret.unwrap_or(width))
+    )
+}
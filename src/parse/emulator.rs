@@ -0,0 +1,506 @@
+//! An interpreter for emitted code, so that tests can check what a program
+//! computes without assembling and running it. Only what the code generator
+//! emits is modelled: the general purpose registers, the four flags it tests,
+//! a flat memory holding the stack and the data sections, and the NASM data
+//! directives that `SectionBuilder` writes.
+
+use std::collections::HashMap;
+
+use super::asm::{Condition, Instruction, Operand, Register};
+
+const MEMORY: usize = 1 << 20;
+// Accesses below this address fault, so that null dereferences are caught.
+const DATA: u64 = 0x1000;
+// Code is addressed separately from memory, as `CODE + index`.
+const CODE: u64 = 1 << 32;
+// The return address `call` starts from, which ends the run when returned to.
+const EXIT: u64 = 1 << 40;
+const MAX_STEPS: usize = 1_000_000;
+
+/// Why a run stopped before returning.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Fault {
+    /// A `ud2` was executed, with the label it follows.
+    Trap(String),
+    /// A call to a function the code doesn't define.
+    Undefined(String),
+    /// A load or store outside of memory.
+    Segfault(u64),
+    /// Division by zero, or a quotient too large for its register.
+    Divide,
+    /// The code ran for too long, and probably never returns.
+    Timeout,
+}
+
+#[derive(Default)]
+struct Flags {
+    zero: bool,
+    sign: bool,
+    carry: bool,
+    overflow: bool,
+}
+
+struct Machine<'a> {
+    code: &'a [Instruction],
+    labels: HashMap<&'a str, u64>,
+    memory: Vec<u8>,
+    registers: [u64; 16],
+    flags: Flags,
+}
+
+fn index(reg: Register) -> usize {
+    reg as usize
+}
+
+// The width in bits of an operand, or None if it takes the width of the
+// other operand.
+fn width(op: &Operand) -> Option<u32> {
+    match op {
+        Operand::Dword(_) => Some(32),
+        Operand::Byte(_) => Some(8),
+        Operand::Imm(_) | Operand::Symbol(_) => None,
+        Operand::Mem { width, .. } | Operand::Rel(_, width) => Some(width.bytes() as u32 * 8),
+        Operand::Reg(_) => Some(64),
+    }
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits == 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+impl<'a> Machine<'a> {
+    fn new(code: &'a [Instruction]) -> Machine<'a> {
+        let mut machine = Machine {
+            code,
+            labels: HashMap::new(),
+            memory: vec![0; MEMORY],
+            registers: [0; 16],
+            flags: Flags::default(),
+        };
+
+        // Lay out the data sections, then fill them in once every label is
+        // known, since a jump table refers to code labels.
+        let mut section = ".text";
+        let mut next = DATA;
+        let mut objects = Vec::new();
+        for (i, instruction) in code.iter().enumerate() {
+            match instruction {
+                Instruction::Label(name) if section == ".text" => {
+                    machine.labels.insert(name, CODE + i as u64);
+                }
+                Instruction::Label(name) => {
+                    machine.labels.insert(name, next);
+                }
+                Instruction::Directive(d) => {
+                    let mut words = d.split_whitespace();
+                    let (directive, rest) = match words.next() {
+                        Some(directive) => (directive, d[directive.len()..].trim()),
+                        None => continue,
+                    };
+                    let width = |suffix: &str| match suffix {
+                        "b" => 1,
+                        "w" => 2,
+                        "d" => 4,
+                        _ => 8,
+                    };
+                    match directive {
+                        "section" => {
+                            section = words.next().unwrap_or(".text");
+                            if let Some(align) =
+                                words.find_map(|w| w.strip_prefix("align=")?.parse::<u64>().ok())
+                            {
+                                next = next.div_ceil(align) * align;
+                            }
+                        }
+                        "times" => {
+                            next += words
+                                .next()
+                                .and_then(|n| n.parse::<u64>().ok())
+                                .unwrap_or(0)
+                        }
+                        "resb" | "resw" | "resd" | "resq" => {
+                            let count = rest.parse::<u64>().unwrap_or(0);
+                            next += count * width(&directive[3..]);
+                        }
+                        "db" | "dw" | "dd" | "dq" => {
+                            let width = width(&directive[1..]);
+                            for value in rest.split(',') {
+                                objects.push((next, width, value.trim()));
+                                next += width;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (address, width, value) in objects {
+            let value = match value.parse::<i64>() {
+                Ok(v) => v as u64,
+                Err(_) => machine.labels[value],
+            };
+            let at = address as usize;
+            machine.memory[at..at + width as usize]
+                .copy_from_slice(&value.to_le_bytes()[..width as usize]);
+        }
+        machine
+    }
+
+    fn address(&self, op: &Operand) -> u64 {
+        match op {
+            Operand::Mem {
+                base,
+                index: i,
+                offset,
+                ..
+            } => {
+                let scaled = i.map_or(0, |(reg, scale)| {
+                    self.registers[index(reg)].wrapping_mul(u64::from(scale))
+                });
+                self.registers[index(*base)]
+                    .wrapping_add(scaled)
+                    .wrapping_add(*offset as u64)
+            }
+            Operand::Rel(name, _) | Operand::Symbol(name) => self.labels[name.as_str()],
+            op => panic!("{} has no address", op),
+        }
+    }
+
+    fn load(&self, address: u64, bytes: usize) -> Result<u64, Fault> {
+        if address < DATA || address as usize + bytes > MEMORY {
+            return Err(Fault::Segfault(address));
+        }
+        let mut value = [0; 8];
+        let at = address as usize;
+        value[..bytes].copy_from_slice(&self.memory[at..at + bytes]);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn store(&mut self, address: u64, bytes: usize, value: u64) -> Result<(), Fault> {
+        if address < DATA || address as usize + bytes > MEMORY {
+            return Err(Fault::Segfault(address));
+        }
+        let at = address as usize;
+        self.memory[at..at + bytes].copy_from_slice(&value.to_le_bytes()[..bytes]);
+        Ok(())
+    }
+
+    // The value of an operand, zero-extended from its width. An immediate
+    // is sign-extended to `bits`.
+    fn read(&self, op: &Operand, bits: u32) -> Result<u64, Fault> {
+        Ok(match op {
+            Operand::Reg(r) => self.registers[index(*r)],
+            Operand::Dword(r) => self.registers[index(*r)] & mask(32),
+            Operand::Byte(r) => self.registers[index(*r)] & mask(8),
+            Operand::Imm(i) => *i as u64 & mask(bits),
+            Operand::Mem { width, .. } | Operand::Rel(_, width) => {
+                self.load(self.address(op), width.bytes())?
+            }
+            Operand::Symbol(name) => self.labels[name.as_str()],
+        })
+    }
+
+    // Write a value to an operand. As on x86-64, writing a dword register
+    // clears the upper half, and writing a byte register keeps the rest.
+    fn write(&mut self, op: &Operand, value: u64) -> Result<(), Fault> {
+        match op {
+            Operand::Reg(r) => self.registers[index(*r)] = value,
+            Operand::Dword(r) => self.registers[index(*r)] = value & mask(32),
+            Operand::Byte(r) => {
+                let reg = &mut self.registers[index(*r)];
+                *reg = (*reg & !mask(8)) | (value & mask(8));
+            }
+            Operand::Mem { width, .. } | Operand::Rel(_, width) => {
+                self.store(self.address(op), width.bytes(), value)?
+            }
+            op => panic!("Can't write to {}", op),
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, value: u64) -> Result<(), Fault> {
+        let rsp = self.registers[index(Register::Rsp)] - 8;
+        self.registers[index(Register::Rsp)] = rsp;
+        self.store(rsp, 8, value)
+    }
+
+    fn pop(&mut self) -> Result<u64, Fault> {
+        let rsp = self.registers[index(Register::Rsp)];
+        self.registers[index(Register::Rsp)] = rsp + 8;
+        self.load(rsp, 8)
+    }
+
+    fn set_result(&mut self, value: u64, bits: u32) {
+        self.flags.zero = value & mask(bits) == 0;
+        self.flags.sign = value >> (bits - 1) & 1 == 1;
+    }
+
+    fn holds(&self, c: Condition) -> bool {
+        let f = &self.flags;
+        match c {
+            Condition::Equal => f.zero,
+            Condition::NotEqual => !f.zero,
+            Condition::Less => f.sign != f.overflow,
+            Condition::LessEqual => f.zero || f.sign != f.overflow,
+            Condition::Greater => !f.zero && f.sign == f.overflow,
+            Condition::GreaterEqual => f.sign == f.overflow,
+            Condition::Below => f.carry,
+            Condition::BelowEqual => f.carry || f.zero,
+            Condition::Above => !f.carry && !f.zero,
+            Condition::AboveEqual => !f.carry,
+            Condition::Overflow => f.overflow,
+            Condition::NotSign => !f.sign,
+        }
+    }
+
+    // Apply a two-operand arithmetic or logic instruction, setting the flags
+    // as x86-64 does. Subtraction is also how `cmp` compares.
+    fn arithmetic(&mut self, i: &Instruction, a: &Operand, b: &Operand) -> Result<(), Fault> {
+        let bits = width(a).or_else(|| width(b)).unwrap_or(64);
+        let (x, y) = (self.read(a, bits)?, self.read(b, bits)?);
+        let top = 1 << (bits - 1);
+        let (result, carry, overflow) = match i {
+            Instruction::Add(_, _) => {
+                let r = x.wrapping_add(y) & mask(bits);
+                let carry = (u128::from(x) + u128::from(y)) > u128::from(mask(bits));
+                (r, carry, (x & top == y & top) && (r & top != x & top))
+            }
+            Instruction::Sub(_, _) | Instruction::Cmp(_, _) => {
+                let r = x.wrapping_sub(y) & mask(bits);
+                (r, x < y, (x & top != y & top) && (r & top != x & top))
+            }
+            Instruction::Imul(_, _) => {
+                let full = i128::from(sign_extend(x, bits)) * i128::from(sign_extend(y, bits));
+                let r = full as u64 & mask(bits);
+                let lost = i128::from(sign_extend(r, bits)) != full;
+                (r, lost, lost)
+            }
+            Instruction::And(_, _) | Instruction::Test(_, _) => (x & y, false, false),
+            Instruction::Or(_, _) => (x | y, false, false),
+            Instruction::Xor(_, _) => (x ^ y, false, false),
+            _ => unreachable!(),
+        };
+        self.flags.carry = carry;
+        self.flags.overflow = overflow;
+        self.set_result(result, bits);
+        if !matches!(i, Instruction::Cmp(_, _) | Instruction::Test(_, _)) {
+            self.write(a, result)?;
+        }
+        Ok(())
+    }
+
+    fn shift(&mut self, i: &Instruction, a: &Operand, b: &Operand) -> Result<(), Fault> {
+        let bits = width(a).unwrap_or(64);
+        let count = (self.read(b, 8)? & u64::from(bits - 1)) as u32;
+        if count == 0 {
+            return Ok(());
+        }
+        let x = self.read(a, bits)?;
+        let (result, carry) = match i {
+            Instruction::Shl(_, _) => (x << count, x >> (bits - count) & 1 == 1),
+            Instruction::Shr(_, _) => (x >> count, x >> (count - 1) & 1 == 1),
+            _ => (
+                (sign_extend(x, bits) >> count) as u64,
+                x >> (count - 1) & 1 == 1,
+            ),
+        };
+        let result = result & mask(bits);
+        self.flags.carry = carry;
+        self.flags.overflow = false;
+        self.set_result(result, bits);
+        self.write(a, result)
+    }
+
+    fn divide(&mut self, divisor: &Operand, signed: bool) -> Result<(), Fault> {
+        let (rax, rdx) = (index(Register::Rax), index(Register::Rdx));
+        let divisor = self.read(divisor, 64)?;
+        if divisor == 0 {
+            return Err(Fault::Divide);
+        }
+        let dividend = (u128::from(self.registers[rdx]) << 64) | u128::from(self.registers[rax]);
+        let (quotient, remainder) = if signed {
+            let (n, d) = (dividend as i128, i128::from(divisor as i64));
+            let q = n / d;
+            if q != i128::from(q as i64) {
+                return Err(Fault::Divide);
+            }
+            (q as u64, (n % d) as u64)
+        } else {
+            let d = u128::from(divisor);
+            let q = dividend / d;
+            if q > u128::from(u64::MAX) {
+                return Err(Fault::Divide);
+            }
+            (q as u64, (dividend % d) as u64)
+        };
+        self.registers[rax] = quotient;
+        self.registers[rdx] = remainder;
+        Ok(())
+    }
+
+    fn jump(&self, target: u64) -> usize {
+        (target - CODE) as usize
+    }
+
+    // The label nearest before `pc`, to say where a trap happened.
+    fn location(&self, pc: usize) -> String {
+        self.code[..pc]
+            .iter()
+            .rev()
+            .find_map(|i| match i {
+                Instruction::Label(name) => Some(name.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    fn call(&mut self, function: &str, args: &[i64]) -> Result<i64, Fault> {
+        let registers = [
+            Register::Rdi,
+            Register::Rsi,
+            Register::Rdx,
+            Register::Rcx,
+            Register::R8,
+            Register::R9,
+        ];
+        for (&reg, &arg) in registers.iter().zip(args) {
+            self.registers[index(reg)] = arg as u64;
+        }
+        self.registers[index(Register::Rsp)] = MEMORY as u64;
+        self.push(EXIT)?;
+        let mut pc = match self.labels.get(function) {
+            Some(&entry) => self.jump(entry),
+            None => return Err(Fault::Undefined(function.to_string())),
+        };
+
+        for _ in 0..MAX_STEPS {
+            let instruction = match self.code.get(pc) {
+                Some(i) => i,
+                None => return Err(Fault::Segfault(CODE + pc as u64)),
+            };
+            pc += 1;
+            match instruction {
+                Instruction::Label(_) | Instruction::Directive(_) => {}
+                Instruction::Mov(a, b) => {
+                    let value = self.read(b, width(a).unwrap_or(64))?;
+                    self.write(a, value)?;
+                }
+                Instruction::Movsxd(a, b) => {
+                    let value = sign_extend(self.read(b, 32)? & mask(32), 32);
+                    self.write(a, value as u64)?;
+                }
+                Instruction::Movsx(a, b) => {
+                    let value = sign_extend(self.read(b, 8)? & mask(8), 8);
+                    self.write(a, value as u64)?;
+                }
+                Instruction::Lea(a, b) => {
+                    let address = self.address(b);
+                    self.write(a, address)?;
+                }
+                Instruction::Xchg(a, b) => {
+                    let (x, y) = (self.read(a, 64)?, self.read(b, 64)?);
+                    self.write(a, y)?;
+                    self.write(b, x)?;
+                }
+                Instruction::Push(a) => {
+                    let value = self.read(a, 64)?;
+                    self.push(value)?;
+                }
+                Instruction::Pop(a) => {
+                    let value = self.pop()?;
+                    self.write(a, value)?;
+                }
+                i @ Instruction::Add(a, b)
+                | i @ Instruction::Sub(a, b)
+                | i @ Instruction::Imul(a, b)
+                | i @ Instruction::And(a, b)
+                | i @ Instruction::Or(a, b)
+                | i @ Instruction::Xor(a, b)
+                | i @ Instruction::Cmp(a, b)
+                | i @ Instruction::Test(a, b) => self.arithmetic(i, a, b)?,
+                i @ Instruction::Shl(a, b)
+                | i @ Instruction::Sar(a, b)
+                | i @ Instruction::Shr(a, b) => self.shift(i, a, b)?,
+                Instruction::Idiv(a) => self.divide(a, true)?,
+                Instruction::Div(a) => self.divide(a, false)?,
+                Instruction::Cqo => {
+                    let rax = self.registers[index(Register::Rax)] as i64;
+                    self.registers[index(Register::Rdx)] = (rax >> 63) as u64;
+                }
+                Instruction::Neg(a) => {
+                    let bits = width(a).unwrap_or(64);
+                    let x = self.read(a, bits)?;
+                    let result = x.wrapping_neg() & mask(bits);
+                    self.flags.carry = x != 0;
+                    self.flags.overflow = x == 1 << (bits - 1);
+                    self.set_result(result, bits);
+                    self.write(a, result)?;
+                }
+                Instruction::Not(a) => {
+                    let bits = width(a).unwrap_or(64);
+                    let x = self.read(a, bits)?;
+                    self.write(a, !x & mask(bits))?;
+                }
+                Instruction::Inc(a) => {
+                    let bits = width(a).unwrap_or(64);
+                    let x = self.read(a, bits)?;
+                    let result = x.wrapping_add(1) & mask(bits);
+                    self.flags.overflow = result == 1 << (bits - 1);
+                    self.set_result(result, bits);
+                    self.write(a, result)?;
+                }
+                Instruction::Set(c, a) => {
+                    let value = self.holds(*c) as u64;
+                    self.write(a, value)?;
+                }
+                Instruction::Cmov(c, a, b) => {
+                    if self.holds(*c) {
+                        let value = self.read(b, 64)?;
+                        self.write(a, value)?;
+                    }
+                }
+                Instruction::Jmp(a) => pc = self.jump(self.read(a, 64)?),
+                Instruction::Jcc(c, label) => {
+                    if self.holds(*c) {
+                        pc = self.jump(self.labels[label.as_str()]);
+                    }
+                }
+                Instruction::Call(name) => {
+                    let target = match self.labels.get(name.as_str()) {
+                        Some(&target) => target,
+                        None => return Err(Fault::Undefined(name.clone())),
+                    };
+                    self.push(CODE + pc as u64)?;
+                    pc = self.jump(target);
+                }
+                Instruction::Ret => match self.pop()? {
+                    EXIT => return Ok(self.registers[index(Register::Rax)] as i64),
+                    to => pc = self.jump(to),
+                },
+                Instruction::Ud2 => return Err(Fault::Trap(self.location(pc - 1))),
+            }
+        }
+        Err(Fault::Timeout)
+    }
+}
+
+/// Call `function` in `code`, as emitted for a whole program, with the
+/// given integer arguments, and return what it leaves in rax.
+pub(crate) fn call(code: &[Instruction], function: &str, args: &[i64]) -> Result<i64, Fault> {
+    Machine::new(code).call(function, args)
+}
+
+/// Run `main` in `code` with no arguments.
+pub(crate) fn run(code: &[Instruction]) -> Result<i64, Fault> {
+    call(code, "main", &[])
+}
@@ -1,5 +1,9 @@
 use itertools::put_back;
 use itertools::Itertools;
+
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::fmt;
 //use snafu::Snafu;
 
 /*#[derive(Debug, Snafu)]
@@ -10,19 +14,42 @@ enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;*/
 
-#[derive(Debug, PartialEq)]
+/// A position in the source text, both 1-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     OpenBrace,
     CloseBrace,
     OpenParenthesis,
     CloseParenthesis,
+    OpenBracket,
+    CloseBracket,
     Semicolon,
+    Comma,
+    Dot,
+    Arrow,
+    Ellipsis,
+    QuestionMark,
+    Colon,
     Keyword(Keyword),
     Identifier(String),
     Literal(Literal),
     Negative,
     Complement,
     Negation,
+    Increment,
+    Decrement,
     Unidentified,
     Addition,
     Multiplication,
@@ -54,131 +81,293 @@ pub enum Token {
     AssignShiftRight,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Keyword {
     Int,
+    Char,
+    Long,
+    Unsigned,
+    Signed,
+    Const,
+    Void,
     Return,
+    Struct,
+    Sizeof,
+    Typedef,
+    If,
+    Else,
+    While,
+    For,
+    Break,
+    Continue,
+    Goto,
+    Register,
+    Volatile,
+    Switch,
+    Case,
+    Default,
+    Static,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     Int(u32),
+    /// Suffixed with `u`.
+    UInt(u32),
+    /// Suffixed with `l` or `ll`.
+    Long(u64),
+    /// Suffixed with both `u` and `l` or `ll`.
+    ULong(u64),
+    /// A character constant such as `'A'` or `'\n'`.
+    Char(u8),
+    /// A string literal's bytes, without the terminating nul.
+    String(Vec<u8>),
+    /// A number that isn't valid in its base, or too big for any type.
+    Invalid(String),
+    /// A number with a suffix other than `u`, `l`, `ll` or a combination.
+    InvalidSuffix(String),
     // For error purposes
     None,
 }
 
+// The byte for the escape sequence whose backslash has just been read.
+fn escape(c: Option<char>) -> Option<u8> {
+    match c? {
+        'n' => Some(b'\n'),
+        't' => Some(b'\t'),
+        '\\' => Some(b'\\'),
+        '\'' => Some(b'\''),
+        '"' => Some(b'"'),
+        '0' => Some(0),
+        _ => None,
+    }
+}
+
+// The value of an integer literal, typed by its suffix and then by the
+// smallest type that holds it, as in C. A decimal literal is only
+// unsigned if suffixed, while others may be unsigned to fit.
+fn integer_literal(literal: &str) -> Literal {
+    let (digits, radix) = match literal.get(..2) {
+        Some("0x") | Some("0X") => (&literal[2..], 16),
+        Some("0b") | Some("0B") => (&literal[2..], 2),
+        Some(_) if literal.starts_with('0') => (&literal[1..], 8),
+        _ => (literal, 10),
+    };
+    let suffix = digits.trim_start_matches(|c: char| c.is_digit(radix));
+    let number = &digits[..digits.len() - suffix.len()];
+    // Digits outside the base are no suffix. A lone `0` is octal with no
+    // digits after the prefix.
+    if suffix.starts_with(|c: char| c.is_ascii_digit()) || (number.is_empty() && radix != 8) {
+        return Literal::Invalid(literal.to_string());
+    }
+    let value = match u64::from_str_radix(number, radix) {
+        Ok(value) => value,
+        Err(_) if number.is_empty() => 0,
+        Err(_) => return Literal::Invalid(literal.to_string()),
+    };
+    let decimal = radix == 10;
+    let int = i32::try_from(value).ok().map(|i| i as u32);
+    let uint = u32::try_from(value).ok();
+    let long = i64::try_from(value).ok().map(|_| value);
+    // The two `l`s of `ll` must have the same case.
+    if suffix.contains("lL") || suffix.contains("Ll") {
+        return Literal::InvalidSuffix(literal.to_string());
+    }
+    let typed = match suffix.to_ascii_lowercase().as_str() {
+        "" if decimal => int.map(Literal::Int).or_else(|| long.map(Literal::Long)),
+        "" => int
+            .map(Literal::Int)
+            .or_else(|| uint.map(Literal::UInt))
+            .or_else(|| long.map(Literal::Long))
+            .or(Some(Literal::ULong(value))),
+        "u" => uint.map(Literal::UInt).or(Some(Literal::ULong(value))),
+        "l" | "ll" if decimal => long.map(Literal::Long),
+        "l" | "ll" => long.map(Literal::Long).or(Some(Literal::ULong(value))),
+        "ul" | "lu" | "ull" | "llu" => Some(Literal::ULong(value)),
+        _ => return Literal::InvalidSuffix(literal.to_string()),
+    };
+    typed.unwrap_or_else(|| Literal::Invalid(literal.to_string()))
+}
+
 pub fn lex(s: &str) -> Vec<Token> {
+    lex_spanned(s).into_iter().map(|(t, _)| t).collect()
+}
+
+/// Like `lex`, but pairs each token with the position of its first
+/// character.
+pub fn lex_spanned(s: &str) -> Vec<(Token, Span)> {
+    // The position of the character most recently taken from the source,
+    // and of the one after it. Anything put back has always just been
+    // taken, so each token starts at `last`.
+    let last = Cell::new(Span { line: 1, col: 0 });
+    let next = Cell::new(Span { line: 1, col: 1 });
     let mut tok = Vec::new();
     let mut it = put_back(
         s.chars()
+            .inspect(|&c| {
+                let at = next.get();
+                last.set(at);
+                next.set(match c {
+                    '\n' => Span {
+                        line: at.line + 1,
+                        col: 1,
+                    },
+                    _ => Span {
+                        line: at.line,
+                        col: at.col + 1,
+                    },
+                });
+            })
             .skip_while(|&c| c == ' ' || c == '\t' || c == '\n'),
     );
     while let Some(c) = it.next() {
         //        print!("{} =>", c);
-        tok.push(match c {
+        let span = last.get();
+        let token = match c {
             '{' => Token::OpenBrace,
             '}' => Token::CloseBrace,
             '(' => Token::OpenParenthesis,
             ')' => Token::CloseParenthesis,
+            '[' => Token::OpenBracket,
+            ']' => Token::CloseBracket,
             ';' => Token::Semicolon,
-            '-' => match it.next().expect("Unexpected EOF") {
-                '=' => Token::AssignSub,
+            ',' => Token::Comma,
+            '?' => Token::QuestionMark,
+            ':' => Token::Colon,
+            '.' => match it.by_ref().peeking_take_while(|&c| c == '.').count() {
+                0 => Token::Dot,
+                2 => Token::Ellipsis,
+                _ => Token::Unidentified,
+            },
+            '-' => match it.next() {
+                Some('=') => Token::AssignSub,
+                Some('>') => Token::Arrow,
+                Some('-') => Token::Decrement,
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::Negative
                 }
             },
             '~' => Token::Complement,
-            '+' => match it.next().expect("Unexpected EOF") {
-                '=' => Token::AssignAdd,
+            '+' => match it.next() {
+                Some('=') => Token::AssignAdd,
+                Some('+') => Token::Increment,
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::Addition
                 }
             },
-            '*' => match it.next().expect("Unexpected EOF") {
-                '=' => Token::AssignMul,
+            '*' => match it.next() {
+                Some('=') => Token::AssignMul,
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::Multiplication
                 }
             },
-            '/' => match it.next().expect("Unexpected EOF") {
-                '=' => Token::AssignDiv,
+            '/' => match it.next() {
+                Some('=') => Token::AssignDiv,
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::Division
                 }
             },
-            '%' => match it.next().expect("Unexpected EOF") {
-                '=' => Token::AssignMod,
+            '%' => match it.next() {
+                Some('=') => Token::AssignMod,
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::Modulo
                 }
             },
-            '^' => match it.next().expect("Unexpected EOF") {
-                '=' => Token::AssignXor,
+            '^' => match it.next() {
+                Some('=') => Token::AssignXor,
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::BitXor
                 }
             },
-            '&' => match it.next().expect("Unexpected EOF") {
-                '&' => Token::And,
-                '=' => Token::AssignAnd,
+            '&' => match it.next() {
+                Some('&') => Token::And,
+                Some('=') => Token::AssignAnd,
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::BitAnd
                 }
             },
-            '|' => match it.next().expect("Unexpected EOF") {
-                '|' => Token::Or,
-                '=' => Token::AssignOr,
+            '|' => match it.next() {
+                Some('|') => Token::Or,
+                Some('=') => Token::AssignOr,
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::BitOr
                 }
             },
-            '=' => match it.next().expect("Unexpected EOF") {
-                '=' => Token::Equal,
+            '=' => match it.next() {
+                Some('=') => Token::Equal,
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::Assign
                 }
             },
-            '!' => match it.next().expect("Unexpected EOF") {
-                '=' => Token::NotEqual,
+            '!' => match it.next() {
+                Some('=') => Token::NotEqual,
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::Negation
                 }
             },
-            '<' => match it.next().expect("Unexpected EOF") {
-                '=' => Token::LessThanEqual,
-                '<' => match it.next().expect("Unexpected EOF") {
-                    '=' => Token::AssignShiftLeft,
+            '<' => match it.next() {
+                Some('=') => Token::LessThanEqual,
+                Some('<') => match it.next() {
+                    Some('=') => Token::AssignShiftLeft,
                     t => {
-                        it.put_back(t);
+                        if let Some(t) = t {
+                            it.put_back(t);
+                        }
                         Token::ShiftLeft
                     }
                 },
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::LessThan
                 }
             },
-            '>' => match it.next().expect("Unexpected EOF") {
-                '=' => Token::GreaterThanEqual,
-                '>' => match it.next().expect("Unexpected EOF") {
-                    '=' => Token::AssignShiftRight,
+            '>' => match it.next() {
+                Some('=') => Token::GreaterThanEqual,
+                Some('>') => match it.next() {
+                    Some('=') => Token::AssignShiftRight,
                     t => {
-                        it.put_back(t);
+                        if let Some(t) = t {
+                            it.put_back(t);
+                        }
                         Token::ShiftRight
                     }
                 },
                 t => {
-                    it.put_back(t);
+                    if let Some(t) = t {
+                        it.put_back(t);
+                    }
                     Token::GreaterThan
                 }
             },
@@ -186,33 +375,75 @@ pub fn lex(s: &str) -> Vec<Token> {
                 it.put_back(c);
                 match it
                     .by_ref()
-                    .peeking_take_while(|&c| {
-                        (c >= 'A' && c <= 'Z')
-                            || (c >= 'a' && c <= 'z')
-                            || (c >= '0' && c <= '9')
-                            || c == '-'
-                            || c == '_'
-                    })
+                    .peeking_take_while(|&c| c.is_ascii_alphanumeric() || c == '_')
                     .collect::<String>()
                     .as_ref()
                 {
                     "int" => Token::Keyword(Keyword::Int),
+                    "char" => Token::Keyword(Keyword::Char),
+                    "long" => Token::Keyword(Keyword::Long),
+                    "unsigned" => Token::Keyword(Keyword::Unsigned),
+                    "signed" => Token::Keyword(Keyword::Signed),
+                    "const" => Token::Keyword(Keyword::Const),
+                    "void" => Token::Keyword(Keyword::Void),
                     "return" => Token::Keyword(Keyword::Return),
+                    "struct" => Token::Keyword(Keyword::Struct),
+                    "sizeof" => Token::Keyword(Keyword::Sizeof),
+                    "typedef" => Token::Keyword(Keyword::Typedef),
+                    "if" => Token::Keyword(Keyword::If),
+                    "else" => Token::Keyword(Keyword::Else),
+                    "while" => Token::Keyword(Keyword::While),
+                    "for" => Token::Keyword(Keyword::For),
+                    "break" => Token::Keyword(Keyword::Break),
+                    "continue" => Token::Keyword(Keyword::Continue),
+                    "goto" => Token::Keyword(Keyword::Goto),
+                    "register" => Token::Keyword(Keyword::Register),
+                    "volatile" => Token::Keyword(Keyword::Volatile),
+                    "switch" => Token::Keyword(Keyword::Switch),
+                    "case" => Token::Keyword(Keyword::Case),
+                    "default" => Token::Keyword(Keyword::Default),
+                    "static" => Token::Keyword(Keyword::Static),
                     s => Token::Identifier(String::from(s)),
                 }
             }
+            '\'' => {
+                let c = match it.next() {
+                    Some('\\') => escape(it.next()),
+                    Some(c) if c.is_ascii() && c != '\'' => Some(c as u8),
+                    _ => None,
+                };
+                match (c, it.next()) {
+                    (Some(c), Some('\'')) => Token::Literal(Literal::Char(c)),
+                    _ => Token::Unidentified,
+                }
+            }
+            '"' => {
+                let mut bytes = Vec::new();
+                loop {
+                    match it.next() {
+                        Some('"') => break Token::Literal(Literal::String(bytes)),
+                        Some('\\') => match escape(it.next()) {
+                            Some(c) => bytes.push(c),
+                            None => break Token::Unidentified,
+                        },
+                        Some(c) if c.is_ascii() && c != '\n' => bytes.push(c as u8),
+                        _ => break Token::Unidentified,
+                    }
+                }
+            }
+            // Letters are taken as part of the literal, so that e.g. `0xG`
+            // and `12ab` are rejected rather than split in two.
             c @ '0'..='9' => {
                 it.put_back(c);
-                Token::Literal(Literal::Int(
-                    it.by_ref()
-                        .peeking_take_while(|&c| c >= '0' && c <= '9')
-                        .collect::<String>()
-                        .parse()
-                        .expect("Failed to parse integer literal"),
-                ))
+                let literal = it
+                    .by_ref()
+                    .peeking_take_while(|c| c.is_ascii_alphanumeric())
+                    .collect::<String>();
+                Token::Literal(integer_literal(&literal))
             }
             _ => Token::Unidentified,
-        });
+        };
+        tok.push((token, span));
         it.by_ref()
             .peeking_take_while(|&c| c == '\t' || c == ' ' || c == '\n')
             .last();
@@ -1,5 +1,14 @@
+pub mod aarch64;
+pub mod asm;
 pub mod ast;
+pub mod diagnostic;
+#[cfg(test)]
+mod emulator;
 pub mod lex;
+pub mod print;
+pub mod section;
+#[cfg(test)]
+mod tests;
 
-pub use ast::parse;
+pub use ast::{can_compile, compile_str, parse, parse_spanned};
 pub use lex::lex;
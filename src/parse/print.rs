@@ -0,0 +1,359 @@
+use super::ast::{
+    BinaryOperator, Constant, Expression, Function, Global, Program, Qualifiers, Statement, Type,
+    UnaryOperator,
+};
+
+/// Render a parsed program back as C source, indented four spaces per
+/// level. Every operand that is itself an operation is parenthesized, so
+/// the output shows how the parser grouped each expression. Sugar is shown
+/// as what it parsed to, e.g. `++x` prints as `x += 1`.
+pub fn pretty(program: &Program) -> String {
+    program
+        .1
+        .iter()
+        .map(global)
+        .chain(program.0.iter().map(function))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn global(g: &Global) -> String {
+    let mut text = format!(
+        "{}{}",
+        if g.is_static { "static " } else { "" },
+        declarator(&g.ty, &g.name)
+    );
+    if let Some(e) = &g.init {
+        text.push_str(&format!(" = {}", item(e)));
+    }
+    text + ";\n"
+}
+
+fn function(f: &Function) -> String {
+    let mut out = format!(
+        "{}{} {{\n",
+        if f.is_static { "static " } else { "" },
+        signature(f)
+    );
+    for s in f.body.iter() {
+        statement(&mut out, 1, s);
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn line(out: &mut String, depth: usize, text: &str) {
+    out.push_str(&"    ".repeat(depth));
+    out.push_str(text);
+    out.push('\n');
+}
+
+// Print `head` and then the statement it controls. A block opens on the same
+// line, and anything else goes on the next line, indented.
+fn clause(out: &mut String, depth: usize, head: &str, s: &Statement) {
+    match s {
+        Statement::Block(body) => {
+            line(out, depth, &format!("{} {{", head));
+            for s in body.iter() {
+                statement(out, depth + 1, s);
+            }
+            line(out, depth, "}");
+        }
+        s => {
+            line(out, depth, head);
+            statement(out, depth + 1, s);
+        }
+    }
+}
+
+fn statement(out: &mut String, depth: usize, s: &Statement) {
+    match s {
+        Statement::Return(Some(e), _) => line(out, depth, &format!("return {};", expression(e))),
+        Statement::Return(None, _) => line(out, depth, "return;"),
+        Statement::Declaration(..) | Statement::Expression(_) => {
+            line(out, depth, &simple_statement(s))
+        }
+        Statement::Struct(name, fields) => {
+            line(out, depth, &format!("struct {} {{", name));
+            for field in fields.iter() {
+                line(
+                    out,
+                    depth + 1,
+                    &format!("{};", declarator(&field.ty, &field.name)),
+                );
+            }
+            line(out, depth, "};");
+        }
+        Statement::Typedef(ty, name) => {
+            line(out, depth, &format!("typedef {};", declarator(ty, name)))
+        }
+        Statement::If(c, s1, s2) => {
+            clause(out, depth, &format!("if ({})", expression(c)), s1);
+            let mut s2 = s2;
+            while let Some(s) = s2 {
+                match &**s {
+                    Statement::If(c, s1, next) => {
+                        clause(out, depth, &format!("else if ({})", expression(c)), s1);
+                        s2 = next;
+                    }
+                    s => {
+                        clause(out, depth, "else", s);
+                        break;
+                    }
+                }
+            }
+        }
+        Statement::While(c, body) => {
+            clause(out, depth, &format!("while ({})", expression(c)), body)
+        }
+        Statement::For(init, c, post, body) => {
+            let head = format!(
+                "for ({}{};{})",
+                init.as_ref()
+                    .map_or(String::from(";"), |init| simple_statement(init)),
+                c.as_ref()
+                    .map_or(String::new(), |c| format!(" {}", expression(c))),
+                post.as_ref()
+                    .map_or(String::new(), |post| format!(" {}", expression(post)))
+            );
+            clause(out, depth, &head, body);
+        }
+        Statement::Break => line(out, depth, "break;"),
+        Statement::Continue => line(out, depth, "continue;"),
+        Statement::Block(body) => {
+            line(out, depth, "{");
+            for s in body.iter() {
+                statement(out, depth + 1, s);
+            }
+            line(out, depth, "}");
+        }
+        Statement::Switch(c, body) => {
+            clause(out, depth, &format!("switch ({})", expression(c)), body)
+        }
+        Statement::Case(value) => line(out, depth, &format!("case {}:", value)),
+        Statement::Default => line(out, depth, "default:"),
+        Statement::Label(label, s) => {
+            line(out, depth, &format!("{}:", label));
+            statement(out, depth, s);
+        }
+        Statement::Goto(label) => line(out, depth, &format!("goto {};", label)),
+    }
+}
+
+// A declaration or expression statement, which can also open a `for`.
+fn simple_statement(s: &Statement) -> String {
+    match s {
+        Statement::Declaration(ty, name, init, qualifiers) => {
+            let mut text = qualifier_names(qualifiers);
+            text.push_str(&declarator(ty, name));
+            // The initializer is parsed as an assignment to the variable.
+            match init {
+                Some(Expression::Assign(_, e)) => text.push_str(&format!(" = {}", item(e))),
+                Some(e) => text.push_str(&format!(" = {}", item(e))),
+                None => {}
+            }
+            text + ";"
+        }
+        Statement::Expression(e) => format!("{};", expression(e)),
+        _ => unreachable!(),
+    }
+}
+
+fn qualifier_names(qualifiers: &Qualifiers) -> String {
+    let mut names = String::new();
+    if qualifiers.register {
+        names.push_str("register ");
+    }
+    if qualifiers.volatile {
+        names.push_str("volatile ");
+    }
+    if qualifiers.constant {
+        names.push_str("const ");
+    }
+    names
+}
+
+fn type_name(ty: &Type) -> String {
+    match ty {
+        Type::Int => String::from("int"),
+        Type::Char => String::from("char"),
+        Type::UInt => String::from("unsigned int"),
+        Type::Long => String::from("long"),
+        Type::ULong => String::from("unsigned long"),
+        Type::Struct(name) => format!("struct {}", name),
+        Type::Alias(name) => name.clone(),
+        Type::Pointer(inner) => match type_name(inner) {
+            name if name.ends_with('*') => name + "*",
+            name => name + " *",
+        },
+        Type::Array(inner, n) => format!("{}[{}]", type_name(inner), n),
+    }
+}
+
+// The return type, name and parameters of a function, as they start its
+// definition.
+pub(crate) fn signature(f: &Function) -> String {
+    let name = format!(
+        "{}({})",
+        f.name,
+        f.params
+            .iter()
+            .map(|(ty, name)| declarator(ty, name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    match &f.ret {
+        Some(ty) => declarator(ty, &name),
+        None => format!("void {}", name),
+    }
+}
+
+// `ty name`, with any pointer stars against the name as in `int **p`.
+pub(crate) fn declarator(ty: &Type, name: &str) -> String {
+    match ty {
+        Type::Array(inner, n) => match &**inner {
+            // The dimensions follow the name outermost first, as in
+            // `int a[2][3]`.
+            Type::Array(_, _) => {
+                let text = declarator(inner, name);
+                let at = text.find('[').unwrap_or(text.len());
+                format!("{}[{}]{}", &text[..at], n, &text[at..])
+            }
+            inner => format!("{}[{}]", declarator(inner, name), n),
+        },
+        Type::Pointer(_) => format!("{}{}", type_name(ty), name),
+        ty => format!("{} {}", type_name(ty), name),
+    }
+}
+
+fn unary_symbol(op: UnaryOperator) -> &'static str {
+    match op {
+        UnaryOperator::Negative => "-",
+        UnaryOperator::Complement => "~",
+        UnaryOperator::Negation => "!",
+        UnaryOperator::Dereference => "*",
+        UnaryOperator::AddressOf => "&",
+    }
+}
+
+fn binary_symbol(op: BinaryOperator) -> &'static str {
+    match op {
+        BinaryOperator::Addition => "+",
+        BinaryOperator::Subtraction => "-",
+        BinaryOperator::Multiplication => "*",
+        BinaryOperator::Division => "/",
+        BinaryOperator::Modulo => "%",
+        BinaryOperator::BitAnd => "&",
+        BinaryOperator::BitOr => "|",
+        BinaryOperator::BitXor => "^",
+        BinaryOperator::ShiftLeft => "<<",
+        BinaryOperator::ShiftRight => ">>",
+        BinaryOperator::LessThan => "<",
+        BinaryOperator::LessThanEqual => "<=",
+        BinaryOperator::GreaterThan => ">",
+        BinaryOperator::GreaterThanEqual => ">=",
+        BinaryOperator::Equal => "==",
+        BinaryOperator::NotEqual => "!=",
+        BinaryOperator::And => "&&",
+        BinaryOperator::Or => "||",
+    }
+}
+
+// An operand of an operator, parenthesized unless it is a single term.
+fn operand(e: &Expression) -> String {
+    match e {
+        Expression::Binary(_, _, _)
+        | Expression::Assign(_, _)
+        | Expression::CompoundAssign(_, _, _)
+        | Expression::Conditional(_, _, _)
+        | Expression::Comma(_, _) => format!("({})", expression(e)),
+        e => expression(e),
+    }
+}
+
+// The operand of a postfix operator, which binds tighter than any prefix.
+fn postfix_operand(e: &Expression) -> String {
+    match e {
+        Expression::Unary(_, _) | Expression::Sizeof(_) | Expression::Cast(_, _) => {
+            format!("({})", expression(e))
+        }
+        e => operand(e),
+    }
+}
+
+// One item of a comma-separated list, where only a comma expression needs
+// parentheses.
+fn item(e: &Expression) -> String {
+    match e {
+        Expression::Comma(_, _) => format!("({})", expression(e)),
+        e => expression(e),
+    }
+}
+
+fn expression(e: &Expression) -> String {
+    match e {
+        Expression::Constant(Constant::Int(i)) => i.to_string(),
+        Expression::Constant(Constant::UInt(i)) => format!("{}u", i),
+        Expression::Constant(Constant::Long(i)) => format!("{}l", i),
+        Expression::Constant(Constant::ULong(i)) => format!("{}ul", i),
+        Expression::Var(name) => name.clone(),
+        Expression::Unary(op, e) => {
+            let op = unary_symbol(*op);
+            match operand(e) {
+                // Keep `-(-x)` from reading as a decrement.
+                e if op == "-" && e.starts_with(op) => format!("{}({})", op, e),
+                e => format!("{}{}", op, e),
+            }
+        }
+        Expression::Binary(op, e1, e2) => {
+            format!("{} {} {}", operand(e1), binary_symbol(*op), operand(e2))
+        }
+        Expression::Assign(lhs, e) => format!("{} = {}", operand(lhs), item(e)),
+        Expression::CompoundAssign(op, lhs, e) => {
+            format!("{} {}= {}", operand(lhs), binary_symbol(*op), item(e))
+        }
+        Expression::Postfix(BinaryOperator::Addition, e) => format!("{}++", postfix_operand(e)),
+        Expression::Postfix(_, e) => format!("{}--", postfix_operand(e)),
+        Expression::Member(e, member) => match &**e {
+            Expression::Unary(UnaryOperator::Dereference, e) => {
+                format!("{}->{}", postfix_operand(e), member)
+            }
+            e => format!("{}.{}", postfix_operand(e), member),
+        },
+        Expression::Index(base, index) => {
+            format!("{}[{}]", postfix_operand(base), expression(index))
+        }
+        Expression::Sizeof(e) => format!("sizeof {}", operand(e)),
+        Expression::SizeofType(ty) => format!("sizeof({})", type_name(ty)),
+        Expression::String(bytes) => format!("\"{}\"", escape_string(bytes)),
+        Expression::Conditional(c, e1, e2) => {
+            format!("{} ? {} : {}", operand(c), expression(e1), operand(e2))
+        }
+        Expression::Comma(e1, e2) => format!("{}, {}", expression(e1), item(e2)),
+        Expression::Call(name, args) => format!(
+            "{}({})",
+            name,
+            args.iter().map(item).collect::<Vec<_>>().join(", ")
+        ),
+        Expression::Cast(ty, e) => format!("({}){}", type_name(ty), operand(e)),
+        Expression::Initializer(items) => format!(
+            "{{{}}}",
+            items.iter().map(item).collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+// The bytes of a string literal, with the escapes the lexer accepts.
+fn escape_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'\n' => String::from("\\n"),
+            b'\t' => String::from("\\t"),
+            b'\\' => String::from("\\\\"),
+            b'"' => String::from("\\\""),
+            0 => String::from("\\0"),
+            b => char::from(b).to_string(),
+        })
+        .collect()
+}
@@ -0,0 +1,144 @@
+/// An output section of the emitted assembly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Section {
+    Data,
+    Rodata,
+    Bss,
+    Text,
+}
+
+impl Section {
+    fn name(self) -> &'static str {
+        match self {
+            Section::Data => ".data",
+            Section::Rodata => ".rodata",
+            Section::Bss => ".bss",
+            Section::Text => ".text",
+        }
+    }
+}
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::asm::Instruction;
+
+/// The line terminator used in the rendered output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    /// Convert `asm`, which uses `\n`, to this line ending.
+    pub fn apply(self, asm: String) -> String {
+        match self {
+            LineEnding::Lf => asm,
+            LineEnding::CrLf => asm.replace('\n', "\r\n"),
+        }
+    }
+}
+
+/// Collects emitted code per section, so that data and code can be emitted
+/// in whatever order the AST is walked and still come out grouped.
+#[derive(Debug, Default)]
+pub struct SectionBuilder {
+    data: Vec<Instruction>,
+    rodata: Vec<Instruction>,
+    bss: Vec<Instruction>,
+    text: Vec<Instruction>,
+    defined: BTreeSet<String>,
+    referenced: BTreeSet<String>,
+    // The bytes laid out so far in each data section, and the strictest
+    // alignment any of its objects needs.
+    layout: HashMap<Section, (usize, usize)>,
+    min_align: usize,
+}
+
+impl SectionBuilder {
+    pub fn push(&mut self, section: Section, code: Vec<Instruction>) {
+        match section {
+            Section::Data => &mut self.data,
+            Section::Rodata => &mut self.rodata,
+            Section::Bss => &mut self.bss,
+            Section::Text => &mut self.text,
+        }
+        .extend(code);
+    }
+
+    /// Align every object placed with `object` to at least `align` bytes,
+    /// e.g. 16 for SIMD loads.
+    pub fn over_align(&mut self, align: usize) {
+        self.min_align = align;
+    }
+
+    /// Append an object named `name`, holding one `width`-byte element per
+    /// value, to a data section. It is padded to start on a multiple of its
+    /// width, or of the over-alignment if that is larger. In `.bss` only the
+    /// number of values matters.
+    pub fn object(&mut self, section: Section, name: &str, width: usize, values: &[String]) {
+        let align = width.max(self.min_align);
+        let (size, max_align) = self.layout.entry(section).or_insert((0, 1));
+        let padding = (align - *size % align) % align;
+        *size += padding + width * values.len();
+        *max_align = align.max(*max_align);
+
+        let suffix = match width {
+            1 => 'b',
+            2 => 'w',
+            4 => 'd',
+            8 => 'q',
+            _ => panic!("No data directive for {}-byte elements.", width),
+        };
+        let mut code = Vec::new();
+        if padding > 0 {
+            code.push(Instruction::Directive(match section {
+                Section::Bss => format!("resb {}", padding),
+                _ => format!("times {} db 0", padding),
+            }));
+        }
+        code.push(Instruction::Label(name.to_owned()));
+        code.push(Instruction::Directive(match section {
+            Section::Bss => format!("res{} {}", suffix, values.len()),
+            _ => format!("d{} {}", suffix, values.join(", ")),
+        }));
+        self.push(section, code);
+    }
+
+    /// Record a symbol defined in this unit.
+    pub fn define(&mut self, name: &str) {
+        self.defined.insert(name.to_owned());
+    }
+
+    /// Record a use of a symbol which may be defined elsewhere. Symbols which
+    /// are never defined are declared `extern` in the output.
+    pub fn reference(&mut self, name: &str) {
+        self.referenced.insert(name.to_owned());
+    }
+
+    /// Render the extern declarations, then the non-empty sections in the
+    /// order data, rodata, bss, text. A section holding objects is aligned
+    /// for the strictest of them, since the assembler's default is 4 bytes.
+    pub fn render(self) -> Vec<Instruction> {
+        let mut out = self
+            .referenced
+            .difference(&self.defined)
+            .map(|name| Instruction::Directive(format!("extern {}", name)))
+            .collect::<Vec<_>>();
+        let sections = vec![
+            (Section::Data, self.data),
+            (Section::Rodata, self.rodata),
+            (Section::Bss, self.bss),
+            (Section::Text, self.text),
+        ];
+        for (section, code) in sections.into_iter().filter(|(_, code)| !code.is_empty()) {
+            out.push(Instruction::Directive(match self.layout.get(&section) {
+                Some((_, align)) => format!("section {} align={}", section.name(), align),
+                None => format!("section {}", section.name()),
+            }));
+            out.extend(code);
+        }
+        out
+    }
+}
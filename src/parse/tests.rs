@@ -0,0 +1,2099 @@
+//! End-to-end tests: C source is lexed, parsed and emitted, and the result
+//! is inspected or run on `emulator`.
+
+use std::collections::HashMap;
+
+use super::asm::{to_nasm, Condition, Instruction, Operand, Register};
+use super::ast::{ASTNode, Context, Error, Limits, Options, Symbol, SymbolKind};
+use super::diagnostic::Severity;
+use super::emulator;
+use super::lex::{lex_spanned, Span};
+use super::{lex, parse, parse_spanned};
+
+fn compile_with(src: &str, options: Options) -> Result<Vec<Instruction>, Error> {
+    parse(lex(src))?.emit(&mut Context::with_options(options))
+}
+
+fn compile(src: &str) -> Vec<Instruction> {
+    compile_with(src, Options::default()).unwrap()
+}
+
+fn asm(src: &str) -> String {
+    to_nasm(&compile(src))
+}
+
+fn run(src: &str) -> i64 {
+    emulator::run(&compile(src)).unwrap()
+}
+
+fn compile_folded(src: &str) -> Vec<Instruction> {
+    let mut program = parse(lex(src)).unwrap();
+    program.fold_constants();
+    program.emit(&mut Context::new()).unwrap()
+}
+
+#[test]
+fn struct_members_are_stored_separately() {
+    let src = "int main() {
+        struct P { int x; int y; };
+        struct P p;
+        p.x = 3;
+        p.y = 4;
+        return p.x * 10 + p.y;
+    }";
+    assert_eq!(run(src), 34);
+}
+
+#[test]
+fn struct_definitions_are_scoped() {
+    let src = "int f() { struct P { int x; }; struct P p; p.x = 1; return p.x; }
+        int main() { struct P { int a; int b; }; struct P p; p.b = 2; return f() + p.b; }";
+    assert_eq!(run(src), 3);
+
+    let src = "int main() { { struct P { int x; }; } struct P p; return 0; }";
+    assert!(matches!(
+        compile_with(src, Options::default()),
+        Err(Error::UnknownStruct { .. })
+    ));
+
+    let src = "int main() { struct P { int x; }; struct P { int y; }; return 0; }";
+    assert!(matches!(
+        compile_with(src, Options::default()),
+        Err(Error::DuplicateDeclaration { .. })
+    ));
+
+    // An inner block may shadow an outer definition.
+    let src = "int main() {
+        struct P { int x; };
+        { struct P { int y; int x; }; struct P q; q.x = 5; q.y = 6; return q.x; }
+    }";
+    assert_eq!(run(src), 5);
+}
+
+#[test]
+fn typedefs_name_their_type() {
+    assert_eq!(run("int main() { typedef int T; T x = 5; return x; }"), 5);
+    let src = "int main() { typedef unsigned U; U u = 0 - 1; return u > 0; }";
+    assert_eq!(run(src), 1);
+    let src = "int main() { typedef int T; int y = 3; T *p = &y; T **q = &p; *p = 4; return **q; }";
+    assert_eq!(run(src), 4);
+    // Other uses of a name before a star are still expressions.
+    let src = "int main() { int a = 6; int b = 7; a * b; return a * b; }";
+    assert_eq!(run(src), 42);
+    let program = parse(lex(src)).unwrap();
+    assert!(program.check(&Options::default()).unwrap().is_empty());
+    let src = "int main() { typedef int T; int y = 2; { int T = 3; T * y; return T * y; } }";
+    assert_eq!(run(src), 6);
+}
+
+#[test]
+fn typedefs_are_scoped() {
+    let src = "int f() { typedef long T; T x = 1; return x; }
+        int main() { T y = 2; return y; }";
+    assert!(matches!(
+        compile_with(src, Options::default()),
+        Err(Error::UnknownType { .. })
+    ));
+
+    let src = "int main() { { typedef int T; } T y = 2; return y; }";
+    assert!(matches!(
+        compile_with(src, Options::default()),
+        Err(Error::UnknownType { .. })
+    ));
+
+    let src = "int f() { typedef long T; T x = 1; return x; }
+        int main() { typedef unsigned T; T y = 0 - 1; return (y > 0) + f(); }";
+    assert_eq!(run(src), 2);
+
+    let src = "int main() {
+        typedef unsigned T;
+        { typedef int T; T y = 0 - 1; if (y > 0) return 1; }
+        T z = 0 - 1;
+        return z > 0;
+    }";
+    assert_eq!(run(src), 1);
+
+    let src = "int main() { typedef int T; typedef long T; return 0; }";
+    assert!(matches!(
+        compile_with(src, Options::default()),
+        Err(Error::ConflictingTypedef { .. })
+    ));
+    assert_eq!(
+        run("int main() { typedef int T; typedef int T; return 3; }"),
+        3
+    );
+}
+
+#[test]
+fn ints_are_four_bytes() {
+    assert_eq!(
+        run("int main() { int *p; return sizeof(p) + sizeof(*p); }"),
+        12
+    );
+
+    let src = "int main() {
+        struct P { int x; int y; };
+        struct P s;
+        int *p = &s.x;
+        *p = 0 - 1;
+        *(p + 1) = 7;
+        return sizeof(s) * 100 + s.x + s.y;
+    }";
+    assert_eq!(run(src), 806);
+
+    let src = "int main() { unsigned u = 0; unsigned *p = &u; *p = *p - 1; return *p > 0; }";
+    assert_eq!(run(src), 1);
+}
+
+#[test]
+fn output_hash_is_deterministic() {
+    let hash = |src: &str| {
+        let program = parse(lex(src)).unwrap();
+        program.emit_with_hash(&mut Context::new()).unwrap().1
+    };
+    // The branch generates labels, which must be numbered the same each
+    // time.
+    let src = "int main() { int a = 1; if (a) return 2; return 3; }";
+    assert_eq!(hash(src), hash(src));
+    assert_ne!(
+        hash(src),
+        hash("int main() { int a = 1; if (a) return 2; return 4; }")
+    );
+}
+
+#[test]
+fn negation_and_complement_work_in_place() {
+    let code = asm("int main() { long x = 5; x = -x; return x; }");
+    let neg = code
+        .find("neg qword [rbp - 8]\nmov rax, [rbp - 8]\n")
+        .unwrap();
+    assert!(!code[neg..].contains("mov [rbp - 8], rax"));
+
+    // An int is worked on as a dword, which wraps an unsigned one back
+    // into 32 bits.
+    let src = "int main() { unsigned u = 1; u = -u; return u; }";
+    assert!(asm(src).contains("neg dword [rbp - 8]\nmov eax, dword [rbp - 8]\n"));
+    assert_eq!(run(src), 0xffff_ffff);
+    assert_eq!(run("int main() { int x = 5; x = ~x; return x; }"), -6);
+}
+
+#[test]
+fn compound_assignment_through_pointers() {
+    let src = "int main() { int x = 3; int *a = &x; a[0] <<= 2; return x; }";
+    assert_eq!(run(src), 12);
+    let src = "int main() { int a[2] = {3, 1}; a[0] <<= 2; a[1] -= 2; return a[0] + a[1]; }";
+    assert_eq!(run(src), 11);
+    let src = "int main() { int x = 0x1234; int *p = &x; *p &= 0xF; return x; }";
+    assert_eq!(run(src), 4);
+    let src = "int main() {
+        int x = 7;
+        int *p = &x;
+        *p %= 4;
+        *p |= 8;
+        *p ^= 1;
+        *p >>= 1;
+        return x;
+    }";
+    assert_eq!(run(src), 5);
+
+    // The target's address is only computed once.
+    let src = "int main() {
+        int i = 0;
+        int x = 5;
+        int *a = &x;
+        a[i++] += 1;
+        return i * 10 + x;
+    }";
+    assert_eq!(run(src), 16);
+}
+
+#[test]
+fn prefix_increment_yields_the_new_value() {
+    let src = "int main() { int x = 5; int y = ++x * 10 + --x; return y * 10 + x; }";
+    assert_eq!(run(src), 655);
+
+    // The operand is only evaluated once.
+    let src = "int main() {
+        int i = 0;
+        int x = 5;
+        int *a = &x;
+        int y = ++a[i++] + 1;
+        return i * 100 + x * 10 + y;
+    }";
+    assert_eq!(run(src), 167);
+    assert!(matches!(
+        compile_with("int main() { return ++5; }", Options::default()),
+        Err(Error::NotAssignable { .. })
+    ));
+}
+
+#[test]
+fn defined_constants_are_substituted() {
+    let defs = |name: &str, value: i64| {
+        let mut defs = HashMap::new();
+        defs.insert(name.to_string(), value);
+        defs
+    };
+    let substituted = |src: &str, defs: &HashMap<String, i64>| {
+        let mut program = parse(lex(src)).unwrap();
+        program.substitute_constants(defs);
+        program.fold_constants();
+        program.emit(&mut Context::new()).unwrap()
+    };
+
+    let code = substituted("int main() { return N * N; }", &defs("N", 10));
+    assert!(to_nasm(&code).contains("mov rax, 100\n"));
+    assert_eq!(emulator::run(&code).unwrap(), 100);
+
+    let src = "int main() { long x = N; return x / 1000000000; }";
+    let code = substituted(src, &defs("N", 5_000_000_000));
+    assert_eq!(emulator::run(&code).unwrap(), 5);
+
+    // Locals shadow a definition, including one declared by a `for`.
+    let src = "int main() {
+        int s = 0;
+        for (int N = 0; N < 3; N = N + 1) s = s + N;
+        { int N = 1; s = s + N * 10; }
+        return s + N * 100;
+    }";
+    let code = substituted(src, &defs("N", 2));
+    assert_eq!(emulator::run(&code).unwrap(), 213);
+}
+
+#[test]
+fn symbol_table_lists_functions_and_globals() {
+    let src = "int counter = 3; int main() { return counter; }";
+    let program = parse(lex(src)).unwrap();
+    let (asm, symbols) = program.emit_with_symbols(&mut Context::new()).unwrap();
+    assert_eq!(
+        symbols,
+        vec![
+            Symbol {
+                name: String::from("main"),
+                kind: SymbolKind::Function {
+                    signature: String::from("int main()"),
+                },
+            },
+            Symbol {
+                name: String::from("counter"),
+                kind: SymbolKind::Global,
+            },
+        ]
+    );
+    assert!(asm.contains("global counter\n"));
+
+    let src = "static int hidden; static int helper() { return hidden; }";
+    let program = parse(lex(src)).unwrap();
+    let (_, symbols) = program.emit_with_symbols(&mut Context::new()).unwrap();
+    assert!(symbols.iter().all(|s| s.kind == SymbolKind::Static));
+    assert_eq!(symbols[1].name, "__static_hidden");
+}
+
+#[test]
+fn globals_are_shared_between_functions() {
+    let src = "int g = 5;
+        long big;
+        int bump() { g = g + 1; return 0; }
+        int main() {
+            bump();
+            big = 7;
+            int *p = &g;
+            *p = *p * 2;
+            return g + big;
+        }";
+    assert_eq!(run(src), 19);
+    assert_eq!(run("int x = 1; int main() { int x = 2; return x; }"), 2);
+    assert_eq!(run("unsigned u = -1; int main() { return u > 0; }"), 1);
+    assert!(matches!(
+        compile_with(
+            "int a = 1; int b = a; int main() { return b; }",
+            Options::default()
+        ),
+        Err(Error::NonConstantInitializer { .. })
+    ));
+}
+
+#[test]
+fn limits_reject_large_programs() {
+    let limited = |src: &str, limits: Limits| {
+        compile_with(
+            src,
+            Options {
+                limits,
+                ..Options::default()
+            },
+        )
+    };
+    let exceeded = |src: &str, limits: Limits| match limited(src, limits) {
+        Err(Error::LimitExceeded { limit, .. }) => limit,
+        r => panic!("expected a limit to be exceeded, got {:?}", r),
+    };
+
+    let src = "int f() { return 1; } int main() { return f(); }";
+    let functions = |n| Limits {
+        functions: Some(n),
+        ..Limits::default()
+    };
+    assert_eq!(exceeded(src, functions(1)), "functions");
+    assert!(limited(src, functions(2)).is_ok());
+
+    // Statements inside other statements count too.
+    let src = "int main() { int a = 0; while (a < 3) { a = a + 1; if (a) a = a; } return a; }";
+    let statements = |n| Limits {
+        statements: Some(n),
+        ..Limits::default()
+    };
+    assert_eq!(exceeded(src, statements(5)), "statements per function");
+    assert!(limited(src, statements(6)).is_ok());
+
+    // A bare block nests as deep as a statement's body.
+    let src = "int main() { if (1) { { while (0) {} } } return 0; }";
+    let nesting = |n| Limits {
+        nesting: Some(n),
+        ..Limits::default()
+    };
+    assert_eq!(exceeded(src, nesting(2)), "levels of block nesting");
+    assert!(limited(src, nesting(3)).is_ok());
+}
+
+#[test]
+fn declaration_lints_point_at_the_declaration() {
+    let src = "int main() {
+    int x = 1;
+    if (x) {
+        int y = 2;
+        int *x = &y;
+        return *x;
+    }
+    for (int i = 0; i < 3; i = i + 1) { int z; }
+    return 0;
+}";
+    let tokens = lex_spanned(src);
+    let program = parse_spanned(tokens.clone()).unwrap();
+    let diagnostics = program.check_spanned(&tokens, &Options::default()).unwrap();
+    let found = diagnostics
+        .iter()
+        .map(|d| (d.code, d.span))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        found,
+        vec![
+            ("shadowing", Some(Span { line: 5, col: 14 })),
+            ("unused-variable", Some(Span { line: 8, col: 45 })),
+        ]
+    );
+
+    let options = Options {
+        warnings_as_errors: true,
+        ..Options::default()
+    };
+    match program.check_spanned(&tokens, &options) {
+        Err(e @ Error::WarningAsError { .. }) => {
+            assert_eq!(e.span(), Some(Span { line: 5, col: 14 }))
+        }
+        r => panic!("expected the shadowing to fail the check, got {:?}", r),
+    }
+    assert!(matches!(
+        compile_with(src, options.clone()),
+        Err(Error::WarningAsError { .. })
+    ));
+    assert!(compile_with("int main() { int x = 1; return x; }", options).is_ok());
+}
+
+#[test]
+fn arrow_reaches_members_through_a_pointer() {
+    let src = "int main() { struct P{int x;}; struct P s; struct P *p=&s; p->x = 9; return p->x; }";
+    assert_eq!(run(src), 9);
+
+    let src = "int main() {
+        struct P { int x; int y; };
+        struct P s;
+        struct P *p;
+        p = &s;
+        p->x = 1;
+        p->y = 2;
+        p->y += 3;
+        return s.x * 10 + (*p).y;
+    }";
+    assert_eq!(run(src), 15);
+}
+
+#[test]
+fn return_takes_a_comma_expression() {
+    assert_eq!(run("int main() { int a=0; return a=5, a+1; }"), 6);
+    // The assignment is its own operand of the comma, so `a` is 5 after it.
+    let src = "int main() { int a = 0; int b = (a = 5, a + 1); return a * 10 + b; }";
+    assert_eq!(run(src), 56);
+}
+
+#[test]
+fn bare_comma_in_return_is_linted() {
+    let lints = |src: &str| {
+        let tokens = lex_spanned(src);
+        let options = Options {
+            lint_return_comma: true,
+            ..Options::default()
+        };
+        let program = parse_spanned(tokens.clone()).unwrap();
+        program
+            .check_spanned(&tokens, &options)
+            .unwrap()
+            .into_iter()
+            .filter(|d| d.code == "return-comma")
+            .map(|d| d.span)
+            .collect::<Vec<_>>()
+    };
+    let src = "int main() {\n    if (0) return 3;\n    return 1, 2;\n}";
+    assert_eq!(lints(src), vec![Some(Span { line: 3, col: 5 })]);
+    assert!(lints("int main() { return (1, 2); }").is_empty());
+    assert_eq!(run("int main() { return 1, 2; }"), 2);
+
+    // The lint is opt-in.
+    let program = parse(lex("int main() { return 1, 2; }")).unwrap();
+    assert!(program.check(&Options::default()).unwrap().is_empty());
+    let src = "int main() { typedef int T; int y = 2; { int T = 3; T * y; return T * y; } }";
+    assert_eq!(run(src), 6);
+}
+
+#[test]
+fn conditional_assigns_in_the_taken_branch_only() {
+    let src = "int main() { int a; int c = 0; c ? (a = 1) : (a = 2); return a; }";
+    assert_eq!(run(src), 2);
+    let src = "int main() { int a; int c = 1; c ? (a = 1) : (a = 2); return a; }";
+    assert_eq!(run(src), 1);
+
+    // A store through a null pointer faults, so would show if it ran.
+    let src = "int main() {
+        int a = 0;
+        int *p = 0;
+        int c = 0;
+        c ? (*p = 1) : (a = 2);
+        return a;
+    }";
+    assert_eq!(run(src), 2);
+}
+
+#[test]
+fn noreturn_calls_end_their_block() {
+    let src = "int main() { int a = 1; if (a) abort(); return a; }";
+    let program = parse(lex(src)).unwrap();
+    let options = Options::default();
+    let cfgs = program.cfg(&options);
+    let reachable = cfgs[0].reachable();
+    // The call's block doesn't flow on to the join, which is only reached
+    // when the condition is false.
+    assert!(reachable.iter().all(|&r| r));
+    assert!(!cfgs[0].edges().contains(&(1, 2)));
+
+    let src = "int main() { fail(); return 1; }";
+    let program = parse(lex(src)).unwrap();
+    assert_eq!(program.cfg(&options)[0].reachable(), vec![true]);
+    let options = Options {
+        noreturn: vec![String::from("fail")],
+        ..Options::default()
+    };
+    assert_eq!(program.cfg(&options)[0].reachable(), vec![true, false]);
+
+    assert!(asm("int main() { exit(1); return 0; }").contains("call exit\nud2\n"));
+    let code = compile_with(src, options).unwrap();
+    assert!(to_nasm(&code).contains("call fail\nud2\n"));
+    assert!(!asm("int main() { puts(0); return 0; }").contains("ud2"));
+}
+
+#[test]
+fn address_taken_variables_are_found() {
+    let src = "int main() { int x; int *p = &x; int y = 1; { int z; int *q = &z; } return y; }";
+    let taken = parse(lex(src)).unwrap().address_taken_variables();
+    let mut main = taken["main"].iter().cloned().collect::<Vec<_>>();
+    main.sort();
+    assert_eq!(main, vec!["x", "z"]);
+}
+
+#[test]
+fn main_takes_argc_and_argv() {
+    let src = "int main(int argc, char **argv){ return argc; }";
+    let code = compile(src);
+    let asm = to_nasm(&code);
+    // argc arrives in edi and argv in rsi, and both are spilled to the frame.
+    assert!(asm.contains("mov dword [rbp - 8], edi\n"));
+    assert!(asm.contains("mov [rbp - 16], rsi\n"));
+    assert_eq!(emulator::call(&code, "main", &[3, 0]).unwrap(), 3);
+
+    // Parameters keep their types, so a pointer one can be written through.
+    let src = "int set(int *p, char c, long n) { *p = c + n; return 0; }
+        int main() { int x = 0; set(&x, 0 - 2, 10); return x; }";
+    assert_eq!(run(src), 8);
+}
+
+#[test]
+fn returns_agree_with_the_return_type() {
+    let src = "int main() { if (1) return; return 0; }";
+    assert!(matches!(
+        compile_with(src, Options::default()),
+        Err(Error::ReturnWithoutValue { .. })
+    ));
+    let src = "void f() { while (1) { return 1; } } int main() { return 0; }";
+    assert!(matches!(
+        compile_with(src, Options::default()),
+        Err(Error::ReturnValueInVoid { .. })
+    ));
+
+    let src = "void f() { if (1) return; } int main() { f(); return 4; }";
+    assert_eq!(run(src), 4);
+}
+
+#[test]
+fn mangled_functions_may_share_a_name() {
+    let src = "int add(int a) { return a + 100; }
+        int add(int a, int b) { return a + b; }
+        int add(long *p) { return *p; }
+        int main() { long n = 1000; return add(1) + add(2, 3) + add(&n); }";
+    assert!(matches!(
+        compile_with(src, Options::default()),
+        Err(Error::DuplicateDeclaration { .. })
+    ));
+
+    let options = Options {
+        mangle: true,
+        ..Options::default()
+    };
+    let code = compile_with(src, options).unwrap();
+    let asm = to_nasm(&code);
+    for symbol in &["add_i", "add_i_i", "add_Pl"] {
+        assert!(asm.contains(&format!("\n{}:\n", symbol)));
+        assert!(asm.contains(&format!("call {}\n", symbol)));
+    }
+    assert!(asm.contains("\nmain:\n"));
+    assert_eq!(emulator::run(&code).unwrap(), 1106);
+}
+
+#[test]
+fn mangled_struct_names_do_not_collide() {
+    let src = "int f(struct a_Pb *p) { return 1; }
+        int f(struct a *p, struct b *q) { return 2; }
+        int g(struct i *p) { return 3; }
+        int g(int *p) { return 4; }
+        int main() { return 0; }";
+    let options = Options {
+        mangle: true,
+        ..Options::default()
+    };
+    // Each pair would share a symbol if names weren't prefixed with their
+    // length.
+    let asm = to_nasm(&compile_with(src, options).unwrap());
+    for symbol in &["f_P4a_Pb", "f_P1a_P1b", "g_P1i", "g_Pi"] {
+        assert!(asm.contains(&format!("\n{}:\n", symbol)), "{}", symbol);
+    }
+}
+
+#[test]
+fn arguments_past_the_sixth_go_on_the_stack() {
+    let seven = "int f(int a, int b, int c, int d, int e, int g, int h) {
+            return a + b * 2 + c * 3 + d * 4 + e * 5 + g * 6 + h * 7;
+        }
+        int main() { return f(1, 1, 1, 1, 1, 1, 10) + f(0, 0, 0, 0, 0, 0, 0); }";
+    assert_eq!(run(seven), 91);
+    let src = "int f(int a, int b, int c, int d, int e, int g, int h, long i) { return h - i; }
+        int main() { int x = 3; return f(0, 0, 0, 0, 0, 0, x * 4, x); }";
+    assert_eq!(run(src), 9);
+
+    let bare_metal = Options {
+        register_args_only: true,
+        ..Options::default()
+    };
+    assert!(matches!(
+        compile_with(seven, bare_metal.clone()),
+        Err(Error::StackArguments { count: 7, .. })
+    ));
+    let src = "int main() { return g(1, 2, 3, 4, 5, 6, 7); }";
+    assert!(matches!(
+        compile_with(src, bare_metal.clone()),
+        Err(Error::StackArguments { count: 7, .. })
+    ));
+
+    let six = "int f(int a, int b, int c, int d, int e, int g) { return a + g; }
+        int main() { return f(1, 2, 3, 4, 5, 6); }";
+    let options = Options {
+        omit_frame_pointer: true,
+        ..bare_metal
+    };
+    let code = compile_with(six, options).unwrap();
+    // Nothing is kept below rsp.
+    assert!(!to_nasm(&code).contains("[rsp - "));
+    assert_eq!(emulator::run(&code).unwrap(), 7);
+}
+
+#[test]
+fn redundant_casts_are_noted_and_elided() {
+    let src = "int main() { int x = 5; return (int)x; }";
+    let code = asm(src);
+    assert!(!code.contains("movsxd rax, eax"));
+    assert_eq!(run(src), 5);
+    assert!(asm("int main() { long x = 5; return (int)x; }").contains("movsxd rax, eax"));
+
+    let program = parse(lex(src)).unwrap();
+    let options = Options {
+        warnings_as_errors: true,
+        ..Options::default()
+    };
+    // A note isn't promoted to an error.
+    let diagnostics = program.check(&options).unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, "redundant-cast");
+    assert_eq!(diagnostics[0].severity, Severity::Note);
+}
+
+#[test]
+fn initializer_lists_may_end_with_a_comma() {
+    assert_eq!(
+        run("int main() { int a[] = {1, 2,}; return a[0] * 10 + a[1]; }"),
+        12
+    );
+    // Elements left out of the list are zeroed.
+    assert_eq!(
+        run("int main() { int a[3] = {4}; return a[0] + a[1] + a[2]; }"),
+        4
+    );
+
+    let src = "int f(int a, int b) { return a + b; } int main() { return f(1, 2,); }";
+    assert!(parse(lex(src)).is_err());
+    let src = "int main() { int a[1] = {1, 2}; return 0; }";
+    assert!(matches!(
+        compile_with(src, Options::default()),
+        Err(Error::ExcessInitializers { .. })
+    ));
+}
+
+#[test]
+fn array_sizes_are_inferred_from_the_initializer() {
+    let src = "int main() { int a[] = {10, 20}; return a[1] + sizeof(a)/sizeof(int); }";
+    assert_eq!(run(src), 22);
+
+    assert!(matches!(
+        parse(lex("int main() { int a[]; return 0; }")),
+        Err(Error::IncompleteArrayType { .. })
+    ));
+}
+
+#[test]
+fn constant_negative_indices_are_diagnosed() {
+    let program = parse(lex("int main() { int a[3]; return a[-1]; }")).unwrap();
+    let diagnostics = program.check(&Options::default()).unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, "negative-index");
+
+    let strict = Options {
+        warnings_as_errors: true,
+        ..Options::default()
+    };
+    assert!(matches!(
+        program.check(&strict),
+        Err(Error::WarningAsError { .. })
+    ));
+
+    let program = parse(lex("int main() { int a[3]; a[2] = 1; return a[3 - 1]; }")).unwrap();
+    assert!(program.check(&Options::default()).unwrap().is_empty());
+    let src = "int main() { typedef int T; int y = 2; { int T = 3; T * y; return T * y; } }";
+    assert_eq!(run(src), 6);
+}
+
+#[test]
+fn stack_alignment_is_checked_before_calls() {
+    let src = "int f(int a) { return a + 1; } int main() { int x = 1; return f(x); }";
+    let options = Options {
+        check_stack_alignment: true,
+        ..Options::default()
+    };
+    let code = compile_with(src, options).unwrap();
+    let call = code
+        .iter()
+        .position(|i| matches!(i, Instruction::Call(_)))
+        .unwrap();
+    assert_eq!(
+        to_nasm(&code[call - 2..call]),
+        to_nasm(&[
+            Instruction::Test(Operand::Reg(Register::Rsp), Operand::Imm(15)),
+            Instruction::Jcc(Condition::NotEqual, String::from("__misaligned")),
+        ])
+    );
+    assert!(to_nasm(&code).contains("__misaligned:"));
+    // Calls from this compiler keep the stack aligned.
+    assert_eq!(emulator::run(&code).unwrap(), 2);
+
+    assert!(!asm(src).contains("__misaligned"));
+}
+
+#[test]
+fn literals_are_typed_by_suffix_and_value() {
+    use super::lex::{Literal, Token};
+    let literal = |src: &str| match lex(src).pop() {
+        Some(Token::Literal(l)) => l,
+        tok => panic!("{:?} is not a literal", tok),
+    };
+    assert_eq!(literal("10UL"), Literal::ULong(10));
+    assert_eq!(literal("10u"), Literal::UInt(10));
+    assert_eq!(literal("10ll"), Literal::Long(10));
+    // Too big for an int, a decimal literal is a long, and a hexadecimal one
+    // may be unsigned.
+    assert_eq!(literal("3000000000"), Literal::Long(3_000_000_000));
+    assert_eq!(literal("0xffffffff"), Literal::UInt(0xffff_ffff));
+    assert_eq!(literal("5000000000u"), Literal::ULong(5_000_000_000));
+    assert_eq!(literal("0"), Literal::Int(0));
+
+    // The product is computed in 64 bits.
+    assert_eq!(
+        run("int main() { return 10UL * 1000000000 / 1000000000; }"),
+        10
+    );
+    assert_eq!(
+        run("int main() { long x = 3000000000; return x / 1000000000; }"),
+        3
+    );
+
+    let err = parse(lex("int main() { return 10x; }")).unwrap_err();
+    assert!(matches!(err, Error::InvalidSuffix { .. }));
+    assert!(err.to_string().starts_with("Invalid suffix"));
+    assert!(matches!(
+        parse(lex("int main() { return 0xG; }")),
+        Err(Error::InvalidLiteral { .. })
+    ));
+    assert!(matches!(
+        parse(lex("int main() { return 10lL; }")),
+        Err(Error::InvalidSuffix { .. })
+    ));
+}
+
+#[test]
+fn misplaced_operators_suggest_a_unary_one() {
+    use super::lex::Token;
+    let err = parse(lex("int main() { return != 1; }")).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::MisplacedOperator {
+            suggested: Token::Negation,
+            ..
+        }
+    ));
+    assert!(err.to_string().contains("Did you mean the unary operator"));
+
+    assert!(matches!(
+        parse(lex("int main() { return ^1; }")),
+        Err(Error::MisplacedOperator {
+            suggested: Token::Complement,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn constant_logical_operands_are_folded() {
+    let code = to_nasm(&compile_folded("int main() { return 1 && 2; }"));
+    assert!(code.contains("mov rax, 1\n"));
+    assert!(!code.contains("cmp"));
+
+    // The right operand is never evaluated once the left decides the
+    // result, so its call goes too.
+    let f = "int f() { return 3; } ";
+    let code = compile_folded(&format!("{}int main() {{ return 0 && f(); }}", f));
+    assert!(!to_nasm(&code).contains("call f"));
+    assert_eq!(emulator::run(&code).unwrap(), 0);
+    let code = compile_folded(&format!("{}int main() {{ return 1 || f(); }}", f));
+    assert!(!to_nasm(&code).contains("call f"));
+    assert_eq!(emulator::run(&code).unwrap(), 1);
+
+    // Otherwise the right operand alone gives the result, and is kept.
+    let code = compile_folded(&format!("{}int main() {{ return 1 && f(); }}", f));
+    assert!(to_nasm(&code).contains("call f"));
+    assert_eq!(emulator::run(&code).unwrap(), 1);
+}
+
+#[test]
+fn self_tail_calls_become_jumps() {
+    let src = "int count(int n, int acc) {
+        if (n == 0) return acc;
+        return count(n - 1, acc + 1);
+    }";
+    let options = Options {
+        tail_calls: true,
+        ..Options::default()
+    };
+    let code = compile_with(src, options).unwrap();
+    assert!(!code.iter().any(|i| matches!(i, Instruction::Call(_))));
+    // The recursive call jumps back above the body's first branch.
+    let label = |target: &str| {
+        code.iter()
+            .position(|i| matches!(i, Instruction::Label(l) if l == target))
+    };
+    let branch = code
+        .iter()
+        .position(|i| matches!(i, Instruction::Jcc(_, _)))
+        .unwrap();
+    assert!(code.iter().any(|i| match i {
+        Instruction::Jmp(Operand::Symbol(target)) => label(target).unwrap() < branch,
+        _ => false,
+    }));
+    assert_eq!(emulator::call(&code, "count", &[1000, 0]).unwrap(), 1000);
+
+    assert!(asm(src).contains("call count"));
+}
+
+#[test]
+fn lone_expressions_read_bound_variables() {
+    use super::ast::compile_expression;
+    let mut vars = HashMap::new();
+    vars.insert(String::from("a"), 8);
+    let code = compile_expression(lex("a + 1"), &vars).unwrap();
+    assert!(code.starts_with("movsxd rax, dword [rbp - 8]\n"));
+    assert!(code.contains("add rax, rcx\n"));
+
+    assert!(matches!(
+        compile_expression(lex("b + 1"), &vars),
+        Err(Error::UndeclaredVariable { .. })
+    ));
+    assert!(compile_expression(lex("a + 1;"), &vars).is_err());
+}
+
+#[test]
+fn struct_returns_are_unsupported() {
+    let err = parse(lex("struct P f() { return 0; }")).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::Unsupported {
+            feature: "struct by value",
+            ..
+        }
+    ));
+    assert!(err.to_string().contains("Return a pointer"));
+
+    // A pointer to one is returned like any other scalar.
+    let src = "struct P *f(struct P *p) { return p; }
+        int main() { struct P { int x; }; struct P s; s.x = 7; struct P *q = f(&s); return q->x; }";
+    assert_eq!(run(src), 7);
+    let program = parse(lex(src)).unwrap();
+    assert!(super::print::pretty(&program).starts_with("struct P *f(struct P *p) {"));
+}
+
+#[test]
+fn output_has_no_stray_whitespace() {
+    use super::ast::normalize;
+    let src = "int g = 1; int f() { return g; } int main() { int x = f(); return x + 2; }";
+    let program = parse(lex(src)).unwrap();
+    let mut ctx = Context::new();
+    let code = program.emit(&mut ctx).unwrap();
+    let out = ctx.render(&code);
+    assert!(out.lines().all(|l| l == l.trim_end()));
+    assert!(!out.contains("\n\n\n"));
+    assert_eq!(normalize(&out), out);
+
+    let messy = "  mov rax, 1  \n\n\n\tret \r\n";
+    assert_eq!(normalize(messy), "mov rax, 1\r\n\r\nret\r\n");
+}
+
+#[test]
+fn specifiers_may_come_in_any_order() {
+    let src = |decl: &str| {
+        format!(
+            "int main() {{ {} x = 0; x = x - 1; return x > 4294967296 && sizeof(x) == 8; }}",
+            decl
+        )
+    };
+    for decl in &["unsigned long int", "long unsigned", "int long unsigned"] {
+        assert_eq!(run(&src(decl)), 1, "{}", decl);
+    }
+    assert_eq!(run("int main() { const unsigned x = 3; return x; }"), 3);
+
+    assert!(matches!(
+        parse(lex("int main() { signed unsigned int x; return 0; }")),
+        Err(Error::ConflictingSpecifiers { .. })
+    ));
+    assert!(matches!(
+        parse(lex("int main() { char int x; return 0; }")),
+        Err(Error::ConflictingSpecifiers { .. })
+    ));
+}
+
+#[test]
+fn frame_pointer_may_be_omitted() {
+    let src = "int main() { int x = 2; int y = x + 3; return y * x; }";
+    let options = Options {
+        omit_frame_pointer: true,
+        ..Options::default()
+    };
+    let code = compile_with(src, options).unwrap();
+    let text = to_nasm(&code);
+    assert!(!text.contains("mov rbp, rsp"));
+    assert!(!text.contains("rbp"));
+    assert!(text.contains("[rsp + "));
+    assert_eq!(emulator::run(&code).unwrap(), 10);
+
+    assert!(asm(src).contains("mov rbp, rsp"));
+}
+
+#[test]
+fn repeated_constants_are_counted() {
+    let src = "int main() { int x = 1000000; return x + 1000000 + 3; }";
+    let constants = parse(lex(src)).unwrap().constants();
+    assert_eq!(constants["main"][&1000000], 2);
+    assert_eq!(constants["main"][&3], 1);
+
+    // Longs are counted too, and constants are told apart by value rather
+    // than by their low 32 bits.
+    let src = "int main() { long x = 5000000000; return x + 5000000000 + 4294967295u; }";
+    let mut program = parse(lex(src)).unwrap();
+    program.fold_constants();
+    let constants = program.constants();
+    assert_eq!(constants["main"][&5_000_000_000], 2);
+    assert_eq!(constants["main"][&4_294_967_295], 1);
+    assert!(!constants["main"].contains_key(&-1));
+    let mut program = parse(lex("int main() { return -1 + 4294967295u; }")).unwrap();
+    program.fold_constants();
+    let constants = program.constants();
+    assert_eq!(constants["main"][&-1], 1);
+    assert_eq!(constants["main"][&4_294_967_295], 1);
+}
+
+#[test]
+fn calls_are_kept_as_statements() {
+    let src = "int f() { return 1; } int main() { f(); 3 + 4; return 0; }";
+    let code = asm(src);
+    assert!(code.contains("call f"));
+    assert!(!code.contains("mov rax, 3"));
+    assert!(!code.contains("mov rax, 4"));
+}
+
+#[test]
+fn division_is_guarded_under_the_flag() {
+    let src = "int main() { int a = 7; int b = 2; return a / b; }";
+    let options = Options {
+        check_division: true,
+        ..Options::default()
+    };
+    let code = compile_with(src, options).unwrap();
+    let div = code
+        .iter()
+        .position(|i| matches!(i, Instruction::Idiv(_)))
+        .unwrap();
+    let guard = code
+        .iter()
+        .position(|i| matches!(i, Instruction::Ud2))
+        .unwrap();
+    assert!(guard < div);
+    assert!(matches!(code[guard - 2], Instruction::Test(..)));
+    assert_eq!(emulator::run(&code).unwrap(), 3);
+
+    assert!(!compile(src).iter().any(|i| matches!(i, Instruction::Ud2)));
+}
+
+#[test]
+fn functions_may_be_renamed() {
+    let mut renames = HashMap::new();
+    renames.insert(String::from("main"), String::from("user_main"));
+    let options = Options {
+        renames,
+        ..Options::default()
+    };
+    let code = to_nasm(&compile_with("int main() { return 0; }", options).unwrap());
+    assert!(code.contains("global user_main\n"));
+    assert!(code.contains("user_main:\n"));
+    assert!(!code.contains("global main\n"));
+}
+
+#[test]
+fn sections_are_grouped_in_order() {
+    let src = "int g = 3; int main() { char *s = \"hi\"; return s[1] - 'h' + g; }";
+    let code = asm(src);
+    let data = code.find("section .data").unwrap();
+    let rodata = code.find("section .rodata").unwrap();
+    let text = code.find("section .text").unwrap();
+    assert!(data < rodata && rodata < text);
+    assert!(code[rodata..text].contains("db 104, 105, 0"));
+    assert_eq!(code.matches("section ").count(), 3);
+    assert_eq!(run(src), 4);
+}
+
+#[test]
+fn variadic_functions_are_unsupported() {
+    for src in &[
+        "int printf(char *format, ...); int main() { return 0; }",
+        "int first(int n, ...) { return n; }",
+    ] {
+        let err = parse(lex(src)).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Unsupported {
+                feature: "variadic functions",
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("fixed parameter list"));
+    }
+}
+
+#[test]
+fn nested_arithmetic_has_higher_register_pressure() {
+    let flat = parse(lex("int main() { int a = 1; return a + 2; }")).unwrap();
+    let nested = parse(lex(
+        "int main() { int a = 1; return (a + 1) * ((a + 2) * ((a + 3) * (a + 4))); }",
+    ))
+    .unwrap();
+    assert!(nested.register_pressure() > flat.register_pressure());
+}
+
+#[test]
+fn double_negation_normalizes_to_bool() {
+    assert_eq!(run("int main() { int x = 7; return !!x; }"), 1);
+    assert_eq!(run("int main() { int x = 0; return !!x; }"), 0);
+    assert_eq!(run("int main() { long x = 4294967296; return !!x; }"), 1);
+}
+
+#[test]
+fn pointer_declarators_record_their_depth() {
+    use super::ast::{Statement, Type};
+    let program = parse(lex("int main() { int *p; int **q; return 0; }")).unwrap();
+    let types = program.0[0]
+        .body
+        .iter()
+        .filter_map(|s| match s {
+            Statement::Declaration(ty, _, _, _) => Some(ty),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert!(matches!(types[0], Type::Pointer(inner) if matches!(**inner, Type::Int)));
+    assert!(matches!(
+        types[1],
+        Type::Pointer(inner) if matches!(&**inner, Type::Pointer(inner) if matches!(**inner, Type::Int))
+    ));
+
+    let src = "int main() { int x = 3; int *p = &x; int **q = &p; **q = 5; return *p + x; }";
+    assert_eq!(run(src), 10);
+}
+
+#[test]
+fn pointer_arithmetic_scales_by_element_size() {
+    let src = "int main() {
+        int a[3];
+        a[0] = 10;
+        a[1] = 20;
+        a[2] = 30;
+        int *p = a;
+        return *(p + 2);
+    }";
+    assert_eq!(run(src), 30);
+    let src = "int main() { long a[4]; long *p = a; long *q = p + 3; return q - p; }";
+    assert_eq!(run(src), 3);
+}
+
+#[test]
+fn uninitialized_reads_are_diagnosed() {
+    let codes = |src: &str| {
+        parse(lex(src))
+            .unwrap()
+            .check(&Options::default())
+            .unwrap()
+            .into_iter()
+            .map(|d| d.code)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        codes("int main() { int x; return x; }"),
+        vec!["uninitialized"]
+    );
+    assert_eq!(
+        codes("int main(int c) { int x; if (c) x = 1; return x; }"),
+        vec!["uninitialized"]
+    );
+    assert!(codes("int main(int c) { int x; if (c) x = 1; else x = 2; return x; }").is_empty());
+    assert!(codes("int main() { int x; x = 4; return x; }").is_empty());
+}
+
+#[test]
+fn line_endings_are_configurable() {
+    use super::section::LineEnding;
+    let render = |line_ending| {
+        let program = parse(lex("int main() { return 1; }")).unwrap();
+        let mut ctx = Context::with_options(Options {
+            line_ending,
+            ..Options::default()
+        });
+        let code = program.emit(&mut ctx).unwrap();
+        ctx.render(&code)
+    };
+    let crlf = render(LineEnding::CrLf);
+    assert!(crlf.contains("ret\r\n"));
+    assert!(!crlf.replace("\r\n", "").contains('\n'));
+    assert!(!render(LineEnding::Lf).contains('\r'));
+}
+
+#[test]
+fn if_else_forms_a_diamond() {
+    let program = parse(lex(
+        "int main(int c) { int x = 0; if (c) x = 1; else x = 2; return x; }",
+    ))
+    .unwrap();
+    let options = Options::default();
+    let cfgs = program.cfg(&options);
+    assert_eq!(cfgs[0].block_count(), 4);
+    let mut edges = cfgs[0].edges().to_vec();
+    edges.sort();
+    assert_eq!(edges, vec![(0, 1), (0, 2), (1, 3), (2, 3)]);
+}
+
+#[test]
+fn simple_conditionals_use_cmov_under_the_flag() {
+    let src = "int main() { int c = 0; return c ? 1 : 2; }";
+    let options = Options {
+        cmov: true,
+        ..Options::default()
+    };
+    let code = compile_with(src, options.clone()).unwrap();
+    assert!(to_nasm(&code).contains("cmove rax, rcx"));
+    assert!(!code.iter().any(|i| matches!(i, Instruction::Jcc(..))));
+    assert_eq!(emulator::run(&code).unwrap(), 2);
+    let code = compile_with(
+        "int main() { int c = 5; return c ? c : 2; }",
+        options.clone(),
+    );
+    assert_eq!(emulator::run(&code.unwrap()).unwrap(), 5);
+
+    assert!(!asm(src).contains("cmov"));
+    // Both branches would run, so one with side effects keeps the branch.
+    let src = "int main() { int c = 0; int x = 0; return c ? x++ : 2; }";
+    assert!(!to_nasm(&compile_with(src, options).unwrap()).contains("cmov"));
+}
+
+#[test]
+fn adjacent_strings_are_concatenated() {
+    let src = "int main() { char *s = \"ab\" \"cd\"; return s[3]; }";
+    let code = asm(src);
+    assert_eq!(code.lines().filter(|l| l.starts_with("db ")).count(), 1);
+    assert!(code.contains("db 97, 98, 99, 100, 0\n"));
+    assert_eq!(run(src), 100);
+    assert_eq!(run("int main() { return sizeof(\"ab\" \"cd\"); }"), 5);
+}
+
+#[test]
+fn int_constants_load_as_signed_values() {
+    let code = to_nasm(&compile_folded("int main() { int x = ~0; return x < 0; }"));
+    assert!(code.contains("mov rax, -1\n"));
+    assert!(!code.contains("4294967295"));
+    assert_eq!(run("int main() { int x = ~0; return x < 0; }"), 1);
+
+    let src = "int main() { int x = 2147483647; return x > 0 && -x - 1 < 0; }";
+    assert!(asm(src).contains("mov rax, 2147483647\n"));
+    assert_eq!(run(src), 1);
+}
+
+#[test]
+fn else_binds_to_the_nearest_if() {
+    let src = |a: i32, b: i32| {
+        format!(
+            "int main() {{ int x = 0; if ({}) if ({}) x = 1; else x = 2; return x; }}",
+            a, b
+        )
+    };
+    assert_eq!(run(&src(1, 1)), 1);
+    assert_eq!(run(&src(1, 0)), 2);
+    assert_eq!(run(&src(0, 0)), 0);
+    assert_eq!(
+        run("int main() { int a = 3; if (a > 2) return 1; else return 2; }"),
+        1
+    );
+    assert_eq!(
+        run("int main() { int a = 1; if (a > 2) return 1; else return 2; }"),
+        2
+    );
+    assert_eq!(
+        run("int main() { int a = 1; if (a > 2) a = 7; return a; }"),
+        1
+    );
+}
+
+#[test]
+fn seh_directives_follow_the_prologue() {
+    let options = Options {
+        seh: true,
+        ..Options::default()
+    };
+    let code = compile_with("int main() { int x = 1; return x; }", options).unwrap();
+    let text = to_nasm(&code);
+    assert!(text.contains(".seh_proc main\n"));
+    assert!(text.contains(".seh_endprologue\n"));
+    assert!(text.contains(".seh_endproc\n"));
+    // Each push is described right after it, in order.
+    let pushed = code
+        .windows(2)
+        .filter_map(|w| match w {
+            [Instruction::Push(Operand::Reg(reg)), Instruction::Directive(d)] => {
+                assert_eq!(*d, format!(".seh_pushreg {}", reg));
+                Some(*reg)
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        pushed,
+        [
+            Register::Rbx,
+            Register::Rbp,
+            Register::R12,
+            Register::R13,
+            Register::R14,
+            Register::R15
+        ]
+    );
+    assert_eq!(text.matches(".seh_pushreg").count(), pushed.len());
+
+    assert!(!asm("int main() { return 0; }").contains(".seh"));
+}
+
+#[test]
+fn while_loops_count() {
+    assert_eq!(
+        run("int main() { int i = 0; int s = 0; while (i < 5) { s = s + i; i = i + 1; } return s; }"),
+        10
+    );
+    // Nested loops get their own labels.
+    let src = "int main() {
+        int i = 0;
+        int n = 0;
+        while (i < 3) {
+            int j = 0;
+            while (j < 4) { n = n + 1; j = j + 1; }
+            i = i + 1;
+        }
+        return n;
+    }";
+    assert_eq!(run(src), 12);
+    assert_eq!(run("int main() { while (0) return 1; return 2; }"), 2);
+}
+
+#[test]
+fn for_loops_allow_empty_clauses() {
+    assert_eq!(
+        run("int main() { int s = 0; for (int i = 1; i <= 4; i = i + 1) s = s + i; return s; }"),
+        10
+    );
+    assert_eq!(
+        run("int main() { int i = 0; for (;;) { i = i + 1; if (i == 7) return i; } }"),
+        7
+    );
+    assert_eq!(
+        run("int main() { int i = 0; for (; i < 3;) i = i + 1; return i; }"),
+        3
+    );
+}
+
+#[test]
+fn instruction_count_may_be_capped() {
+    let options = |limit| Options {
+        max_instructions: Some(limit),
+        ..Options::default()
+    };
+    let large = "int main() { int a = 1; a = a * 2 + 3; a = a * 2 + 3; a = a * 2 + 3; return a; }";
+    assert!(matches!(
+        compile_with(large, options(40)),
+        Err(Error::CodeSizeExceeded { limit: 40, .. })
+    ));
+    assert!(compile_with("int main() { return 0; }", options(40)).is_ok());
+}
+
+#[test]
+fn break_and_continue_target_the_innermost_loop() {
+    let src = "int main() {
+        int n = 0;
+        for (int i = 0; i < 10; i = i + 1) {
+            if (i == 2) continue;
+            if (i == 5) break;
+            int j = 0;
+            while (1) { j = j + 1; if (j == 3) break; }
+            n = n + j;
+        }
+        return n;
+    }";
+    // i = 0, 1, 3 and 4 each add 3.
+    assert_eq!(run(src), 12);
+    assert!(matches!(
+        compile_with("int main() { break; return 0; }", Options::default()),
+        Err(Error::NotInLoop { statement: "break" })
+    ));
+    assert!(matches!(
+        compile_with(
+            "int main() { if (1) continue; return 0; }",
+            Options::default()
+        ),
+        Err(Error::NotInLoop {
+            statement: "continue"
+        })
+    ));
+}
+
+#[test]
+fn nested_conditionals_group_to_the_right() {
+    let src = |a: i32, c: i32| {
+        format!(
+            "int main() {{ int a = {}; int c = {}; return a ? 1 : c ? 2 : 3; }}",
+            a, c
+        )
+    };
+    assert_eq!(run(&src(1, 0)), 1);
+    assert_eq!(run(&src(0, 1)), 2);
+    assert_eq!(run(&src(0, 0)), 3);
+    let program = parse(lex(
+        "int main() { int a; int b; int c; int d; int e; return a ? b : c ? d : e; }",
+    ));
+    assert!(super::print::pretty(&program.unwrap()).contains("return a ? b : (c ? d : e);"));
+}
+
+#[test]
+fn volatile_reads_are_kept() {
+    let loads = |src: &str| {
+        let options = Options {
+            peephole: true,
+            ..Options::default()
+        };
+        let code = compile_with(src, options).unwrap();
+        to_nasm(&code)
+            .matches("movsxd rax, dword [rbp - 8]")
+            .count()
+    };
+    assert_eq!(
+        loads("int main() { volatile int x = 5; x; x; return 0; }"),
+        2
+    );
+    assert_eq!(loads("int main() { int x = 5; x; x; return 0; }"), 0);
+}
+
+#[test]
+fn undefined_functions_are_declared_extern() {
+    let code = asm("int f() { return 1; } int main() { f(); return puts(0); }");
+    assert!(code.starts_with("extern puts\n"));
+    assert!(!code.contains("extern f\n"));
+    assert!(!asm("int main() { return 0; }").contains("extern"));
+}
+
+#[test]
+fn parameters_are_usable_as_locals() {
+    assert_eq!(
+        run("int twice(int n) { n = n * 2; return n; } int main() { return twice(21); }"),
+        42
+    );
+    let src = "int f(int a, int b, int c, int d, int e, int g) {
+            return a + b * 2 + c * 3 + d * 4 + e * 5 + g * 6;
+        }
+        int main() { return f(1, 1, 1, 1, 1, 2); }";
+    assert_eq!(run(src), 27);
+}
+
+#[test]
+fn each_function_is_global() {
+    let src = "int g = 2; int sq(int x) { return x * x; } int main() { return sq(g) + g; }";
+    let code = asm(src);
+    assert!(code.contains("global sq\n"));
+    assert!(code.contains("global main\n"));
+    assert!(code.contains("sq:\n") && code.contains("main:\n"));
+    assert_eq!(run(src), 6);
+}
+
+#[test]
+fn member_and_index_access_chain() {
+    use super::ast::{Expression, Statement};
+    let src = "int main() {
+        struct S { int a; int b[3]; int c; };
+        struct S s;
+        struct S *p = &s;
+        s.a = 1;
+        s.b[0] = 2;
+        s.b[1] = 3;
+        p->b[2] = 4;
+        s.c = 5;
+        return s.b[1] * 100 + p->b[2] * 10 + s.a + s.c + sizeof(s);
+    }";
+    assert_eq!(run(src), 300 + 40 + 6 + 20);
+
+    let src = "int main() { struct S { int b[2]; }; struct S a; return a.b[1]; }";
+    let program = parse(lex(src)).unwrap();
+    let printed = super::print::pretty(&program);
+    assert!(printed.contains("int b[2];"));
+    assert!(printed.contains("return a.b[1];"));
+    let e = match program.0[0].body.last() {
+        Some(Statement::Return(Some(e), _)) => e,
+        s => panic!("Expected a return, found {:?}", s),
+    };
+    assert!(matches!(
+        e,
+        Expression::Index(base, _) if matches!(&**base, Expression::Member(a, b)
+            if b == "b" && matches!(&**a, Expression::Var(a) if a == "a"))
+    ));
+}
+
+#[test]
+fn profiling_counts_calls_to_each_function() {
+    let src = "int f() { return 1; } int main() { f(); f(); return f(); }";
+    let options = Options {
+        profile: true,
+        ..Options::default()
+    };
+    let code = compile_with(src, options).unwrap();
+    let text = to_nasm(&code);
+    for name in &["f", "main"] {
+        assert!(text.contains(&format!("inc qword [rel __prof_{}]\n", name)));
+        assert!(text.contains(&format!("global __prof_{}\n", name)));
+    }
+    let bss = text.find("section .bss").unwrap();
+    let counter = text.find("__prof_f:\nresq 1\n").unwrap();
+    assert!(bss < counter && counter < text.find("section .text").unwrap());
+    assert!(!asm(src).contains("__prof"));
+}
+
+#[test]
+fn calls_pass_arguments_and_return_results() {
+    let src = "int seven() { return 7; }
+        int main() { return seven() + sub(10, 3) * 10; }
+        int sub(int a, int b) { return a - b; }";
+    assert_eq!(run(src), 77);
+    let code = asm(src);
+    assert!(code.contains("call seven\n"));
+    assert!(code.contains("call sub\n"));
+}
+
+#[test]
+fn blocks_scope_their_declarations() {
+    let src = "int main() { int x = 1; { int x = 2; x = x + 1; } return x; }";
+    assert_eq!(run(src), 1);
+    let src = "int main() { int x = 1; { int x = 5; return x; } }";
+    assert_eq!(run(src), 5);
+    assert!(matches!(
+        compile_with(
+            "int main() { { int y = 2; } return y; }",
+            Options::default()
+        ),
+        Err(Error::UndeclaredVariable { .. })
+    ));
+}
+
+#[test]
+fn dense_switches_use_a_jump_table() {
+    let options = Options {
+        jump_tables: true,
+        ..Options::default()
+    };
+    let cases = (0..8)
+        .map(|i| format!("case {}: return {};", i, i * 3))
+        .collect::<String>();
+    let dense = format!(
+        "int main() {{ int x = 5; switch (x) {{ {} }} return 99; }}",
+        cases
+    );
+    let code = compile_with(&dense, options.clone()).unwrap();
+    let text = to_nasm(&code);
+    assert!(text.contains("section .rodata"));
+    assert!(text.contains("jmp qword [rcx + rax*8]\n"));
+    assert_eq!(emulator::run(&code).unwrap(), 15);
+    assert!(!asm(&dense).contains("section .rodata"));
+
+    // Labels are folded, so they can be written as expressions.
+    let folded = "int main() { int x = 6; switch (x) {
+            case 1 - 2: return 1; case 0: return 2; case 1 << 2: return 3; case 5: return 4;
+            case 2 * 3: return 5; case ~-8: return 6; } return 99; }";
+    let code = compile_with(folded, options.clone()).unwrap();
+    assert!(to_nasm(&code).contains("jmp qword [rcx + rax*8]\n"));
+    assert_eq!(emulator::run(&code).unwrap(), 5);
+    assert_eq!(run(&folded.replace("x = 6", "x = -1")), 1);
+    assert_eq!(run(&folded.replace("x = 6", "x = 4")), 3);
+    assert!(matches!(
+        parse(lex(
+            "int main() { int x = 6; switch (x) { case x + 1: return 1; } return 0; }"
+        )),
+        Err(Error::NonConstantCase { .. })
+    ));
+
+    let sparse =
+        "int main() { int x = 5; switch (x) { case 1: return 1; case 1000: return 2; } return 3; }";
+    let code = compile_with(sparse, options).unwrap();
+    assert!(!to_nasm(&code).contains("section .rodata"));
+    assert_eq!(emulator::run(&code).unwrap(), 3);
+}
+
+#[test]
+fn break_in_a_switch_leaves_only_the_switch() {
+    let src = "int main() {
+        int n = 0;
+        for (int i = 0; i < 5; i = i + 1) {
+            switch (i) {
+                case 1: continue;
+                case 3: break;
+                default: n = n + 10;
+            }
+            n = n + 1;
+        }
+        return n;
+    }";
+    // i = 0, 2 and 4 add 11; i = 3 adds 1; i = 1 adds nothing.
+    assert_eq!(run(src), 34);
+}
+
+#[test]
+fn uninitialized_slots_do_not_collide() {
+    let src = "int main() { int a; int b = 7; a = 3; int c = 9; return a * 100 + b * 10 + c; }";
+    assert_eq!(run(src), 379);
+}
+
+#[test]
+fn prologue_reserves_the_frame_at_once() {
+    // Six saved registers and the return address take 56 bytes, so the
+    // frame is padded to keep rsp 16-byte aligned.
+    let reserved = |src: &str| {
+        compile(src)
+            .iter()
+            .filter_map(|i| match i {
+                Instruction::Sub(Operand::Reg(Register::Rsp), Operand::Imm(n)) => Some(*n),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        reserved("int main() { int a = 1; int b = 2; int c = 3; return a + b + c; }"),
+        [24]
+    );
+    assert_eq!(
+        reserved("int main() { int a = 1; int b = 2; int c = 3; int d; d = 4; return a + d; }"),
+        [40]
+    );
+}
+
+#[test]
+fn can_compile_agrees_with_compile() {
+    use super::can_compile;
+    for src in &[
+        "int main() { int x = 2; return x * 3; }",
+        "int f(int a) { return a; } int main() { return f(1); }",
+        "int main() { return y; }",
+        "int main() { break; }",
+        "int main() { return 1 }",
+        "int main() { struct S s; return 0; }",
+        "int main() { int x; int x; return 0; }",
+        "int f(int a, int a) { return a; } int main() { return 0; }",
+        "int main() { continue; }",
+        "int main() { case 1: return 0; }",
+        "int main() { switch (1) { case 2: case 2: break; } return 0; }",
+        "int main() { int a[2] = {1, 2, 3}; return 0; }",
+        "int main() { int a[2]; int b[2]; a = b; return 0; }",
+        "int main() { int x = 1; return x[0]; }",
+        "int main() { int x = 1; return x.y; }",
+        "int main() { struct P { int x; }; struct P p; return p.y; }",
+        "int main() { struct P { int x; }; struct P p; return (int)p; }",
+        "int main() { typedef int T; typedef long T; return 0; }",
+        "int main() { U x; return 0; }",
+        "int main() { return sizeof(struct Q); }",
+        "int g = 1; int h = g; int main() { return 0; }",
+        "int main() { int x = 0; for (int i = 0; i < 3; i += 1) { x += i; } return x; }",
+        "int main() { int a[3] = {1, 2}; int *p = a; p[1] = 4; return *(p + 1); }",
+    ] {
+        let tokens = lex(src);
+        // No code is generated, but the same error is found as by emitting.
+        assert_eq!(
+            format!("{:?}", can_compile(&tokens)),
+            format!(
+                "{:?}",
+                parse(tokens.clone())
+                    .and_then(|p| p.emit(&mut Context::new()))
+                    .map(|_| ())
+            ),
+            "{}",
+            src
+        );
+        // The tokens are only borrowed.
+        assert_eq!(tokens, lex(src));
+    }
+    assert!(can_compile(&lex("int main() { return 0; }")).is_ok());
+    assert!(can_compile(&lex("int main() { return y; }")).is_err());
+}
+
+#[test]
+fn static_functions_are_not_exported() {
+    let code = asm("static int helper() { return 4; } int main() { return helper(); }");
+    assert!(!code.contains("global helper"));
+    assert!(!code.contains("global __static_helper"));
+    assert!(code.contains("__static_helper:\n"));
+    assert!(code.contains("global main\n"));
+    assert_eq!(
+        run("static int helper() { return 4; } int main() { return helper(); }"),
+        4
+    );
+}
+
+#[test]
+fn shifts_take_the_count_in_cl() {
+    let src = "int main() { int n = 3; int x = 1; return x << n; }";
+    assert_eq!(run(src), 8);
+    assert!(asm(src).contains("shl rax, cl\n"));
+    assert_eq!(run("int main() { int x = 100; return x >> 2; }"), 25);
+    assert_eq!(run("int main() { int x = 0 - 64; return x >> 3; }"), -8);
+    assert_eq!(
+        run("int main() { unsigned x = 0 - 64; return (x >> 28) == 15; }"),
+        1
+    );
+    // Shifts bind looser than addition and tighter than comparison.
+    assert_eq!(run("int main() { int x = 1; return x + 2 << 1 == 6; }"), 1);
+}
+
+#[test]
+fn bitwise_operators_emit_separate_lines() {
+    for (op, mnemonic, expected) in &[("&", "and", 2), ("|", "or", 7), ("^", "xor", 5)] {
+        let src = format!("int main() {{ int a = 6; int b = 3; return a {} b; }}", op);
+        let code = asm(&src);
+        assert!(code.contains(&format!("\n{} rcx, rax\nmov rax, rcx\n", mnemonic)));
+        assert_eq!(run(&src), *expected);
+    }
+}
+
+#[test]
+fn pointers_cast_to_integers() {
+    let src = "int main() { int x = 5; long a = (long)&x; int *p = (int *)(a + 0); return *p; }";
+    assert_eq!(run(src), 5);
+    let src = "int main() { int a[2]; long p = (long)&a[0]; long q = (long)&a[1]; return q - p; }";
+    assert_eq!(run(src), 4);
+
+    let codes = |src: &str| {
+        parse(lex(src))
+            .unwrap()
+            .check(&Options::default())
+            .unwrap()
+            .into_iter()
+            .map(|d| d.code)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        codes("int main() { int x = 1; return (int)&x; }"),
+        vec!["pointer-to-int-cast"]
+    );
+    assert!(codes("int main() { int x = 1; long a = (long)&x; return a > 0; }").is_empty());
+}
+
+#[test]
+fn data_objects_are_aligned() {
+    let src = "char c = 1; long l = 2; int main() { return c + l; }";
+    let code = asm(src);
+    assert!(code.contains("section .data align=8\n"));
+    assert!(code.contains("c:\ndb 1\nglobal l\ntimes 7 db 0\nl:\ndq 2\n"));
+    assert_eq!(run(src), 3);
+
+    let options = Options {
+        data_alignment: Some(16),
+        ..Options::default()
+    };
+    let code = to_nasm(&compile_with(src, options).unwrap());
+    assert!(code.contains("section .data align=16\n"));
+    assert!(code.contains("c:\ndb 1\nglobal l\ntimes 15 db 0\nl:\ndq 2\n"));
+}
+
+#[test]
+fn truncated_input_is_an_error() {
+    let full = lex("int main(int a) { int x = a; if (x < 2) { return -x; } return sizeof(int); }");
+    for len in 1..full.len() {
+        let tokens = full[..len].to_vec();
+        assert!(parse(tokens.clone()).is_err(), "{:?}", tokens);
+    }
+    assert!(matches!(
+        parse(lex("int main(")),
+        Err(Error::UnexpectedEnd { .. })
+    ));
+    assert!(matches!(
+        parse(lex("int main() { return")),
+        Err(Error::UnexpectedEnd { .. })
+    ));
+    // Nor does the lexer panic on a source ending in an operator.
+    for op in &[
+        "-", "+", "*", "/", "%", "^", "&", "|", "=", "!", "<", "<<", ">", ">>",
+    ] {
+        let src = format!("int main() {{ return 1 {}", op);
+        assert!(parse(lex(&src)).is_err(), "{}", src);
+    }
+}
+
+#[test]
+fn compile_str_turns_source_into_assembly() {
+    use crate::parse::compile_str;
+    let code = compile_str("int main() { return 2 + 3; }").unwrap();
+    assert!(code.contains("global main\n"));
+    assert!(code.contains("mov rax, 2\npush rax\nmov rax, 3\npop rcx\nadd rax, rcx\n"));
+    // Folding is a separate pass.
+    assert!(to_nasm(&compile_folded("int main() { return 2 + 3; }")).contains("mov rax, 5\n"));
+    assert!(matches!(
+        compile_str("int main() { return x; }"),
+        Err(Error::UndeclaredVariable { .. })
+    ));
+}
+
+#[test]
+fn parenthesized_declarators_group() {
+    use super::print::pretty;
+    let grouped = parse(lex(
+        "int main() { int x = 1; int (*p) = &x; int (**q); return *p; }",
+    ));
+    let plain = parse(lex(
+        "int main() { int x = 1; int *p = &x; int **q; return *p; }",
+    ));
+    assert_eq!(pretty(&grouped.unwrap()), pretty(&plain.unwrap()));
+    assert_eq!(
+        run("int main() { int x = 4; int (*p) = &x; return *p; }"),
+        4
+    );
+    assert!(matches!(
+        parse(lex("int main() { int (*fp)(int); return 0; }")),
+        Err(Error::Unsupported {
+            feature: "function pointers",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn longest_path_takes_the_longer_branch() {
+    let length = |src: &str| {
+        let program = parse(lex(src)).unwrap();
+        program.max_path_instructions(&Options::default())[0]
+    };
+    let long = "x = x + 1; x = x * 3;";
+    let short = "x = 2;";
+    let diamond = |then: &str, otherwise: &str| {
+        length(&format!(
+            "int main(int c) {{ int x = 1; if (c) {{ {} }} else {{ {} }} return x; }}",
+            then, otherwise
+        ))
+    };
+    let base = diamond(long, short);
+    assert_eq!(diamond(short, long), base);
+    // The shorter branch doesn't count.
+    assert_eq!(diamond(long, ""), base);
+    // Growing the longer branch grows the path by the added statement's
+    // instructions.
+    let added = length("int main() { int x = 1; x = x * 3; return x; }")
+        - length("int main() { int x = 1; return x; }");
+    assert_eq!(
+        diamond(&format!("{} x = x * 3;", long), short),
+        base + added
+    );
+}
+
+#[test]
+fn emit_produces_structured_instructions() {
+    use Instruction::*;
+    use Register::*;
+    let code = compile("int main() { return 2 + 3; }");
+    let start = code
+        .iter()
+        .position(|i| *i == Mov(Operand::Reg(Rax), Operand::Imm(2)))
+        .unwrap();
+    assert_eq!(
+        code[start..start + 5],
+        [
+            Mov(Operand::Reg(Rax), Operand::Imm(2)),
+            Push(Operand::Reg(Rax)),
+            Mov(Operand::Reg(Rax), Operand::Imm(3)),
+            Pop(Operand::Reg(Rcx)),
+            Add(Operand::Reg(Rax), Operand::Reg(Rcx)),
+        ]
+    );
+    assert_eq!(code.iter().filter(|i| **i == Ret).count(), 2);
+    assert!(code.contains(&Label(String::from("main"))));
+}
+
+#[test]
+fn constant_arithmetic_is_folded() {
+    let code = compile_folded("int main() { return 2 + 3 * 4; }");
+    assert!(code.contains(&Instruction::Mov(
+        Operand::Reg(Register::Rax),
+        Operand::Imm(14)
+    )));
+    assert!(!code.iter().any(|i| matches!(i, Instruction::Imul(..))));
+
+    let code = compile_folded("int main() { int x = 5; return x + 0; }");
+    assert!(code.contains(&Instruction::Mov(
+        Operand::Reg(Register::Rax),
+        Operand::Imm(0)
+    )));
+    assert!(code.iter().any(|i| matches!(i, Instruction::Add(..))));
+    // Division by zero is left for runtime.
+    let code = compile_folded("int main() { return 1 / 0; }");
+    assert!(code.iter().any(|i| matches!(i, Instruction::Idiv(_))));
+}
+
+#[test]
+fn signed_overflow_traps_under_the_flag() {
+    let src = "int main() { int a = 2; int b = a + 3; b = b - a; return b * a; }";
+    let options = Options {
+        trap_overflow: true,
+        ..Options::default()
+    };
+    let code = compile_with(src, options.clone()).unwrap();
+    let after = |op: fn(&Instruction) -> bool| {
+        code.windows(2)
+            .filter(|w| op(&w[0]))
+            .all(|w| w[1] == Instruction::Jcc(Condition::Overflow, String::from("__overflow")))
+    };
+    assert!(after(|i| matches!(
+        i,
+        Instruction::Add(Operand::Reg(Register::Rax), _)
+    )));
+    assert!(after(|i| matches!(
+        i,
+        Instruction::Sub(Operand::Reg(Register::Rax), _)
+    )));
+    assert!(after(|i| matches!(i, Instruction::Imul(..))));
+    assert_eq!(to_nasm(&code).matches("jo __overflow\n").count(), 3);
+    assert!(to_nasm(&code).contains("__overflow:\nud2\n"));
+    assert_eq!(emulator::run(&code).unwrap(), 6);
+
+    let src = "int main() { int a = 2147483647; return a + 1; }";
+    assert!(emulator::run(&compile_with(src, options).unwrap()).is_err());
+    assert!(!asm(src).contains("jo "));
+}
+
+#[test]
+fn peephole_collapses_push_pop_pairs() {
+    let src = "int main() { int a = 2; int b = 3; return a + b; }";
+    let plain = compile(src);
+    let options = Options {
+        peephole: true,
+        ..Options::default()
+    };
+    let optimized = compile_with(src, options).unwrap();
+    assert!(optimized.len() < plain.len());
+    assert_eq!(emulator::run(&optimized).unwrap(), 5);
+    assert_eq!(emulator::run(&plain).unwrap(), 5);
+
+    // A load that reads the pushed value keeps its push.
+    use super::asm::peephole;
+    use Register::*;
+    let clobbered = vec![
+        Instruction::Push(Operand::Reg(Rax)),
+        Instruction::Mov(Operand::Reg(Rcx), Operand::at(Rsp, 0)),
+        Instruction::Pop(Operand::Reg(Rcx)),
+    ];
+    assert_eq!(peephole(&clobbered), clobbered);
+}
+
+#[test]
+fn unsigned_comparison_and_division_differ() {
+    assert_eq!(run("int main() { return (unsigned)(0 - 1) > 0; }"), 1);
+    assert_eq!(run("int main() { return (0 - 1) > 0; }"), 0);
+    let src = "int main() { unsigned x = 0 - 2; return x / 2 == 2147483647; }";
+    assert_eq!(run(src), 1);
+    let code = compile(src);
+    assert!(code.iter().any(|i| matches!(i, Instruction::Div(_))));
+    assert!(!code.iter().any(|i| matches!(i, Instruction::Idiv(_))));
+    assert_eq!(run("int main() { int x = 0 - 2; return x / 2; }"), -1);
+}
+
+#[test]
+fn division_truncates_unless_flooring() {
+    let src = "int main() { return -7 / 2; }";
+    assert_eq!(run(src), -3);
+    assert_eq!(run("int main() { return -7 % 2; }"), -1);
+    let floor = || Options {
+        floor_division: true,
+        ..Options::default()
+    };
+    assert_eq!(
+        emulator::run(&compile_with(src, floor()).unwrap()).unwrap(),
+        -4
+    );
+    let src = "int main() { return -7 % 2; }";
+    assert_eq!(
+        emulator::run(&compile_with(src, floor()).unwrap()).unwrap(),
+        1
+    );
+    // Exact division needs no correction.
+    let src = "int main() { return -8 / 2; }";
+    assert_eq!(
+        emulator::run(&compile_with(src, floor()).unwrap()).unwrap(),
+        -4
+    );
+
+    // Folding leaves the rounding to emit.
+    for (src, floored) in &[
+        ("-7 / 2", -4),
+        ("-7 % 2", 1),
+        ("7 / -2", -4),
+        ("-8 / 2", -4),
+    ] {
+        let mut program = parse(lex(&format!("int main() {{ return {}; }}", src))).unwrap();
+        program.fold_constants();
+        let code = program.emit(&mut Context::with_options(floor())).unwrap();
+        assert_eq!(emulator::run(&code).unwrap(), *floored, "{}", src);
+    }
+    let folded = compile_folded("int main() { return -7 / 2; }");
+    assert_eq!(emulator::run(&folded).unwrap(), -3);
+    let folded = compile_folded("int main() { return 7 / 2; }");
+    assert!(!folded.iter().any(|i| matches!(i, Instruction::Idiv(_))));
+}
+
+#[test]
+fn char_literals_are_their_code() {
+    use super::lex::{Literal, Token};
+    assert_eq!(lex("'A'"), vec![Token::Literal(Literal::Char(b'A'))]);
+    assert!(asm("int main() { return 'A'; }").contains("mov rax, 65\n"));
+    assert!(asm("int main() { return '\\n'; }").contains("mov rax, 10\n"));
+    assert_eq!(run("int main() { return '\\0'; }"), 0);
+    assert_eq!(run("int main() { return '\\\\' + '\\''; }"), 92 + 39);
+}
+
+#[test]
+fn integer_literals_take_a_base_prefix() {
+    use super::lex::{Literal, Token};
+    assert_eq!(lex("0x10"), vec![Token::Literal(Literal::Int(16))]);
+    assert_eq!(lex("0b111"), vec![Token::Literal(Literal::Int(7))]);
+    assert_eq!(lex("0755"), vec![Token::Literal(Literal::Int(0o755))]);
+    for src in &["0xG", "0b2"] {
+        let src = format!("int main() {{ return {}; }}", src);
+        assert!(matches!(
+            parse(lex(&src)),
+            Err(Error::InvalidLiteral { .. })
+        ));
+    }
+}
+
+#[test]
+fn memory_operands_paired_with_immediates_are_sized() {
+    use Register::*;
+    let store = Instruction::Mov(Operand::at(Rbp, -8), Operand::Imm(0));
+    assert_eq!(store.to_string(), "mov qword [rbp - 8], 0");
+    let push = Instruction::Push(Operand::at(Rbp, -8));
+    assert_eq!(push.to_string(), "push qword [rbp - 8]");
+    // A register operand implies the size.
+    let store = Instruction::Mov(Operand::at(Rbp, -8), Operand::Reg(Rax));
+    assert_eq!(store.to_string(), "mov [rbp - 8], rax");
+}
+
+#[test]
+fn postfix_increment_yields_the_old_value() {
+    let src = "int main() { int x = 5; int y = x++; return x * 10 + y; }";
+    assert_eq!(run(src), 65);
+    let src = "int main() { int x = 5; int y = x--; return x * 10 + y; }";
+    assert_eq!(run(src), 45);
+    assert!(matches!(
+        compile_with("int main() { return (5)++; }", Options::default()),
+        Err(Error::NotAssignable { .. })
+    ));
+}
+
+#[test]
+fn parse_errors_render_a_caret_under_the_token() {
+    use super::diagnostic::snippet;
+    let src = "int main() {\n\treturn 1 2;\n}";
+    let err = parse_spanned(lex_spanned(src)).unwrap_err();
+    let span = err.span().unwrap();
+    assert_eq!(span, Span { line: 2, col: 11 });
+    // The tab expands to four columns, and the caret moves along with it.
+    assert_eq!(
+        snippet(src, span, 4),
+        "2 |     return 1 2;\n  |              ^"
+    );
+}
+
+#[test]
+fn addresses_are_taken_and_dereferenced() {
+    let src = "int main() { int x = 3; int *p = &x; return *p; }";
+    assert_eq!(run(src), 3);
+    assert!(asm(src).contains("lea rax, [rbp - 8]\n"));
+    assert_eq!(run("int main() { int x = 4; return *&x; }"), 4);
+    let src = "int main() { int x = 3; int *p = &x; *p = 5; return x; }";
+    assert_eq!(run(src), 5);
+}
+
+#[test]
+fn void_functions_return_early() {
+    let src = "void set(int *p, int x) { if (x < 0) return; *p = x; }
+        int main() { int a = 1; set(&a, 0 - 5); int b = 1; set(&b, 7); return a * 10 + b; }";
+    assert_eq!(run(src), 17);
+}
+
+#[test]
+fn comma_expressions_yield_the_right_operand() {
+    assert_eq!(run("int main() { int x = 0; return (x = 1, x + 2); }"), 3);
+    let src = "int main() { int s = 0; int i; int j; for (i = 0, j = 10; i < j; i++, j--) s++; return s; }";
+    assert_eq!(run(src), 5);
+    // Arguments are still separated by commas.
+    let src = "int sub(int a, int b) { return a - b; } int main() { return sub(5, (1, 2)); }";
+    assert_eq!(run(src), 3);
+}
+
+#[test]
+fn goto_jumps_forward_and_backward() {
+    let src = "int main() { int x = 1; goto end; x = 2; end: return x; }";
+    assert_eq!(run(src), 1);
+    let src = "int main() { int i = 0; again: i = i + 1; if (i < 5) goto again; return i; }";
+    assert_eq!(run(src), 5);
+    // Each function has its own labels.
+    let src = "int f() { goto out; out: return 2; } int main() { goto out; out: return f(); }";
+    assert_eq!(run(src), 2);
+    assert!(matches!(
+        compile_with("int main() { goto nowhere; return 0; }", Options::default()),
+        Err(Error::UndefinedLabel { .. })
+    ));
+}
+
+#[test]
+fn parse_errors_report_their_line() {
+    let src = "int main() {\n    int x = 1;\n    return x x;\n}";
+    let err = parse_spanned(lex_spanned(src)).unwrap_err();
+    assert_eq!(err.span(), Some(Span { line: 3, col: 14 }));
+    assert!(err.to_string().contains("3:14"), "{}", err);
+
+    // Running out of tokens is reported at the last one.
+    let err = parse_spanned(lex_spanned("int main() {\n\n    return")).unwrap_err();
+    assert!(matches!(err, Error::UnexpectedEnd { .. }));
+    assert_eq!(err.span().map(|s| s.line), Some(3));
+}
+
+#[test]
+fn pretty_prints_a_program() {
+    let src = "int add(int a, int b) { return a + b * 2; }
+        int main() { int x = 1; while (x < 10) { x = add(x, 1); } if (!x) return 0; else return -x; }";
+    let program = parse(lex(src)).unwrap();
+    let expected = "int add(int a, int b) {
+    return a + (b * 2);
+}
+
+int main() {
+    int x = 1;
+    while (x < 10) {
+        x = add(x, 1);
+    }
+    if (!x)
+        return 0;
+    else
+        return -x;
+}
+";
+    assert_eq!(super::print::pretty(&program), expected);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn programs_round_trip_through_json() {
+    let src = "int g = 2;
+        int main() { struct P { int x; }; struct P p; p.x = g; unsigned u = 'a'; char *s = \"s\"; return p.x ? -u : s[0]; }";
+    let program = parse(lex(src)).unwrap();
+    let json = serde_json::to_string(&program).unwrap();
+    let back: super::ast::Program = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{:?}", back), format!("{:?}", program));
+}
+
+#[test]
+fn both_targets_render_the_same_code() {
+    use super::asm::Target;
+    let code = compile("int main() { return 1 + 2; }");
+    let x86 = Target::X86_64.render(&code);
+    assert_eq!(x86, to_nasm(&code));
+    assert!(x86.contains("mov rax, 1\npush rax\n"));
+    assert!(x86.contains("add rax, rcx\n"));
+    assert!(x86.contains("ret\n"));
+
+    let arm = Target::AArch64.render(&code);
+    assert!(arm.contains("mov x0, #1\nstr x0, [sp, #-16]!\n"));
+    assert!(arm.contains("ldr x3, [sp], #16\nadds x0, x0, x3\n"));
+    assert!(arm.contains("ret"));
+    assert!(!arm.contains("rax"));
+}
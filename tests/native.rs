@@ -0,0 +1,46 @@
+//! End-to-end tests which assemble, link and run the compiler's output.
+//! They need `nasm` and `cc`, so they only run under the `native` feature.
+#![cfg(feature = "native")]
+
+use std::env;
+use std::fs;
+use std::process::Command;
+
+use threecc::parse::compile_str;
+
+/// Compile, assemble and link `src` under `name` in the temporary
+/// directory, run it, and return its exit code.
+fn exit_code(name: &str, src: &str) -> i32 {
+    let dir = env::temp_dir();
+    let asm = dir.join(format!("3cc-{}.asm", name));
+    let obj = dir.join(format!("3cc-{}.o", name));
+    let bin = dir.join(format!("3cc-{}", name));
+    fs::write(&asm, compile_str(src).unwrap()).unwrap();
+
+    let status = Command::new("nasm")
+        .args(["-f", "elf64", "-o"])
+        .arg(&obj)
+        .arg(&asm)
+        .status()
+        .expect("Failed to run nasm");
+    assert!(status.success(), "nasm failed");
+    let status = Command::new("cc")
+        .arg("-no-pie")
+        .arg("-o")
+        .arg(&bin)
+        .arg(&obj)
+        .status()
+        .expect("Failed to run cc");
+    assert!(status.success(), "cc failed");
+
+    Command::new(&bin)
+        .status()
+        .expect("Failed to run the program")
+        .code()
+        .expect("Program was killed by a signal")
+}
+
+#[test]
+fn returns_exit_code() {
+    assert_eq!(exit_code("return", "int main(){ return 42; }"), 42);
+}